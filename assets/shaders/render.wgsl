@@ -1,38 +1,339 @@
 
 @group(0) @binding(0)
-var texture: texture_storage_2d<rgba8unorm, write>;
+var texture: texture_storage_2d<rgba16float, write>;
 
+// both layouts match simulation.wgsl
+#ifdef THREE_D
 struct Particle {
     position: vec3<f32>,
+    flavour: u32,
     velocity: vec3<f32>,
-    acceleration: vec3<f32>,
-    index: f32
+    flags: u32,
+    age: f32,
+    contact: f32,
+}
+#else
+struct Particle {
+    position: vec2<f32>,
+    velocity: vec2<f32>,
+    flavour: u32,
+    flags: u32,
+    age: f32,
+    contact: f32,
+}
+#endif
+
+@group(0) @binding(1)
+var<storage, read_write> particles: array<Particle>;
+
+// see Particle::DEAD in objects.rs
+const DEAD: u32 = 1u;
+
+// the first flavour_count of ParticleColours in objects.rs, indexed by flavour
+@group(0) @binding(2)
+var<storage, read> colours: array<vec4<f32>>;
+
+#ifndef THREE_D
+// the walls' signed distance field, bound by the simulation too
+@group(0) @binding(5)
+var obstacles: texture_2d<f32>;
+
+// see WALL_COLOUR in backend.rs
+const WALL: vec4<f32> = vec4<f32>(0.2, 0.2, 0.2, 1.);
+
+// the light particles add to each pixel, then how much they covered it, in fixed point since
+// there are no atomic floats
+@group(0) @binding(6)
+var<storage, read_write> accumulation: array<atomic<u32>>;
+
+// see DensityUniform in density.rs
+struct Density {
+    mode: u32,
+    radius: f32,
+    threshold: f32,
+    _padding: u32,
+}
+
+@group(0) @binding(7)
+var<uniform> density: Density;
+
+// fixed point units per 1, fine enough for the tails of the density kernels
+const LIGHT_SCALE: f32 = 1024.;
+// see PARTICLE_RADIUS in backend.rs
+const PARTICLE_RADIUS: f32 = 3.;
+
+fn pixel_index(pixel: vec2<i32>) -> u32 {
+    return 4u * (u32(pixel.y) * textureDimensions(texture).x + u32(pixel.x));
+}
+
+// what shows where there are no particles
+fn background(location: vec2<i32>) -> vec4<f32> {
+    // the field is as big as the texture
+    if textureLoad(obstacles, min(location, vec2<i32>(textureDimensions(obstacles)) - 1), 0).r < 0. {
+        return WALL;
+    }
+    return vec4<f32>(0.5, 0.5, 0.5, 1.0);
+}
+
+fn accumulate(pixel: vec2<i32>, colour: vec3<f32>, weight: f32) {
+    let light = vec4<u32>(vec4<f32>(colour * weight, weight) * LIGHT_SCALE + 0.5);
+    let index = pixel_index(pixel);
+    atomicAdd(&accumulation[index], light.r);
+    atomicAdd(&accumulation[index + 1u], light.g);
+    atomicAdd(&accumulation[index + 2u], light.b);
+    atomicAdd(&accumulation[index + 3u], light.a);
+}
+
+// see DensityField::kernel in density.rs
+fn kernel(distance_squared: f32) -> f32 {
+    if distance_squared > density.radius * density.radius {
+        return 0.;
+    }
+    let sigma = density.radius / 3.;
+    return exp(-distance_squared / (2. * sigma * sigma));
 }
 
-@group(0) @binding(1) 
-var<storage, read_write> particles: array<Particle, 64>;
+// see DensityField::shade in density.rs, with an alpha of 0 to leave the background showing
+fn shade(light: vec3<f32>, weight: f32, background: vec4<f32>) -> vec4<f32> {
+    if weight <= 0. {
+        return vec4<f32>(0.);
+    }
+    let colour = light / weight;
+    // RenderMode in density.rs, by index
+    switch density.mode {
+        // membrane
+        case 1u: {
+            if weight < density.threshold {
+                return vec4<f32>(0.);
+            }
+            let rim = 1. - smoothstep(density.threshold, density.threshold * 1.5, weight);
+            return vec4<f32>(colour * (0.5 + rim), 1.);
+        }
+        // blend
+        case 2u: {
+            let t = smoothstep(0., density.threshold, weight);
+            return vec4<f32>(mix(background.rgb, colour, t), 1.);
+        }
+        // dots
+        default: {
+            return vec4<f32>(light, 1.);
+        }
+    }
+}
+#endif
+
+#ifdef THREE_D
+// see CameraUniform in camera.rs
+struct Camera {
+    view_projection: mat4x4<f32>,
+    viewport: vec2<f32>,
+    focal_length: f32,
+    particle_radius: f32,
+}
+
+@group(0) @binding(3)
+var<uniform> camera: Camera;
+
+// the nearest sphere surface in each pixel, as the bits of its distance from the camera, which
+// order like the distances since they're positive
+@group(0) @binding(4)
+var<storage, read_write> depths: array<atomic<u32>>;
+
+// must match camera.rs
+const MAX_SPLAT_RADIUS: f32 = 32.;
+const LIGHT: vec3<f32> = vec3<f32>(-0.48, 0.64, 0.6);
 
+struct Splat {
+    centre: vec2<f32>,
+    radius: f32,
+    // 0 if the particle isn't drawn
+    distance: f32,
+}
+
+fn project(position: vec3<f32>) -> Splat {
+    let clip = camera.view_projection * vec4<f32>(position, 1.);
+    if clip.w <= camera.particle_radius {
+        return Splat(vec2<f32>(0., 0.), 0., 0.);
+    }
+
+    let ndc = clip.xy / clip.w;
+    let centre = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5) * camera.viewport;
+    let radius = min(camera.particle_radius * camera.focal_length / clip.w, MAX_SPLAT_RADIUS);
+    return Splat(centre, radius, clip.w);
+}
+
+// brightness at `offset` radii from the centre, with y down like the image
+fn shade(offset: vec2<f32>) -> f32 {
+    let normal = vec3<f32>(offset.x, -offset.y, sqrt(max(1. - dot(offset, offset), 0.)));
+    return 0.25 + 0.75 * max(dot(normal, LIGHT), 0.);
+}
+
+fn pixel_index(pixel: vec2<i32>) -> u32 {
+    return u32(pixel.y) * u32(camera.viewport.x) + u32(pixel.x);
+}
+
+// the depth pass keeps the nearest surface in each pixel, then the colour pass draws the pixels
+// where it's this sphere's
+fn draw_sphere(position: vec3<f32>, flavour: u32, colour_pass: bool) {
+    let splat = project(position);
+    if splat.distance <= 0. {
+        return;
+    }
+
+    let lower = max(vec2<i32>(floor(splat.centre - splat.radius)), vec2<i32>(0, 0));
+    let upper = min(vec2<i32>(ceil(splat.centre + splat.radius)), vec2<i32>(camera.viewport));
+    for (var j = lower.y; j < upper.y; j++) {
+        for (var i = lower.x; i < upper.x; i++) {
+            let pixel = vec2<i32>(i, j);
+            let offset = (vec2<f32>(pixel) + 0.5 - splat.centre) / splat.radius;
+            if dot(offset, offset) > 1. {
+                continue;
+            }
+
+            let bulge = sqrt(1. - dot(offset, offset)) * camera.particle_radius;
+            let depth = bitcast<u32>(splat.distance - bulge);
+            let index = pixel_index(pixel);
+            if !colour_pass {
+                atomicMin(&depths[index], depth);
+            } else if atomicLoad(&depths[index]) == depth {
+                textureStore(texture, pixel, vec4<f32>(colours[flavour].rgb * shade(offset), 1.));
+            }
+        }
+    }
+}
+#endif
 
 @compute @workgroup_size(8, 8, 1)
 fn init(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
     let location = vec2<i32>(i32(invocation_id.x), i32(invocation_id.y));
 
-    let color = vec4<f32>(0.5, 0.5, 0.5, 1.0);
-    textureStore(texture, location, color);
+#ifdef THREE_D
+    textureStore(texture, location, vec4<f32>(0.5, 0.5, 0.5, 1.0));
+
+    if f32(invocation_id.x) < camera.viewport.x && f32(invocation_id.y) < camera.viewport.y {
+        atomicStore(&depths[pixel_index(location)], 0xffffffffu);
+    }
+#else
+    textureStore(texture, location, background(location));
+
+    if all(invocation_id.xy < textureDimensions(texture)) {
+        let index = pixel_index(location);
+        for (var channel = 0u; channel < 4u; channel++) {
+            atomicStore(&accumulation[index + channel], 0u);
+        }
+    }
+#endif
 }
 
+#ifdef THREE_D
 @compute @workgroup_size(8, 8, 1)
-fn update(@builtin(local_invocation_index) invocation_id: u32, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
+fn depth(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    if invocation_id >= arrayLength(&particles) {
+        return;
+    }
+    if (particles[invocation_id].flags & DEAD) != 0u {
+        return;
+    }
+
     let particle = particles[invocation_id];
-    let color = vec4<f32>(1., 0., 0., 1.0);
+    draw_sphere(particle.position, particle.flavour, false);
+}
 
-    let position = vec3<i32>(particle.position).xy;
+@compute @workgroup_size(8, 8, 1)
+fn update(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    if invocation_id >= arrayLength(&particles) {
+        return;
+    }
+    if (particles[invocation_id].flags & DEAD) != 0u {
+        return;
+    }
 
-    let stride = 3;
+    let particle = particles[invocation_id];
+    draw_sphere(particle.position, particle.flavour, true);
+}
+#else
+// the world is periodic, so what's drawn past an edge comes back in at the opposite one
+fn wrap_pixel(pixel: vec2<i32>, size: vec2<i32>) -> vec2<i32> {
+    return (pixel % size + size) % size;
+}
 
-    for (var i = position.x - stride; i < position.x + stride; i++) {
-        for (var j = position.y - stride; j < position.y + stride; j++) {
-            textureStore(texture, vec2<i32>(i, j), color);
+@compute @workgroup_size(8, 8, 1)
+fn update(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    if invocation_id >= arrayLength(&particles) {
+        return;
+    }
+
+    let particle = particles[invocation_id];
+    if (particle.flags & DEAD) != 0u {
+        return;
+    }
+    let colour = colours[particle.flavour].rgb;
+
+    // every pixel whose centre is in the disc, so overlapping particles add up
+    let size = vec2<i32>(textureDimensions(texture));
+    let lower = vec2<i32>(floor(particle.position - PARTICLE_RADIUS));
+    let upper = vec2<i32>(ceil(particle.position + PARTICLE_RADIUS));
+    for (var j = lower.y; j < upper.y; j++) {
+        for (var i = lower.x; i < upper.x; i++) {
+            let pixel = wrap_pixel(vec2<i32>(i, j), size);
+            let offset = vec2<f32>(vec2<i32>(i, j)) + 0.5 - particle.position;
+            if dot(offset, offset) <= PARTICLE_RADIUS * PARTICLE_RADIUS {
+                accumulate(pixel, colour, 1.);
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+// instead of update for the density field modes, adding each particle's kernel
+@compute @workgroup_size(8, 8, 1)
+fn splat(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    if invocation_id >= arrayLength(&particles) {
+        return;
+    }
+
+    let particle = particles[invocation_id];
+    if (particle.flags & DEAD) != 0u {
+        return;
+    }
+    let colour = colours[particle.flavour].rgb;
+
+    let size = vec2<i32>(textureDimensions(texture));
+    let lower = vec2<i32>(floor(particle.position - density.radius));
+    let upper = vec2<i32>(ceil(particle.position + density.radius));
+    for (var j = lower.y; j < upper.y; j++) {
+        for (var i = lower.x; i < upper.x; i++) {
+            let pixel = wrap_pixel(vec2<i32>(i, j), size);
+            let offset = vec2<f32>(vec2<i32>(i, j)) + 0.5 - particle.position;
+            let weight = kernel(dot(offset, offset));
+            if weight > 0. {
+                accumulate(pixel, colour, weight);
+            }
+        }
+    }
+}
+
+// pixels the particles reached are shaded for the render mode, the rest keep what init drew
+@compute @workgroup_size(8, 8, 1)
+fn resolve(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    if any(invocation_id.xy >= textureDimensions(texture)) {
+        return;
+    }
+    let location = vec2<i32>(invocation_id.xy);
+
+    let index = pixel_index(location);
+    let light = vec4<f32>(vec4<u32>(
+        atomicLoad(&accumulation[index]),
+        atomicLoad(&accumulation[index + 1u]),
+        atomicLoad(&accumulation[index + 2u]),
+        atomicLoad(&accumulation[index + 3u]),
+    )) / LIGHT_SCALE;
+    let colour = shade(light.rgb, light.a, background(location));
+    if colour.a > 0. {
+        textureStore(texture, location, colour);
+    }
+}
+#endif
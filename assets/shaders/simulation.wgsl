@@ -1,43 +1,435 @@
+
+#ifdef THREE_D
+// see Particle in objects.rs
 struct Particle {
     position: vec3<f32>,
+    flavour: u32,
     velocity: vec3<f32>,
-    acceleration: vec3<f32>,
-    index: f32
+    flags: u32,
+    age: f32,
+    contact: f32,
+}
+
+alias Vector = vec3<f32>;
+#else
+// see PackedParticle in objects.rs
+struct Particle {
+    position: vec2<f32>,
+    velocity: vec2<f32>,
+    flavour: u32,
+    flags: u32,
+    age: f32,
+    contact: f32,
+}
+
+alias Vector = vec2<f32>;
+#endif
+
+// must match MAX_EMITTERS in lifecycle.rs
+const MAX_EMITTERS: u32 = 8u;
+// see Particle::DEAD in objects.rs
+const DEAD: u32 = 1u;
+// must match MAX_SOURCES and FLOW_SIZE in forces.rs
+const MAX_SOURCES: u32 = 8u;
+const FLOW_SIZE: u32 = 32u;
+// see FieldSource::VORTEX in forces.rs
+const VORTEX: u32 = 1u;
+
+@group(0) @binding(0)
+var<storage, read_write> particles: array<Particle>;
+
+// row-major flavour_count x flavour_count, the attraction of the row flavour to the column flavour
+@group(0) @binding(1)
+var<storage, read> weights: array<f32>;
+
+struct SimulationParams {
+    // the depth is 0 in 2D
+    world_size: vec3<f32>,
+    dt: f32,
+    max_distance: f32,
+    repulsion_distance: f32,
+    force_scale: f32,
+    friction_half_life: f32,
+    seed: u32,
+    spawn_count: u32,
+    // rows of the weights, properties and rules
+    flavour_count: u32,
+}
+
+@group(0) @binding(2)
+var<uniform> params: SimulationParams;
+
+// see PhysicalProperties in objects.rs
+struct PhysicalProperties {
+    mass: f32,
+    radius: f32,
+    friction: f32,
+    // 0 for no limit
+    max_speed: f32,
+}
+
+// one per flavour in use
+@group(0) @binding(3)
+var<storage, read> properties: array<PhysicalProperties>;
+
+// see LifecycleRule in lifecycle.rs
+struct LifecycleRule {
+    // 0 to live forever
+    lifespan: f32,
+    respawn: u32,
+    // 0 to never convert
+    conversion_time: f32,
+    convert_by: u32,
+    convert_to: u32,
+}
+
+@group(0) @binding(4)
+var<storage, read> rules: array<LifecycleRule>;
+
+// see SpawnBatch in lifecycle.rs
+struct SpawnBatch {
+    position: vec3<f32>,
+    flavour: u32,
+    radius: f32,
+    first: u32,
+    count: u32,
+}
+
+struct SpawnBatches {
+    batches: array<SpawnBatch, MAX_EMITTERS>,
+}
+
+@group(0) @binding(5)
+var<uniform> spawns: SpawnBatches;
+
+// indices of the dead particles, pushed by integrate and popped by spawn
+struct FreeList {
+    count: atomic<i32>,
+    slots: array<u32>,
+}
+
+@group(0) @binding(6)
+var<storage, read_write> free_list: FreeList;
+
+#ifndef THREE_D
+// signed distance to the walls in pixels, negative inside them, see ObstacleField in obstacles.rs
+@group(0) @binding(7)
+var obstacles: texture_2d<f32>;
+#endif
+
+// see FieldSource in forces.rs
+struct FieldSource {
+    position: vec3<f32>,
+    kind: u32,
+    strength: f32,
+    radius: f32,
+}
+
+// see ForceFieldUniform in forces.rs
+struct ForceField {
+    gravity: vec3<f32>,
+    source_count: u32,
+    flow_strength: f32,
+    sources: array<FieldSource, MAX_SOURCES>,
 }
 
-@group(0) @binding(0) 
-var<storage, read_write> particles: array<Particle, 64>;
+@group(0) @binding(8)
+var<uniform> field: ForceField;
 
-// @group(0) @binding(1)
-// var weights: texture_2d<f32>;
+// FLOW_SIZE x FLOW_SIZE vectors over the world's width and height, row-major
+@group(0) @binding(9)
+var<storage, read> flow: array<vec2<f32>>;
 
+fn world_size() -> Vector {
+#ifdef THREE_D
+    return params.world_size;
+#else
+    return params.world_size.xy;
+#endif
+}
+
+// wrap a position into the world, which has its origin at the top left and y down
+fn wrap(position: Vector) -> Vector {
+    return position - world_size() * floor(position / world_size());
+}
+
+// shortest offset from a to b in the periodic world
+fn wrapped_offset(a: Vector, b: Vector) -> Vector {
+    let offset = b - a;
+    return offset - world_size() * round(offset / world_size());
+}
+
+// PCG hash, see lifecycle.rs
+fn hash(input: u32) -> u32 {
+    let state = input * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// uniform in 0..1
+fn random(seed: u32, index: u32) -> f32 {
+    return f32(hash(seed ^ hash(index)) >> 8u) / 16777216.;
+}
+
+fn random_vector(seed: u32, index: u32) -> Vector {
+#ifdef THREE_D
+    return vec3<f32>(random(seed, 3u * index), random(seed, 3u * index + 1u), random(seed, 3u * index + 2u));
+#else
+    return vec2<f32>(random(seed, 3u * index), random(seed, 3u * index + 1u));
+#endif
+}
+
+#ifndef THREE_D
+// the distance at the nearest cell, see ObstacleField::at
+fn obstacle_distance(cell: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(obstacles));
+    return textureLoad(obstacles, clamp(cell, vec2<i32>(0, 0), size - 1), 0).r;
+}
+
+// pushes a particle inside a wall out along the field's slope and reflects its velocity if it's
+// heading further in, see ObstacleField::collide
+fn collide(particle: ptr<function, Particle>) {
+    let cell = vec2<i32>(floor((*particle).position));
+    let distance = obstacle_distance(cell);
+    if distance >= 0. {
+        return;
+    }
+
+    let slope = vec2<f32>(
+        obstacle_distance(cell + vec2<i32>(1, 0)) - obstacle_distance(cell - vec2<i32>(1, 0)),
+        obstacle_distance(cell + vec2<i32>(0, 1)) - obstacle_distance(cell - vec2<i32>(0, 1)),
+    );
+    let length = length(slope);
+    if length <= 0. {
+        return;
+    }
+    let normal = slope / length;
+
+    (*particle).position += normal * (0.5 - distance);
+    let along = dot((*particle).velocity, normal);
+    if along < 0. {
+        (*particle).velocity -= 2. * along * normal;
+    }
+}
+#endif
+
+// the flow grid's vector in cell (i, j), wrapping around the world
+fn flow_vector(i: i32, j: i32) -> vec2<f32> {
+    let n = i32(FLOW_SIZE);
+    return flow[((j % n + n) % n) * n + (i % n + n) % n];
+}
+
+// the flow grid interpolated between cell centres, see ForceField::sample_flow
+fn sample_flow(position: vec2<f32>) -> vec2<f32> {
+    let cell = position / params.world_size.xy * f32(FLOW_SIZE) - 0.5;
+    let base = floor(cell);
+    let t = cell - base;
+    let i = i32(base.x);
+    let j = i32(base.y);
+    let bottom = mix(flow_vector(i, j), flow_vector(i + 1, j), t.x);
+    let top = mix(flow_vector(i, j + 1), flow_vector(i + 1, j + 1), t.x);
+    return mix(bottom, top, t.y);
+}
+
+// gravity, the attractors and vortices, and the flow, see ForceField::acceleration
+fn external_acceleration(position: Vector, mass: f32) -> Vector {
+#ifdef THREE_D
+    var acceleration = field.gravity;
+#else
+    var acceleration = field.gravity.xy;
+#endif
+    for (var i = 0u; i < field.source_count; i++) {
+        let source = field.sources[i];
+#ifdef THREE_D
+        let offset = wrapped_offset(position, source.position);
+#else
+        let offset = wrapped_offset(position, source.position.xy);
+#endif
+        let distance = length(offset);
+        if distance <= 0. || distance >= source.radius {
+            continue;
+        }
+
+        let strength = source.strength * (1. - distance / source.radius) / mass;
+        var direction = offset;
+        if source.kind == VORTEX {
+            direction.x = -offset.y;
+            direction.y = offset.x;
+#ifdef THREE_D
+            direction.z = 0.;
+#endif
+        }
+        acceleration += direction / distance * strength;
+    }
+
+    let flow = sample_flow(position.xy) * field.flow_strength / mass;
+#ifdef THREE_D
+    acceleration += vec3<f32>(flow, 0.);
+#else
+    acceleration += flow;
+#endif
+    return acceleration;
+}
+
+// force between two particles `distance` apart, as a fraction of max_distance; see cpu.rs
+fn force(distance: f32, weight: f32, repulsion: f32) -> f32 {
+    if distance < repulsion {
+        return distance / repulsion - 1.;
+    }
+    if distance < 1. {
+        return weight * (1. - abs(2. * distance - 1. - repulsion) / (1. - repulsion));
+    }
+    return 0.;
+}
 
 @compute @workgroup_size(8, 8, 1)
-fn init(@builtin(local_invocation_index) invocation_id: u32, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
-    particles[invocation_id].position += vec3<f32>(256.);
+fn init(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    if invocation_id >= arrayLength(&particles) {
+        return;
+    }
+
+    particles[invocation_id].position = wrap(particles[invocation_id].position);
 }
 
+// accumulates forces into the velocity; positions are only read here, so every particle sees
+// the same positions regardless of dispatch order
 @compute @workgroup_size(8, 8, 1)
-fn update(@builtin(local_invocation_index) invocation_id: u32, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
+fn update(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    let count = arrayLength(&particles);
+    if invocation_id >= count {
+        return;
+    }
 
-    var position = particles[invocation_id].position;
-    var velocity = particles[invocation_id].velocity;
+    if (particles[invocation_id].flags & DEAD) != 0u {
+        return;
+    }
 
-    let max = 512.;
+    let position = particles[invocation_id].position;
+    let flavour = particles[invocation_id].flavour;
+    let own = properties[flavour];
+    let rule = rules[flavour];
 
-    position += velocity;
+    var acceleration = Vector();
+    var touching = false;
+    for (var other = 0u; other < count; other++) {
+        if other == invocation_id || (particles[other].flags & DEAD) != 0u {
+            continue;
+        }
 
-    if position.x < 0. {
-        position.x = max;
-    } else if position.x > max {
-        position.x = 0.;
+        let offset = wrapped_offset(position, particles[other].position);
+        let distance = length(offset);
+        if distance <= 0. || distance >= params.max_distance {
+            continue;
+        }
+
+        let other_flavour = particles[other].flavour;
+        let weight = weights[flavour * params.flavour_count + other_flavour];
+        // see pair_repulsion_distance in cpu.rs
+        let radius = properties[other_flavour].radius;
+        let repulsion = params.repulsion_distance * (own.radius + radius) / 2.;
+        acceleration += offset / distance * force(distance / params.max_distance, weight, repulsion);
+        // touching is being close enough to repel
+        if rule.conversion_time > 0. && other_flavour == rule.convert_by && distance / params.max_distance < repulsion {
+            touching = true;
+        }
+    }
+    if touching {
+        particles[invocation_id].contact += params.dt;
+    }
+    acceleration *= params.max_distance * params.force_scale / own.mass;
+    acceleration += external_acceleration(position, own.mass);
+
+    // see accelerate in cpu.rs
+    let friction = pow(0.5, params.dt * own.friction / params.friction_half_life);
+    var velocity = particles[invocation_id].velocity * friction + acceleration * params.dt;
+    let speed = length(velocity);
+    if own.max_speed > 0. && speed > own.max_speed {
+        velocity *= own.max_speed / speed;
+    }
+    particles[invocation_id].velocity = velocity;
+}
+
+// moves the particles, then ages them, see live in lifecycle.rs
+@compute @workgroup_size(8, 8, 1)
+fn integrate(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let invocation_id = workgroup_id.x * 64u + local_index;
+    if invocation_id >= arrayLength(&particles) {
+        return;
+    }
+
+    var particle = particles[invocation_id];
+    if (particle.flags & DEAD) != 0u {
+        return;
+    }
+    particle.position = wrap(particle.position + particle.velocity * params.dt);
+#ifndef THREE_D
+    collide(&particle);
+    particle.position = wrap(particle.position);
+#endif
+    particle.age += params.dt;
+
+    let conversion = rules[particle.flavour];
+    if conversion.conversion_time > 0. && particle.contact >= conversion.conversion_time {
+        particle.flavour = conversion.convert_to;
+        particle.age = 0.;
+        particle.contact = 0.;
+    }
+
+    let rule = rules[particle.flavour];
+    if rule.lifespan > 0. && particle.age >= rule.lifespan {
+        if rule.respawn != 0u {
+            particle.position = random_vector(params.seed, invocation_id) * world_size();
+            particle.velocity = Vector();
+            particle.age = 0.;
+            particle.contact = 0.;
+        } else {
+            particle.flags |= DEAD;
+            let slot = atomicAdd(&free_list.count, 1);
+            free_list.slots[slot] = invocation_id;
+        }
     }
 
-    if position.y < 0. {
-        position.y = max;
-    } else if position.y > max {
-        position.y = 0.;
+    particles[invocation_id] = particle;
+}
+
+// fills free slots with the emitters' spawns, once per frame after the steps. Which spawn gets
+// which slot depends on the order the invocations pop them in.
+@compute @workgroup_size(8, 8, 1)
+fn spawn(@builtin(workgroup_id) workgroup_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32) {
+    let index = workgroup_id.x * 64u + local_index;
+    if index >= params.spawn_count {
+        return;
     }
 
-    particles[invocation_id].position = position;
-}
\ No newline at end of file
+    // pops that find the list empty undo themselves, which can only make other pops fail when
+    // there's nothing left to pop anyway
+    // a var because naga 0.12 can't compare the result of an atomic bound with let
+    var remaining = atomicAdd(&free_list.count, -1);
+    if remaining <= 0 {
+        atomicAdd(&free_list.count, 1);
+        return;
+    }
+    let slot = free_list.slots[remaining - 1];
+
+    for (var i = 0u; i < MAX_EMITTERS; i++) {
+        let batch = spawns.batches[i];
+        if index < batch.first || index >= batch.first + batch.count {
+            continue;
+        }
+
+        // see spawned in lifecycle.rs
+#ifdef THREE_D
+        let centre = batch.position;
+#else
+        let centre = batch.position.xy;
+#endif
+        let offset = (random_vector(~params.seed, index) * 2. - 1.) * batch.radius;
+        var particle: Particle;
+        particle.position = wrap(centre + offset);
+        particle.flavour = batch.flavour;
+        particles[slot] = particle;
+        return;
+    }
+}
@@ -0,0 +1,63 @@
+use bytemuck::cast_slice;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, SeedableRng};
+use rusty_particle_life::{
+    config::{DEFAULT_FLAVOURS, DEFAULT_PARTICLES, DEFAULT_WORLD_SIZE},
+    objects::{FlavourProperties, ForceParams, ParticleColours, Particles, Weights},
+    preset::Preset,
+};
+
+const COUNTS: [usize; 3] = [1_000, 10_000, 100_000];
+
+fn new_particles(count: usize) -> Particles {
+    Particles::new(
+        count,
+        DEFAULT_FLAVOURS,
+        [DEFAULT_WORLD_SIZE.0 as f32, DEFAULT_WORLD_SIZE.1 as f32, 0.],
+        &mut StdRng::seed_from_u64(0),
+    )
+}
+
+fn particles(c: &mut Criterion) {
+    let mut group = c.benchmark_group("particles_new");
+    for count in COUNTS {
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            b.iter(|| new_particles(count))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("particles_cast_slice");
+    for count in COUNTS {
+        let particles = new_particles(count);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| cast_slice::<_, u8>(black_box(&particles.0)).to_vec())
+        });
+    }
+    group.finish();
+}
+
+fn presets(c: &mut Criterion) {
+    let preset = Preset {
+        seed: 0,
+        particle_count: DEFAULT_PARTICLES as u32,
+        flavour_count: DEFAULT_FLAVOURS as u32,
+        weights: Weights::random(&mut StdRng::seed_from_u64(0)),
+        colours: ParticleColours::default(),
+        params: ForceParams::default(),
+        properties: FlavourProperties::default(),
+        obstacles: None,
+        forces: Default::default(),
+    };
+    let bytes = preset.to_bytes();
+
+    c.bench_function("preset_to_bytes", |b| {
+        b.iter(|| black_box(&preset).to_bytes())
+    });
+    c.bench_function("preset_from_bytes", |b| {
+        b.iter(|| Preset::from_bytes(black_box(&bytes)).unwrap())
+    });
+}
+
+criterion_group!(benches, particles, presets);
+criterion_main!(benches);
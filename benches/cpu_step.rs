@@ -0,0 +1,128 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rusty_particle_life::{
+    backend::SimulationBackend,
+    config::{DEFAULT_FLAVOURS, DEFAULT_WORLD_SIZE},
+    cpu::{CpuSimulation, CpuStepper, SpatialGrid},
+    lifecycle::Lifecycle,
+    objects::{FlavourProperties, ForceParams, Particle, Weights, FIXED_TIMESTEP},
+};
+
+const COUNTS: [usize; 4] = [1_000, 10_000, 20_000, 100_000];
+
+/// World scaled with the particle count so density, and work per particle, stays at that of
+/// 1000 particles in the default window
+fn world_size(count: usize) -> [f32; 3] {
+    let scale = (count as f32 / 1_000.).sqrt();
+    [
+        DEFAULT_WORLD_SIZE.0 as f32 * scale,
+        DEFAULT_WORLD_SIZE.1 as f32 * scale,
+        0.,
+    ]
+}
+
+fn particles(count: usize, world_size: [f32; 3]) -> Vec<Particle> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..count)
+        .map(|_| {
+            Particle::new(
+                [
+                    rng.gen::<f32>() * world_size[0],
+                    rng.gen::<f32>() * world_size[1],
+                    0.,
+                ],
+                [0., 0., 0.],
+                rng.gen_range(0..DEFAULT_FLAVOURS),
+            )
+        })
+        .collect()
+}
+
+fn step(c: &mut Criterion) {
+    let weights = Weights::random(&mut StdRng::seed_from_u64(1));
+    let params = ForceParams::default();
+    let properties = FlavourProperties::default();
+    let lifecycle = Lifecycle::default();
+
+    let mut group = c.benchmark_group("cpu_step");
+    group.sample_size(10);
+    for count in COUNTS {
+        let world_size = world_size(count);
+        let mut particles = particles(count, world_size);
+        let mut stepper = CpuStepper::default();
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| {
+                stepper.step(
+                    &mut particles,
+                    &weights,
+                    &params,
+                    &properties,
+                    &lifecycle,
+                    world_size,
+                    FIXED_TIMESTEP,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The structure-of-arrays backend, including its per-step sort into cells
+fn simulation(c: &mut Criterion) {
+    let weights = Weights::random(&mut StdRng::seed_from_u64(1));
+    let params = ForceParams::default();
+    let properties = FlavourProperties::default();
+    let lifecycle = Lifecycle::default();
+
+    let mut group = c.benchmark_group("cpu_simulation");
+    group.sample_size(10);
+    for count in COUNTS {
+        let world_size = world_size(count);
+        let mut simulation = CpuSimulation::default();
+        simulation.init(count, world_size);
+        simulation.upload(&particles(count, world_size));
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| {
+                simulation.step(
+                    &weights,
+                    &params,
+                    &properties,
+                    &lifecycle,
+                    FIXED_TIMESTEP,
+                    1,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn binning(c: &mut Criterion) {
+    let params = ForceParams::default();
+
+    let mut group = c.benchmark_group("spatial_grid_build");
+    for count in COUNTS {
+        let world_size = world_size(count);
+        let particles = particles(count, world_size);
+        let mut grid = SpatialGrid::default();
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| {
+                grid.build(
+                    &particles,
+                    (world_size[0], world_size[1]),
+                    params.max_distance,
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, step, simulation, binning);
+criterion_main!(benches);
@@ -1 +1,3 @@
-cargo watch -x 'run --target wasm32-unknown-unknown --release'
\ No newline at end of file
+# The WebGPU build, for browsers with WebGPU such as Chrome 113 and later. Others need the WebGL2
+# build from cargo_watch_webgl2.sh.
+cargo watch -x 'run --target wasm32-unknown-unknown --release'
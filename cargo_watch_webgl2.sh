@@ -0,0 +1,3 @@
+# The WebGL2 build, for browsers without WebGPU such as Firefox and Safari, with the simulation on
+# the CPU. Serves it like cargo_watch_web.sh, with wasm-server-runner (see .cargo/config.toml).
+cargo watch -x 'run --target wasm32-unknown-unknown --release --features webgl2'
@@ -0,0 +1,398 @@
+//! Metrics computed from snapshots of the particle buffer, for comparing rule sets
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_egui::{
+    egui::{
+        self,
+        plot::{Legend, Line, Plot, PlotPoints},
+    },
+    EguiContexts,
+};
+
+use crate::{
+    config::Config,
+    cpu::{wrapped_offset, SpatialGrid},
+    objects::{ForceParams, Particle, MAX_FLAVOURS},
+    readback::ParticleSnapshot,
+};
+
+const MAX_HISTORY: usize = 200;
+const RDF_BINS: usize = 32;
+/// Coarse cells used to measure how well flavours are mixed
+const MIXING_CELLS: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Metrics {
+    pub time: f32,
+    pub kinetic_energy: f32,
+    pub mean_speed: f32,
+    pub clusters: usize,
+    pub mixing_entropy: f32,
+}
+
+#[derive(Resource)]
+pub struct AnalysisSettings {
+    pub enabled: bool,
+    /// seconds between snapshots
+    pub interval: f32,
+    /// DBSCAN neighbourhood radius in pixels
+    pub cluster_radius: f32,
+    /// DBSCAN core point threshold, including the point itself
+    pub cluster_min_points: usize,
+    /// flavour pair shown in the radial distribution plot
+    pub rdf_pair: (usize, usize),
+}
+
+impl Default for AnalysisSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 0.5,
+            cluster_radius: 12.,
+            cluster_min_points: 4,
+            rdf_pair: (0, 0),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct AnalysisHistory {
+    pub metrics: VecDeque<Metrics>,
+    /// g(r) for `AnalysisSettings::rdf_pair` from the latest snapshot, bin centres in pixels
+    pub rdf: Vec<[f64; 2]>,
+    since_request: f32,
+}
+
+pub struct Analysis;
+impl Plugin for Analysis {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AnalysisSettings>()
+            .init_resource::<AnalysisHistory>()
+            .add_systems(Update, (analyse_snapshots, analysis_ui).chain());
+    }
+}
+
+pub fn kinetic_energy(particles: &[Particle]) -> f32 {
+    particles
+        .iter()
+        .map(|p| 0.5 * p.velocity.iter().map(|v| v * v).sum::<f32>())
+        .sum()
+}
+
+pub fn mean_speed(particles: &[Particle]) -> f32 {
+    if particles.is_empty() {
+        return 0.;
+    }
+    particles
+        .iter()
+        .map(|p| p.velocity.iter().map(|v| v * v).sum::<f32>().sqrt())
+        .sum::<f32>()
+        / particles.len() as f32
+}
+
+fn distance(a: &Particle, b: &Particle, world_size: (f32, f32)) -> f32 {
+    let dx = wrapped_offset(a.position[0], b.position[0], world_size.0);
+    let dy = wrapped_offset(a.position[1], b.position[1], world_size.1);
+    (dx * dx + dy * dy).sqrt()
+}
+
+/// Radial distribution function g(r) of flavour `b` around flavour `a`, out to `max_distance`,
+/// normalised so a uniform random arrangement gives 1 in every bin
+pub fn radial_distribution(
+    particles: &[Particle],
+    pair: (usize, usize),
+    max_distance: f32,
+    bins: usize,
+    world_size: (f32, f32),
+) -> Vec<f32> {
+    let count_a = particles.iter().filter(|p| p.flavour() == pair.0).count();
+    let count_b = particles.iter().filter(|p| p.flavour() == pair.1).count();
+    let mut histogram = vec![0.; bins];
+    if count_a == 0 || count_b == 0 {
+        return histogram;
+    }
+
+    let mut grid = SpatialGrid::default();
+    grid.build(particles, world_size, max_distance);
+
+    let bin_width = max_distance / bins as f32;
+    for (i, a) in particles.iter().enumerate() {
+        if a.flavour() != pair.0 {
+            continue;
+        }
+        for j in grid.neighbours(a) {
+            let b = &particles[j as usize];
+            if j as usize == i || b.flavour() != pair.1 {
+                continue;
+            }
+            let r = distance(a, b, world_size);
+            if r < max_distance {
+                histogram[((r / bin_width) as usize).min(bins - 1)] += 1.;
+            }
+        }
+    }
+
+    // pairs expected in each annulus if `b` were spread uniformly
+    let others = if pair.0 == pair.1 {
+        count_b - 1
+    } else {
+        count_b
+    };
+    let density = others as f32 / (world_size.0 * world_size.1);
+    for (bin, value) in histogram.iter_mut().enumerate() {
+        let inner = bin as f32 * bin_width;
+        let outer = inner + bin_width;
+        let area = std::f32::consts::PI * (outer * outer - inner * inner);
+        *value /= count_a as f32 * density * area;
+    }
+
+    histogram
+}
+
+/// Number of DBSCAN clusters; points that aren't density-reachable from a core point are noise
+pub fn count_clusters(
+    particles: &[Particle],
+    radius: f32,
+    min_points: usize,
+    world_size: (f32, f32),
+) -> usize {
+    let mut grid = SpatialGrid::default();
+    grid.build(particles, world_size, radius);
+
+    let neighbours = |i: usize| -> Vec<usize> {
+        grid.neighbours(&particles[i])
+            .map(|j| j as usize)
+            .filter(|&j| distance(&particles[i], &particles[j], world_size) <= radius)
+            .collect()
+    };
+
+    let mut visited = vec![false; particles.len()];
+    let mut clusters = 0;
+    let mut stack = Vec::new();
+
+    for start in 0..particles.len() {
+        if visited[start] {
+            continue;
+        }
+        let seeds = neighbours(start);
+        if seeds.len() < min_points {
+            continue;
+        }
+
+        clusters += 1;
+        visited[start] = true;
+        stack.extend(seeds);
+        while let Some(i) = stack.pop() {
+            if visited[i] {
+                continue;
+            }
+            visited[i] = true;
+
+            let reachable = neighbours(i);
+            if reachable.len() >= min_points {
+                stack.extend(reachable.into_iter().filter(|&j| !visited[j]));
+            }
+        }
+    }
+
+    clusters
+}
+
+/// Shannon entropy of the flavour mix in coarse cells, weighted by cell population and normalised
+/// to 0..1: 0 when every cell holds a single flavour, 1 when every cell holds all flavours equally
+pub fn mixing_entropy(particles: &[Particle], cells: usize, world_size: (f32, f32)) -> f32 {
+    let flavours = particles
+        .iter()
+        .map(|p| p.flavour() + 1)
+        .max()
+        .unwrap_or(0)
+        .min(MAX_FLAVOURS);
+    if flavours < 2 {
+        return 0.;
+    }
+
+    let mut counts = vec![[0u32; MAX_FLAVOURS]; cells * cells];
+    for p in particles {
+        let column = ((p.position[0] / world_size.0 * cells as f32) as usize).min(cells - 1);
+        let row = ((p.position[1] / world_size.1 * cells as f32) as usize).min(cells - 1);
+        counts[row * cells + column][p.flavour().min(MAX_FLAVOURS - 1)] += 1;
+    }
+
+    let mut entropy = 0.;
+    for cell in &counts {
+        let total: u32 = cell.iter().sum();
+        if total == 0 {
+            continue;
+        }
+        let cell_entropy: f32 = cell
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f32 / total as f32;
+                -p * p.ln()
+            })
+            .sum();
+        entropy += cell_entropy * total as f32;
+    }
+
+    entropy / (particles.len() as f32 * (flavours as f32).ln())
+}
+
+pub(crate) fn analyse_snapshots(
+    time: Res<Time>,
+    settings: Res<AnalysisSettings>,
+    params: Res<ForceParams>,
+    config: Res<Config>,
+    snapshot: Res<ParticleSnapshot>,
+    mut history: ResMut<AnalysisHistory>,
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    history.since_request += time.delta_seconds();
+    if history.since_request >= settings.interval {
+        history.since_request = 0.;
+        snapshot.request();
+    }
+
+    let Some(mut particles) = snapshot.take() else {
+        return;
+    };
+    // the dead are only slots waiting for an emitter
+    particles.retain(Particle::is_alive);
+
+    let world_size = config.world_size_f32();
+    let metrics = Metrics {
+        time: time.elapsed_seconds(),
+        kinetic_energy: kinetic_energy(&particles),
+        mean_speed: mean_speed(&particles),
+        clusters: count_clusters(
+            &particles,
+            settings.cluster_radius,
+            settings.cluster_min_points,
+            world_size,
+        ),
+        mixing_entropy: mixing_entropy(&particles, MIXING_CELLS, world_size),
+    };
+
+    let bin_width = params.max_distance / RDF_BINS as f32;
+    history.rdf = radial_distribution(
+        &particles,
+        settings.rdf_pair,
+        params.max_distance,
+        RDF_BINS,
+        world_size,
+    )
+    .into_iter()
+    .enumerate()
+    .map(|(bin, g)| [((bin as f32 + 0.5) * bin_width) as f64, g as f64])
+    .collect();
+
+    if history.metrics.len() == MAX_HISTORY {
+        history.metrics.pop_front();
+    }
+    history.metrics.push_back(metrics);
+}
+
+fn analysis_ui(
+    mut contexts: EguiContexts,
+    config: Res<Config>,
+    mut settings: ResMut<AnalysisSettings>,
+    mut history: ResMut<AnalysisHistory>,
+) {
+    let flavour_count = config.flavour_count;
+    egui::Window::new("Analysis")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.checkbox(&mut settings.enabled, "enabled");
+            ui.add(egui::Slider::new(&mut settings.interval, 0.1..=5.0).text("interval (s)"));
+            ui.add(
+                egui::Slider::new(&mut settings.cluster_radius, 2.0..=64.0).text("cluster radius"),
+            );
+            ui.add(
+                egui::Slider::new(&mut settings.cluster_min_points, 1..=32)
+                    .text("cluster min points"),
+            );
+            if ui.button("Clear").clicked() {
+                history.metrics.clear();
+                history.rdf.clear();
+            }
+
+            if let Some(latest) = history.metrics.back() {
+                ui.separator();
+                ui.label(format!("kinetic energy: {:.1}", latest.kinetic_energy));
+                ui.label(format!("mean speed: {:.2} px/s", latest.mean_speed));
+                ui.label(format!("clusters: {}", latest.clusters));
+                ui.label(format!("mixing entropy: {:.3}", latest.mixing_entropy));
+            }
+
+            let series = |value: fn(&Metrics) -> f32| -> PlotPoints {
+                history
+                    .metrics
+                    .iter()
+                    .map(|m| [m.time as f64, value(m) as f64])
+                    .collect()
+            };
+
+            Plot::new("energy_plot")
+                .height(100.)
+                .include_y(0.)
+                .legend(Legend::default())
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(series(|m| m.kinetic_energy)).name("kinetic energy"));
+                });
+            Plot::new("speed_plot")
+                .height(100.)
+                .include_y(0.)
+                .legend(Legend::default())
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(series(|m| m.mean_speed)).name("mean speed"));
+                });
+            Plot::new("structure_plot")
+                .height(100.)
+                .include_y(0.)
+                .include_y(1.)
+                .legend(Legend::default())
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(series(|m| m.clusters as f32)).name("clusters"));
+                    plot_ui.line(Line::new(series(|m| m.mixing_entropy)).name("mixing entropy"));
+                });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("g(r) around");
+                egui::ComboBox::from_id_source("rdf_a")
+                    .selected_text(settings.rdf_pair.0.to_string())
+                    .show_ui(ui, |ui| {
+                        for flavour in 0..flavour_count {
+                            ui.selectable_value(
+                                &mut settings.rdf_pair.0,
+                                flavour,
+                                flavour.to_string(),
+                            );
+                        }
+                    });
+                ui.label("of");
+                egui::ComboBox::from_id_source("rdf_b")
+                    .selected_text(settings.rdf_pair.1.to_string())
+                    .show_ui(ui, |ui| {
+                        for flavour in 0..flavour_count {
+                            ui.selectable_value(
+                                &mut settings.rdf_pair.1,
+                                flavour,
+                                flavour.to_string(),
+                            );
+                        }
+                    });
+            });
+            Plot::new("rdf_plot")
+                .height(100.)
+                .include_y(0.)
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(PlotPoints::from(history.rdf.clone())).name("g(r)"));
+                });
+        });
+}
@@ -0,0 +1,316 @@
+//! Where the simulation runs: compute shaders when the platform has them, otherwise the CPU.
+//! The web build falls back to the CPU when the browser doesn't support WebGPU, which needs the
+//! `webgl2` feature so Bevy can still draw.
+
+use bevy::{prelude::*, render::extract_resource::ExtractResourcePlugin};
+use clap::ValueEnum;
+
+use crate::{
+    camera::{shade, CameraUniform, OrbitCamera},
+    config::Config,
+    cpu::CpuSimulation,
+    density::{DensityField, RenderMode},
+    forces::{self, ForceField},
+    hdr::{encode_pixel, PIXEL_SIZE},
+    lifecycle::Lifecycle,
+    objects::{
+        FlavourProperties, ForceParams, Particle, ParticleColours, Particles, RenderImage,
+        SimulationSteps, Weights,
+    },
+    obstacles::{self, ObstacleField},
+    readback::{ParticleSnapshot, Readback},
+    render::RenderPlugin,
+};
+
+/// Radius of the disc drawn for each particle in 2D, matching render.wgsl
+const PARTICLE_RADIUS: f32 = 3.;
+const BACKGROUND: [f32; 4] = [0.5, 0.5, 0.5, 1.];
+/// Matching `WALL` in render.wgsl
+const WALL_COLOUR: [f32; 4] = [0.2, 0.2, 0.2, 1.];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BackendKind {
+    /// compute shaders, needs WebGPU on the web
+    #[default]
+    Gpu,
+    /// CPU simulation, multithreaded where the platform allows, drawn into the render image
+    Cpu,
+}
+
+impl BackendKind {
+    /// The requested backend if it can run here, otherwise the CPU fallback
+    pub fn select(requested: Option<BackendKind>) -> BackendKind {
+        match requested {
+            Some(BackendKind::Cpu) => BackendKind::Cpu,
+            _ if !Self::compute_available() => {
+                warn!("compute shaders aren't available, falling back to the CPU");
+                BackendKind::Cpu
+            }
+            _ => BackendKind::Gpu,
+        }
+    }
+
+    /// Whether compute shaders can be used with this build, and on the web this browser
+    pub fn compute_available() -> bool {
+        !cfg!(feature = "webgl2") && webgpu_available()
+    }
+
+    /// Whether Bevy can render at all, with WebGPU or the WebGL2 fallback
+    pub fn renderer_available() -> bool {
+        cfg!(feature = "webgl2") || webgpu_available()
+    }
+}
+
+// https://developer.mozilla.org/en-US/docs/Web/API/GPU
+// Firefox has navigator.gpu, despite the docs saying it's nightly only
+#[cfg(target_arch = "wasm32")]
+fn webgpu_available() -> bool {
+    use js_sys::Object;
+
+    let Some(window) = web_sys::window() else {
+        return false;
+    };
+    let gpu = window.navigator().gpu();
+    !gpu.is_undefined()
+        && Object::get_prototype_of(&gpu).has_own_property(&"wgslLanguageFeatures".into())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn webgpu_available() -> bool {
+    true
+}
+
+/// A particle life simulation that can be driven without the render graph, so the compute shader
+/// and the CPU implementation can be compared directly
+pub trait SimulationBackend {
+    /// Allocates space for `particle_count` particles in a periodic world of `world_size`, which
+    /// is flat if it has no depth
+    fn init(&mut self, particle_count: usize, world_size: [f32; 3]);
+    /// Replaces the simulated particles, which must match the count given to `init`
+    fn upload(&mut self, particles: &[Particle]);
+    /// Advances `steps` fixed steps of `dt` seconds and spawns what the emitters owe for them.
+    /// The CPU spawns after every step with a seed of its own; the GPU's uniforms are written once
+    /// per call, so it spawns after the last step.
+    fn step(
+        &mut self,
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        lifecycle: &Lifecycle,
+        dt: f32,
+        steps: u32,
+    );
+    /// Replaces the walls particles bounce off, which only a flat world has
+    fn set_obstacles(&mut self, obstacles: &ObstacleField);
+    /// Replaces the gravity, attractors, vortices and flow acting on every particle
+    fn set_forces(&mut self, forces: &ForceField);
+    /// Copies the current particles back, blocking until they're available
+    fn readback(&mut self) -> Vec<Particle>;
+}
+
+/// Adds the simulation and particle rendering for the selected backend
+pub struct Backend(pub BackendKind);
+impl Plugin for Backend {
+    fn build(&self, app: &mut App) {
+        match self.0 {
+            BackendKind::Gpu => {
+                app.add_plugins((RenderPlugin, Readback));
+            }
+            BackendKind::Cpu => {
+                app.add_plugins(CpuBackend);
+            }
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct CpuState(CpuSimulation);
+
+/// Steps the particles in the main world and rasterises them into the render image, which is
+/// drawn by the same sprite as on the GPU backend
+struct CpuBackend;
+impl Plugin for CpuBackend {
+    fn build(&self, app: &mut App) {
+        // frame capture reads these in the render world
+        app.add_plugins((
+            ExtractResourcePlugin::<RenderImage>::default(),
+            ExtractResourcePlugin::<Config>::default(),
+        ));
+
+        app.init_resource::<CpuState>()
+            .init_resource::<ParticleSnapshot>()
+            // after every edit in Update and the fields built from them, so an edit takes effect
+            // on the frame it's made, like the GPU backend which steps after extraction
+            .add_systems(
+                PostUpdate,
+                (step_particles, draw_particles, serve_snapshots)
+                    .chain()
+                    .after(obstacles::update_field)
+                    .after(forces::update_field),
+            );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn step_particles(
+    mut state: ResMut<CpuState>,
+    particles: Res<Particles>,
+    weights: Res<Weights>,
+    params: Res<ForceParams>,
+    properties: Res<FlavourProperties>,
+    lifecycle: Res<Lifecycle>,
+    obstacles: Res<ObstacleField>,
+    forces: Res<ForceField>,
+    steps: Res<SimulationSteps>,
+    config: Res<Config>,
+) {
+    // like the GPU backend, the resource is only uploaded when it's replaced
+    if particles.is_changed() {
+        state.0.init(particles.0.len(), config.volume());
+        state.0.upload(&particles.0);
+    }
+    if obstacles.is_changed() {
+        state.0.set_obstacles(&obstacles);
+    }
+    if forces.is_changed() {
+        state.0.set_forces(&forces);
+    }
+
+    state.0.step(
+        &weights,
+        &params,
+        &properties,
+        &lifecycle,
+        steps.dt,
+        steps.count,
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_particles(
+    state: Res<CpuState>,
+    render_image: Option<Res<RenderImage>>,
+    mut images: ResMut<Assets<Image>>,
+    config: Res<Config>,
+    camera: Res<OrbitCamera>,
+    obstacles: Res<ObstacleField>,
+    colours: Res<ParticleColours>,
+    density: Res<DensityField>,
+    mut light: Local<Vec<[f32; 4]>>,
+) {
+    let Some(image) = render_image.and_then(|render_image| images.get_mut(&render_image.image))
+    else {
+        return;
+    };
+
+    let background = encode_pixel(BACKGROUND);
+    for pixel in image.data.chunks_exact_mut(PIXEL_SIZE) {
+        pixel.copy_from_slice(&background);
+    }
+
+    let (width, height) = (config.world_size.0 as i32, config.world_size.1 as i32);
+    if config.three_d {
+        let camera = camera.uniform(config.volume(), config.world_size);
+        draw_spheres(
+            &mut image.data,
+            (width, height),
+            &camera,
+            &colours,
+            state.0.living(),
+        );
+        return;
+    }
+
+    // discs add their light, so overlaps are brighter than one particle, and the density field
+    // modes add kernels instead
+    let radius = match density.mode {
+        RenderMode::Dots => PARTICLE_RADIUS,
+        RenderMode::Membrane | RenderMode::Blend => density.radius,
+    };
+    light.clear();
+    light.resize((width * height) as usize, [0.; 4]);
+    for ([x, y, _], flavour) in state.0.living() {
+        let [r, g, b, _] = colours.0[flavour];
+        // the world is periodic, so what's drawn past an edge comes back in at the opposite one
+        let lower = ((x - radius).floor() as i32, (y - radius).floor() as i32);
+        let upper = ((x + radius).ceil() as i32, (y + radius).ceil() as i32);
+        for j in lower.1..upper.1 {
+            for i in lower.0..upper.0 {
+                let offset = [i as f32 + 0.5 - x, j as f32 + 0.5 - y];
+                let distance_squared = offset[0] * offset[0] + offset[1] * offset[1];
+                let weight = match density.mode {
+                    RenderMode::Dots if distance_squared <= radius * radius => 1.,
+                    RenderMode::Dots => 0.,
+                    _ => density.kernel(distance_squared),
+                };
+                if weight <= 0. {
+                    continue;
+                }
+                let pixel =
+                    &mut light[(j.rem_euclid(height) * width + i.rem_euclid(width)) as usize];
+                for (sum, channel) in pixel.iter_mut().zip([r, g, b, 1.]) {
+                    *sum += channel * weight;
+                }
+            }
+        }
+    }
+
+    // the field is as big as the render image in 2D
+    let wall = encode_pixel(WALL_COLOUR);
+    let pixels = image.data.chunks_exact_mut(PIXEL_SIZE);
+    for (index, (pixel, &[r, g, b, weight])) in pixels.zip(&*light).enumerate() {
+        let background = if obstacles.distance.get(index).is_some_and(|&d| d < 0.) {
+            pixel.copy_from_slice(&wall);
+            WALL_COLOUR
+        } else {
+            BACKGROUND
+        };
+        if let Some(colour) = density.shade([r, g, b], weight, background) {
+            pixel.copy_from_slice(&encode_pixel(colour));
+        }
+    }
+}
+
+/// Shaded discs, drawn back to front so nearer spheres cover further ones like the GPU's depth test
+fn draw_spheres(
+    data: &mut [u8],
+    (width, height): (i32, i32),
+    camera: &CameraUniform,
+    colours: &ParticleColours,
+    particles: impl Iterator<Item = ([f32; 3], usize)>,
+) {
+    let mut splats: Vec<_> = particles
+        .filter_map(|(position, flavour)| Some((camera.project(position)?, flavour)))
+        .collect();
+    splats.sort_unstable_by(|(a, _), (b, _)| b.distance.total_cmp(&a.distance));
+
+    for (splat, flavour) in splats {
+        let [x, y] = splat.centre;
+        let lower = (
+            ((x - splat.radius).floor() as i32).max(0),
+            ((y - splat.radius).floor() as i32).max(0),
+        );
+        let upper = (
+            ((x + splat.radius).ceil() as i32).min(width),
+            ((y + splat.radius).ceil() as i32).min(height),
+        );
+        for j in lower.1..upper.1 {
+            for i in lower.0..upper.0 {
+                let offset = [
+                    (i as f32 + 0.5 - x) / splat.radius,
+                    (j as f32 + 0.5 - y) / splat.radius,
+                ];
+                if offset[0] * offset[0] + offset[1] * offset[1] > 1. {
+                    continue;
+                }
+                let [r, g, b, _] = colours.0[flavour].map(|c| c * shade(offset));
+                let pixel = (j * width + i) as usize * PIXEL_SIZE;
+                data[pixel..pixel + PIXEL_SIZE].copy_from_slice(&encode_pixel([r, g, b, 1.]));
+            }
+        }
+    }
+}
+
+fn serve_snapshots(state: Res<CpuState>, snapshot: Res<ParticleSnapshot>) {
+    snapshot.fulfil(|| state.0.to_particles());
+}
@@ -0,0 +1,170 @@
+//! The perspective camera that 3D worlds are drawn through. It orbits the centre of the volume,
+//! and both backends project particles with it into the render image, so the sprite showing that
+//! image works the same as in 2D.
+
+use bevy::{
+    input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel},
+    prelude::*,
+    render::extract_resource::ExtractResource,
+};
+use bevy_egui::EguiContexts;
+use bytemuck::{Pod, Zeroable};
+
+/// Radius of a particle's sphere in world units, about the size of the 2D squares
+pub const PARTICLE_RADIUS: f32 = 3.;
+/// Largest sphere drawn in pixels, so a particle next to the camera can't stall a frame. Must
+/// match render.wgsl.
+pub const MAX_SPLAT_RADIUS: f32 = 32.;
+/// Direction towards the light in view space, with y up. Must match render.wgsl.
+pub const LIGHT: [f32; 3] = [-0.48, 0.64, 0.6];
+
+/// Radians per pixel dragged
+const ORBIT_SPEED: f32 = 0.01;
+/// Just short of straight up or down, where the view would flip
+const MAX_PITCH: f32 = 1.5;
+/// Zoom factor per line scrolled
+const ZOOM_SPEED: f32 = 1.1;
+const DISTANCE_RANGE: (f32, f32) = (0.2, 10.);
+/// Pixels of touchpad scrolling that count as one line
+const PIXELS_PER_LINE: f32 = 50.;
+
+#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Debug)]
+pub struct OrbitCamera {
+    /// rotation about the vertical axis through the centre, in radians
+    pub yaw: f32,
+    /// elevation above the horizontal, in radians
+    pub pitch: f32,
+    /// distance from the centre, in multiples of the volume's width
+    pub distance: f32,
+    /// vertical field of view in radians
+    pub fov: f32,
+}
+
+impl Default for OrbitCamera {
+    fn default() -> Self {
+        Self {
+            yaw: 0.6,
+            pitch: 0.4,
+            distance: 1.8,
+            fov: std::f32::consts::FRAC_PI_4,
+        }
+    }
+}
+
+impl OrbitCamera {
+    /// The camera looking at a world of `world_size` through an image of `viewport` pixels
+    pub fn uniform(&self, world_size: [f32; 3], viewport: (u32, u32)) -> CameraUniform {
+        let size = Vec3::from(world_size);
+        let centre = size / 2.;
+        let distance = self.distance * size.x;
+        let direction = Vec3::new(
+            self.pitch.cos() * self.yaw.sin(),
+            self.pitch.sin(),
+            self.pitch.cos() * self.yaw.cos(),
+        );
+
+        let view = Mat4::look_at_rh(centre + direction * distance, centre, Vec3::Y);
+        let (width, height) = (viewport.0 as f32, viewport.1 as f32);
+        // only x, y and w of the clip position are used, so there's no need for a far plane
+        let projection = Mat4::perspective_infinite_rh(self.fov, width / height, PARTICLE_RADIUS);
+
+        CameraUniform {
+            view_projection: (projection * view).to_cols_array_2d(),
+            viewport: [width, height],
+            focal_length: height / 2. / (self.fov / 2.).tan(),
+            particle_radius: PARTICLE_RADIUS,
+        }
+    }
+}
+
+#[derive(Pod, Zeroable, Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct CameraUniform {
+    pub view_projection: [[f32; 4]; 4],
+    /// render image size in pixels
+    pub viewport: [f32; 2],
+    /// pixels per world unit at a distance of one world unit
+    pub focal_length: f32,
+    pub particle_radius: f32,
+}
+
+/// Where a particle's sphere lands in the render image
+#[derive(Clone, Copy, Debug)]
+pub struct Splat {
+    /// in pixels from the top left
+    pub centre: [f32; 2],
+    /// in pixels
+    pub radius: f32,
+    /// from the camera, in world units
+    pub distance: f32,
+}
+
+impl CameraUniform {
+    /// `project` in render.wgsl. Particles too close to or behind the camera aren't drawn.
+    pub fn project(&self, position: [f32; 3]) -> Option<Splat> {
+        let clip =
+            Mat4::from_cols_array_2d(&self.view_projection) * Vec3::from(position).extend(1.);
+        if clip.w <= self.particle_radius {
+            return None;
+        }
+
+        let ndc = clip.truncate().truncate() / clip.w;
+        Some(Splat {
+            centre: [
+                (ndc.x * 0.5 + 0.5) * self.viewport[0],
+                (0.5 - ndc.y * 0.5) * self.viewport[1],
+            ],
+            radius: (self.particle_radius * self.focal_length / clip.w).min(MAX_SPLAT_RADIUS),
+            distance: clip.w,
+        })
+    }
+}
+
+/// Brightness of a sphere at `offset` from its centre in radii, with y down like the image.
+/// `shade` in render.wgsl.
+pub fn shade(offset: [f32; 2]) -> f32 {
+    let [x, y] = offset;
+    let normal = Vec3::new(x, -y, (1. - x * x - y * y).max(0.).sqrt());
+    0.25 + 0.75 * normal.dot(Vec3::from(LIGHT)).max(0.)
+}
+
+/// Dragging orbits the camera and scrolling zooms, unless the pointer is over egui
+pub struct OrbitControls;
+impl Plugin for OrbitControls {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, orbit_controls);
+    }
+}
+
+fn orbit_controls(
+    mut camera: ResMut<OrbitCamera>,
+    mut contexts: EguiContexts,
+    buttons: Res<Input<MouseButton>>,
+    mut motion: EventReader<MouseMotion>,
+    mut wheel: EventReader<MouseWheel>,
+) {
+    let ctx = contexts.ctx_mut();
+    if ctx.wants_pointer_input() || ctx.is_pointer_over_area() {
+        motion.clear();
+        wheel.clear();
+        return;
+    }
+
+    let dragged: Vec2 = motion.iter().map(|motion| motion.delta).sum();
+    if buttons.pressed(MouseButton::Left) && dragged != Vec2::ZERO {
+        camera.yaw -= dragged.x * ORBIT_SPEED;
+        camera.pitch = (camera.pitch + dragged.y * ORBIT_SPEED).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    let scrolled: f32 = wheel
+        .iter()
+        .map(|wheel| match wheel.unit {
+            MouseScrollUnit::Line => wheel.y,
+            MouseScrollUnit::Pixel => wheel.y / PIXELS_PER_LINE,
+        })
+        .sum();
+    if scrolled != 0. {
+        camera.distance = (camera.distance * ZOOM_SPEED.powf(-scrolled))
+            .clamp(DISTANCE_RANGE.0, DISTANCE_RANGE.1);
+    }
+}
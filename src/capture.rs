@@ -0,0 +1,278 @@
+//! Writes rendered frames to disk as PNGs or a video, and stops the app after a frame limit, for
+//! batch rendering
+
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex,
+    },
+};
+
+use bevy::{
+    app::AppExit,
+    core::FrameCount,
+    prelude::*,
+    render::{
+        extract_resource::ExtractResourcePlugin,
+        render_asset::RenderAssets,
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d,
+            ImageCopyBuffer, ImageDataLayout, MapMode, TextureDimension, TextureFormat,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::video::VideoEncoder;
+use crate::{
+    config::Config,
+    hdr::{self, Glow, PIXEL_SIZE},
+    objects::RenderImage,
+};
+
+pub struct CapturedFrame {
+    pub width: u32,
+    pub height: u32,
+    /// tightly packed RGBA8 rows
+    pub data: Vec<u8>,
+}
+
+#[derive(Resource)]
+struct FrameSender(Sender<CapturedFrame>);
+
+#[derive(Resource)]
+pub struct FrameReceiver(pub Mutex<Receiver<CapturedFrame>>);
+
+#[derive(Resource, Default)]
+struct CaptureBuffer(Option<Buffer>);
+
+#[derive(Resource, Default)]
+pub struct SavedFrames(pub u32);
+
+pub struct Capture;
+impl Plugin for Capture {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = channel();
+
+        app.insert_resource(FrameReceiver(Mutex::new(receiver)))
+            .init_resource::<SavedFrames>()
+            // headless runs have no window to adjust the glow from, so they get its defaults
+            .init_resource::<Glow>()
+            .add_plugins(ExtractResourcePlugin::<Glow>::default())
+            .add_systems(Startup, create_output_dir)
+            .add_systems(Update, (save_frames, exit_after_frame_limit).chain());
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(Startup, start_video)
+            .add_systems(Last, finish_video);
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(FrameSender(sender))
+            .init_resource::<CaptureBuffer>()
+            .add_systems(Render, capture_frame.in_set(RenderSet::Cleanup));
+    }
+}
+
+fn create_output_dir(config: Res<Config>) {
+    if let Some(dir) = &config.output_dir {
+        if let Err(err) = std::fs::create_dir_all(dir) {
+            error!("couldn't create {}: {}", dir.display(), err);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn start_video(mut commands: Commands, config: Res<Config>, mut exit: EventWriter<AppExit>) {
+    let Some(path) = &config.video else {
+        return;
+    };
+    match VideoEncoder::spawn(&config.ffmpeg, path, config.world_size, config.fps) {
+        Ok(encoder) => commands.insert_resource(encoder),
+        Err(err) => {
+            // a batch render without its video would be wasted
+            error!("{}", err);
+            exit.send(AppExit);
+        }
+    }
+}
+
+/// ffmpeg only writes a playable file once its input is closed, which has to happen before the
+/// app exits since the resource isn't dropped on every platform
+#[cfg(not(target_arch = "wasm32"))]
+fn finish_video(
+    mut commands: Commands,
+    exit: EventReader<AppExit>,
+    video: Option<ResMut<VideoEncoder>>,
+) {
+    if exit.is_empty() {
+        return;
+    }
+    let Some(mut video) = video else {
+        return;
+    };
+    if let Err(err) = video.finish() {
+        error!("{}", err);
+    }
+    commands.remove_resource::<VideoEncoder>();
+}
+
+/// Copies the render image back from the GPU once the frame has been submitted. This blocks on
+/// the GPU, which is fine for batch rendering where every frame has to be written.
+#[allow(clippy::too_many_arguments)]
+fn capture_frame(
+    config: Res<Config>,
+    render_image: Res<RenderImage>,
+    gpu_images: Res<RenderAssets<Image>>,
+    glow: Res<Glow>,
+    sender: Res<FrameSender>,
+    mut capture_buffer: ResMut<CaptureBuffer>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if !config.recording() {
+        return;
+    }
+    let Some(gpu_image) = gpu_images.get(&render_image.image) else {
+        return;
+    };
+
+    let (width, height) = config.world_size;
+    let row_bytes = width as usize * PIXEL_SIZE;
+    let padded_row_bytes = RenderDevice::align_copy_bytes_per_row(row_bytes);
+
+    let buffer = capture_buffer.0.get_or_insert_with(|| {
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("frame capture buffer"),
+            size: (padded_row_bytes * height as usize) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    });
+
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("frame capture"),
+    });
+    encoder.copy_texture_to_buffer(
+        gpu_image.texture.as_image_copy(),
+        ImageCopyBuffer {
+            buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_row_bytes as u32),
+                rows_per_image: None,
+            },
+        },
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    render_queue.submit([encoder.finish()]);
+
+    let slice = buffer.slice(..);
+    slice.map_async(MapMode::Read, |result| {
+        if let Err(err) = result {
+            error!("couldn't map frame capture buffer: {}", err);
+        }
+    });
+    render_device.poll(wgpu::Maintain::Wait);
+
+    let mut data = Vec::with_capacity(row_bytes * height as usize);
+    {
+        let range = slice.get_mapped_range();
+        for row in range.chunks(padded_row_bytes) {
+            data.extend_from_slice(&row[..row_bytes]);
+        }
+    }
+    buffer.unmap();
+    // the image is half floats, which are tonemapped like the window shows them
+    let data = hdr::to_rgba8(&data, glow.tonemapping);
+
+    // the receiver only goes away when the app is shutting down
+    let _ = sender.0.send(CapturedFrame {
+        width,
+        height,
+        data,
+    });
+}
+
+fn save_frames(
+    config: Res<Config>,
+    receiver: Res<FrameReceiver>,
+    mut saved: ResMut<SavedFrames>,
+    #[cfg(not(target_arch = "wasm32"))] mut commands: Commands,
+    #[cfg(not(target_arch = "wasm32"))] mut video: Option<ResMut<VideoEncoder>>,
+) {
+    if !config.recording() {
+        return;
+    }
+
+    let receiver = receiver.0.lock().unwrap();
+    for frame in receiver.try_iter() {
+        if config.frame_limit.is_some_and(|limit| saved.0 >= limit) {
+            continue;
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(encoder) = &mut video {
+            if let Err(err) = encoder.write_frame(&frame) {
+                // the rest of the video would fail the same way
+                error!("{}", err);
+                commands.remove_resource::<VideoEncoder>();
+                video = None;
+            }
+        }
+
+        if let Some(dir) = &config.output_dir {
+            let path = dir.join(format!("frame_{:05}.png", saved.0));
+            save_png(&path, frame);
+        }
+        saved.0 += 1;
+    }
+}
+
+fn save_png(path: &Path, frame: CapturedFrame) {
+    // the frame is plain RGBA8, but only the sRGB variant converts to a DynamicImage
+    let image = Image::new(
+        Extent3d {
+            width: frame.width,
+            height: frame.height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        frame.data,
+        TextureFormat::Rgba8UnormSrgb,
+    );
+
+    let result = image
+        .try_into_dynamic()
+        .map_err(|err| err.to_string())
+        .and_then(|image| image.save(path).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        error!("couldn't write {}: {}", path.display(), err);
+    }
+}
+
+/// While recording the limit counts written frames, so none are lost to pipelining
+fn exit_after_frame_limit(
+    config: Res<Config>,
+    frames: Res<FrameCount>,
+    saved: Res<SavedFrames>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(limit) = config.frame_limit else {
+        return;
+    };
+
+    let done = if config.recording() {
+        saved.0
+    } else {
+        frames.0
+    };
+    if done >= limit {
+        exit.send(AppExit);
+    }
+}
@@ -0,0 +1,385 @@
+//! Saving the exact state of every particle, with the rules and world it was simulated in, so a
+//! run can be resumed from any moment. Presets only store the rules, from which a simulation
+//! starts over.
+//!
+//! Particles are read back from whichever backend is running, and a loaded checkpoint replaces
+//! the `Particles` resource, which both backends re-upload when it changes. Lifecycle rules and
+//! emitters aren't part of the rules, as in presets, so they're stored next to them: particles
+//! part way through their lifespans or conversions only carry on as they were with them.
+
+use std::{fmt, path::PathBuf};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+
+use crate::{
+    analysis,
+    config::Config,
+    forces::ExternalForces,
+    lifecycle::Lifecycle,
+    objects::{FlavourProperties, ForceParams, Particle, ParticleColours, Particles, Weights},
+    obstacles::ObstacleMap,
+    preset::{Preset, PresetError},
+    readback::ParticleSnapshot,
+    replay::current_rules,
+};
+
+const MAGIC: &[u8; 4] = b"RPLK";
+/// Version 2 added the lifecycle, which version 1 checkpoints get the default for
+pub const CHECKPOINT_VERSION: u16 = 2;
+/// Bytes of one stored particle
+const PARTICLE_SIZE: usize = 40;
+
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    /// the seed, counts and rules the particles were simulated with
+    pub rules: Preset,
+    pub lifecycle: Lifecycle,
+    pub world_size: (u32, u32),
+    pub three_d: bool,
+    pub particles: Vec<Particle>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckpointError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    BadPreset(PresetError),
+    /// a lifecycle cut short, or naming flavours or emitters that can't exist
+    BadLifecycle,
+    /// the header's particle count, which the rules disagree with
+    WrongCount(u32),
+    /// a particle's flavour, which the rules don't have
+    BadFlavour(u32),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::BadMagic => write!(f, "not a checkpoint"),
+            CheckpointError::UnsupportedVersion(version) => {
+                write!(f, "unsupported checkpoint version {}", version)
+            }
+            CheckpointError::Truncated => write!(f, "checkpoint is truncated"),
+            CheckpointError::BadPreset(err) => write!(f, "checkpoint's rules are invalid: {}", err),
+            CheckpointError::BadLifecycle => write!(f, "checkpoint's lifecycle is invalid"),
+            CheckpointError::WrongCount(count) => write!(
+                f,
+                "checkpoint has {} particles, which its rules disagree with",
+                count
+            ),
+            CheckpointError::BadFlavour(flavour) => {
+                write!(
+                    f,
+                    "checkpoint has a particle of unknown flavour {}",
+                    flavour
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl Checkpoint {
+    /// A header, the rules in the preset format, the lifecycle as replays store it, then each
+    /// particle's fields in order. Flat worlds store z too, which is always 0.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let preset = self.rules.to_bytes();
+        let lifecycle = self.lifecycle.to_bytes(self.rules.flavour_count as usize);
+        let mut bytes = Vec::with_capacity(
+            32 + preset.len() + lifecycle.len() + self.particles.len() * PARTICLE_SIZE,
+        );
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        for value in [
+            self.particles.len() as u32,
+            self.world_size.0,
+            self.world_size.1,
+            self.three_d as u32,
+            preset.len() as u32,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&preset);
+        bytes.extend_from_slice(&(lifecycle.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&lifecycle);
+
+        for particle in &self.particles {
+            for value in particle.position.into_iter().chain(particle.velocity) {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&particle.flavour.to_le_bytes());
+            bytes.extend_from_slice(&particle.flags.to_le_bytes());
+            bytes.extend_from_slice(&particle.age.to_le_bytes());
+            bytes.extend_from_slice(&particle.contact.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        let mut reader = Reader(bytes);
+
+        if reader.take::<4>()? != *MAGIC {
+            return Err(CheckpointError::BadMagic);
+        }
+        let version = u16::from_le_bytes(reader.take()?);
+        if !(1..=CHECKPOINT_VERSION).contains(&version) {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+
+        let count = reader.u32()?;
+        let world_size = (reader.u32()?, reader.u32()?);
+        let three_d = reader.u32()? != 0;
+        let rules = Preset::from_bytes(reader.section()?).map_err(CheckpointError::BadPreset)?;
+        if count != rules.particle_count {
+            return Err(CheckpointError::WrongCount(count));
+        }
+        let lifecycle = if version >= 2 {
+            let mut section = reader.section()?;
+            Lifecycle::read(&mut section, rules.flavour_count)
+                .ok_or(CheckpointError::BadLifecycle)?
+        } else {
+            Lifecycle::default()
+        };
+
+        // checked against what's left so a bad count can't allocate more than the file holds
+        if reader.0.len() / PARTICLE_SIZE < count as usize {
+            return Err(CheckpointError::Truncated);
+        }
+        let mut particles = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let particle = Particle {
+                position: [reader.f32()?, reader.f32()?, reader.f32()?],
+                velocity: [reader.f32()?, reader.f32()?, reader.f32()?],
+                flavour: reader.u32()?,
+                flags: reader.u32()?,
+                age: reader.f32()?,
+                contact: reader.f32()?,
+                _padding: [0; 2],
+            };
+            if particle.flavour >= rules.flavour_count {
+                return Err(CheckpointError::BadFlavour(particle.flavour));
+            }
+            particles.push(particle);
+        }
+
+        Ok(Self {
+            rules,
+            lifecycle,
+            world_size,
+            three_d,
+            particles,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        if self.0.len() < N {
+            return Err(CheckpointError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, CheckpointError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, CheckpointError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    /// Length prefixed, since neither the preset format nor the lifecycle has an end marker
+    fn section(&mut self) -> Result<&[u8], CheckpointError> {
+        let len = self.u32()? as usize;
+        if self.0.len() < len {
+            return Err(CheckpointError::Truncated);
+        }
+        let (section, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(section)
+    }
+}
+
+/// Saves and loads checkpoints from a window. Resuming one at startup is `--checkpoint`.
+pub struct Checkpoints;
+impl Plugin for Checkpoints {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CheckpointFile>().add_systems(
+            Update,
+            (checkpoint_ui, load_checkpoint, save_checkpoint)
+                .chain()
+                // analysis takes every snapshot that arrives, including the one asked for here
+                .before(analysis::analyse_snapshots),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct CheckpointFile {
+    path: String,
+    load: Option<PathBuf>,
+    /// where the particles go once they've been read back
+    save: Option<PathBuf>,
+    /// how the last save or load went
+    status: Option<String>,
+}
+
+impl Default for CheckpointFile {
+    fn default() -> Self {
+        Self {
+            path: "checkpoint.rplk".to_string(),
+            load: None,
+            save: None,
+            status: None,
+        }
+    }
+}
+
+fn checkpoint_ui(
+    mut contexts: EguiContexts,
+    config: Res<Config>,
+    snapshot: Res<ParticleSnapshot>,
+    mut file: ResMut<CheckpointFile>,
+) {
+    egui::Window::new("Checkpoint")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.text_edit_singleline(&mut file.path);
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(file.save.is_none(), egui::Button::new("Save"))
+                    .clicked()
+                {
+                    file.save = Some(PathBuf::from(&file.path));
+                    snapshot.request();
+                }
+                // a replay's timeline can't move the particles, so loading would break it
+                let replaying = config.record.is_some() || config.replay.is_some();
+                if ui
+                    .add_enabled(!replaying, egui::Button::new("Load"))
+                    .clicked()
+                {
+                    file.load = Some(PathBuf::from(&file.path));
+                }
+            });
+            if let Some(status) = &file.status {
+                ui.label(status);
+            }
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn save_checkpoint(
+    mut file: ResMut<CheckpointFile>,
+    snapshot: Res<ParticleSnapshot>,
+    config: Res<Config>,
+    weights: Res<Weights>,
+    colours: Res<ParticleColours>,
+    params: Res<ForceParams>,
+    properties: Res<FlavourProperties>,
+    obstacles: Res<ObstacleMap>,
+    forces: Res<ExternalForces>,
+    lifecycle: Res<Lifecycle>,
+) {
+    let Some(path) = file.save.clone() else {
+        return;
+    };
+    let Some(particles) = snapshot.take() else {
+        return;
+    };
+
+    let checkpoint = Checkpoint {
+        rules: current_rules(
+            &config,
+            &weights,
+            &colours,
+            &params,
+            &properties,
+            &obstacles,
+            &forces,
+        ),
+        lifecycle: lifecycle.clone(),
+        world_size: config.world_size,
+        three_d: config.three_d,
+        particles,
+    };
+    file.status = Some(match std::fs::write(&path, checkpoint.to_bytes()) {
+        Ok(()) => format!("saved {}", path.display()),
+        Err(err) => format!("couldn't write {}: {}", path.display(), err),
+    });
+    file.save = None;
+}
+
+/// Only a checkpoint of the same world and counts can be loaded while running, since the render
+/// image and the menus are sized by them
+#[allow(clippy::too_many_arguments)]
+fn load_checkpoint(
+    mut file: ResMut<CheckpointFile>,
+    config: Res<Config>,
+    mut particles: ResMut<Particles>,
+    mut weights: ResMut<Weights>,
+    mut colours: ResMut<ParticleColours>,
+    mut params: ResMut<ForceParams>,
+    mut properties: ResMut<FlavourProperties>,
+    mut obstacles: ResMut<ObstacleMap>,
+    mut forces: ResMut<ExternalForces>,
+    mut lifecycle: ResMut<Lifecycle>,
+) {
+    let Some(path) = file.load.take() else {
+        return;
+    };
+
+    let loaded = std::fs::read(&path)
+        .map_err(|err| format!("couldn't read {}: {}", path.display(), err))
+        .and_then(|bytes| {
+            Checkpoint::from_bytes(&bytes)
+                .map_err(|err| format!("couldn't load {}: {}", path.display(), err))
+        })
+        .and_then(|checkpoint| {
+            let rules = &checkpoint.rules;
+            let fits = checkpoint.world_size == config.world_size
+                && checkpoint.three_d == config.three_d
+                && rules.particle_count as usize == config.particle_count
+                && rules.flavour_count as usize == config.flavour_count;
+            if fits {
+                Ok(checkpoint)
+            } else {
+                Err(format!(
+                    "{} is for another world or count, resume it with --checkpoint",
+                    path.display()
+                ))
+            }
+        });
+    let checkpoint = match loaded {
+        Ok(checkpoint) => checkpoint,
+        Err(err) => {
+            file.status = Some(err);
+            return;
+        }
+    };
+
+    let rules = checkpoint.rules;
+    weights.set_if_neq(rules.weights);
+    colours.set_if_neq(rules.colours);
+    *params = rules.params;
+    *properties = rules.properties;
+    let (width, height) = config.world_size;
+    obstacles.set_if_neq(match &rules.obstacles {
+        Some(map) => map.resized(width, height),
+        None => ObstacleMap::new(width, height),
+    });
+    forces.set_if_neq(rules.forces);
+    lifecycle.set_if_neq(checkpoint.lifecycle);
+    *particles = Particles(checkpoint.particles);
+
+    file.status = Some(format!("loaded {}", path.display()));
+}
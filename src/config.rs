@@ -0,0 +1,449 @@
+//! Startup configuration, from the command line natively or the URL query string on the web
+
+use std::path::{Path, PathBuf};
+
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+
+use crate::{
+    backend::BackendKind, checkpoint::Checkpoint, density::RenderMode, objects::MAX_FLAVOURS,
+    obstacles::ObstacleMap, preset::Preset, replay::Replay, WORKGROUP_SIZE,
+};
+
+pub const DEFAULT_PARTICLES: usize = 64;
+pub const DEFAULT_FLAVOURS: usize = 6;
+pub const DEFAULT_WORLD_SIZE: (u32, u32) = (512, 512);
+pub const DEFAULT_WINDOW_SIZE: (u32, u32) = (1280, 720);
+/// The CPU fallback on the web is single-threaded, so keep it interactive
+pub const MAX_WEB_CPU_PARTICLES: usize = 2000;
+/// Most particles accepted: as many as one dispatch covers, since each dimension is limited to
+/// 65535 workgroups and particles are only dispatched along the first
+pub const MAX_PARTICLES: usize =
+    65535 * (WORKGROUP_SIZE.0 * WORKGROUP_SIZE.1 * WORKGROUP_SIZE.2) as usize;
+/// Largest world side, and window side, accepted: wgpu's default limit on texture sizes
+pub const MAX_WORLD_SIZE: u32 = 8192;
+/// Frame rate of recordings, which advance the simulation by the same time every frame
+pub const DEFAULT_FPS: u32 = 30;
+
+#[derive(Parser, Debug, Default)]
+#[command(about = "Particle life simulation")]
+pub struct Args {
+    /// number of particles
+    #[arg(short = 'n', long)]
+    pub particles: Option<usize>,
+    /// number of particle flavours
+    #[arg(short, long)]
+    pub flavours: Option<usize>,
+    /// seed for the initial particles and weights, random if not given
+    #[arg(short, long)]
+    pub seed: Option<u64>,
+    /// preset file; other arguments override its values
+    #[arg(short, long)]
+    pub preset: Option<PathBuf>,
+    /// simulation area in pixels, as WIDTHxHEIGHT
+    #[arg(long, value_parser = parse_size)]
+    pub world_size: Option<(u32, u32)>,
+    /// window size in logical pixels, as WIDTHxHEIGHT
+    #[arg(long, value_parser = parse_size)]
+    pub window_size: Option<(u32, u32)>,
+    /// simulate a periodic cube as wide as the world, viewed through an orbiting camera
+    #[arg(long = "3d")]
+    pub three_d: bool,
+    /// where the simulation runs; falls back to the CPU if compute shaders aren't available
+    #[arg(long, value_enum)]
+    pub backend: Option<BackendKind>,
+    /// present frames as fast as possible instead of waiting for vsync
+    #[arg(long)]
+    pub no_vsync: bool,
+    /// run without a window, for batch rendering
+    #[arg(long)]
+    pub headless: bool,
+    /// exit after this many frames
+    #[arg(long)]
+    pub frames: Option<u32>,
+    /// write every rendered frame to this directory as a PNG
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// encode every rendered frame into this video with ffmpeg, in a format chosen by its
+    /// extension such as .mp4 or .webm
+    #[arg(long)]
+    pub video: Option<PathBuf>,
+    /// frames per second of simulated time while writing frames or video
+    #[arg(long)]
+    pub fps: Option<u32>,
+    /// the ffmpeg executable for --video
+    #[arg(long, default_value = "ffmpeg")]
+    pub ffmpeg: PathBuf,
+    /// record the session's starting rules and every edit to this file when the app exits
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// play back a recorded session; its starting state replaces the other arguments'
+    #[arg(long, conflicts_with = "record")]
+    pub replay: Option<PathBuf>,
+    /// resume from a checkpoint saved from the Checkpoint window; its particles, rules and world
+    /// replace the other arguments'
+    #[arg(long, conflicts_with_all = ["record", "replay"])]
+    pub checkpoint: Option<PathBuf>,
+    /// PNG whose dark pixels are walls, stretched over the world; replaces the preset's walls
+    #[arg(long)]
+    pub obstacles: Option<PathBuf>,
+    /// how 2D particles are drawn, as dots or a density field
+    #[arg(long, value_enum)]
+    pub render_mode: Option<RenderMode>,
+}
+
+/// `WIDTHxHEIGHT`, each side between 1 and `MAX_WORLD_SIZE`
+pub fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got {}", value))?;
+    let width = width.parse::<u32>().map_err(|err| err.to_string())?;
+    let height = height.parse::<u32>().map_err(|err| err.to_string())?;
+    if width == 0 || height == 0 {
+        return Err("size must be non-zero".to_string());
+    }
+    if width > MAX_WORLD_SIZE || height > MAX_WORLD_SIZE {
+        return Err(format!("sizes can be at most {}", MAX_WORLD_SIZE));
+    }
+    Ok((width, height))
+}
+
+/// Decodes a query string's `%XX` escapes, and `+` for a space as forms encode it
+fn percent_decode(value: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let escape = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .ok_or_else(|| format!("bad escape in {}", value))?;
+                rest = &rest[2..];
+                u8::from_str_radix(escape, 16).unwrap()
+            }
+            _ => byte,
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| format!("{} isn't valid UTF-8 once decoded", value))
+}
+
+/// The contents of a file named on the command line. The web build has no file system, so
+/// there it's an error rather than whatever the platform's stub says.
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    if cfg!(target_arch = "wasm32") {
+        return Err(format!(
+            "the web build can't read {}, share presets in the URL instead",
+            path.display()
+        ));
+    }
+    std::fs::read(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))
+}
+
+#[derive(Resource, Clone, Debug, ExtractResource)]
+pub struct Config {
+    pub particle_count: usize,
+    pub flavour_count: usize,
+    pub seed: u64,
+    pub world_size: (u32, u32),
+    pub window_size: (u32, u32),
+    pub three_d: bool,
+    pub backend: BackendKind,
+    pub vsync: bool,
+    pub headless: bool,
+    pub frame_limit: Option<u32>,
+    pub output_dir: Option<PathBuf>,
+    pub video: Option<PathBuf>,
+    pub fps: u32,
+    pub ffmpeg: PathBuf,
+    /// where to write a replay of the session
+    pub record: Option<PathBuf>,
+    pub replay: Option<Replay>,
+    /// the particles to start from, which `run` takes
+    pub checkpoint: Option<Checkpoint>,
+    pub preset: Option<Preset>,
+    /// walls from the preset or `--obstacles`, at whatever size they were made
+    pub obstacles: Option<ObstacleMap>,
+    /// ignored in 3D, which always draws spheres
+    pub render_mode: RenderMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            particle_count: DEFAULT_PARTICLES,
+            flavour_count: DEFAULT_FLAVOURS,
+            seed: rand::random(),
+            world_size: DEFAULT_WORLD_SIZE,
+            window_size: DEFAULT_WINDOW_SIZE,
+            three_d: false,
+            // selecting checks what's available, and warns, so it's left to `from_parts`
+            backend: BackendKind::Gpu,
+            vsync: true,
+            headless: false,
+            frame_limit: None,
+            output_dir: None,
+            video: None,
+            fps: DEFAULT_FPS,
+            ffmpeg: PathBuf::from("ffmpeg"),
+            record: None,
+            replay: None,
+            checkpoint: None,
+            preset: None,
+            obstacles: None,
+            render_mode: RenderMode::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Parses the process arguments, exiting with a usage message if they're invalid
+    pub fn from_args() -> Self {
+        Self::from_parsed(Args::parse())
+            .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit())
+    }
+
+    /// Reads `?particles=1000&seed=4&no-vsync` style query strings, with the same names and
+    /// validation as the command line
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        Self::from_url(query, "")
+    }
+
+    /// Like `from_query`, starting from a preset shared in the URL fragment (see
+    /// `Preset::to_base64`). Values in the query string override the preset's.
+    pub fn from_url(query: &str, fragment: &str) -> Result<Self, String> {
+        let mut args = vec!["rusty-particle-life".to_string()];
+        for pair in query.trim_start_matches('?').split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            match pair.split_once('=') {
+                Some((key, value)) => {
+                    args.push(format!("--{}", percent_decode(key)?));
+                    args.push(percent_decode(value)?);
+                }
+                None => args.push(format!("--{}", percent_decode(pair)?)),
+            }
+        }
+        let args = Args::try_parse_from(args).map_err(|err| err.to_string())?;
+
+        let fragment = fragment.trim_start_matches('#');
+        let preset = if fragment.is_empty() {
+            None
+        } else {
+            let preset = Preset::from_base64(fragment)
+                .map_err(|err| format!("couldn't load the shared preset: {}", err))?;
+            Some(preset)
+        };
+
+        Self::from_parts(args, preset)
+    }
+
+    pub fn from_parsed(args: Args) -> Result<Self, String> {
+        let preset = match &args.preset {
+            Some(path) => {
+                let bytes = read_file(path)?;
+                let preset = Preset::from_bytes(&bytes)
+                    .map_err(|err| format!("couldn't load {}: {}", path.display(), err))?;
+                Some(preset)
+            }
+            None => None,
+        };
+        let obstacles = match &args.obstacles {
+            Some(path) => {
+                let bytes = read_file(path)?;
+                let obstacles = ObstacleMap::from_png(&bytes)
+                    .map_err(|err| format!("couldn't load {}: {}", path.display(), err))?;
+                Some(obstacles)
+            }
+            None => None,
+        };
+
+        let replay = match &args.replay {
+            Some(path) => {
+                let bytes = read_file(path)?;
+                let replay = Replay::from_bytes(&bytes)
+                    .map_err(|err| format!("couldn't load {}: {}", path.display(), err))?;
+                Some(replay)
+            }
+            None => None,
+        };
+
+        let checkpoint = match &args.checkpoint {
+            Some(path) => {
+                let bytes = read_file(path)?;
+                let checkpoint = Checkpoint::from_bytes(&bytes)
+                    .map_err(|err| format!("couldn't load {}: {}", path.display(), err))?;
+                Some(checkpoint)
+            }
+            None => None,
+        };
+
+        let mut config = Self::from_parts(args, preset)?;
+        if obstacles.is_some() {
+            config.obstacles = obstacles;
+        }
+        if let Some(replay) = replay {
+            config.replay_from(replay);
+            config.validate()?;
+        }
+        if let Some(checkpoint) = checkpoint {
+            config.resume_from(checkpoint);
+            config.validate()?;
+            // the web's CPU limit may have lowered the count
+            if let Some(checkpoint) = &mut config.checkpoint {
+                checkpoint.particles.truncate(config.particle_count);
+                checkpoint.rules.particle_count = checkpoint.particles.len() as u32;
+            }
+        }
+        Ok(config)
+    }
+
+    fn from_parts(args: Args, preset: Option<Preset>) -> Result<Self, String> {
+        let mut config = Config::default();
+
+        if let Some(preset) = preset {
+            config.particle_count = preset.particle_count as usize;
+            config.flavour_count = preset.flavour_count as usize;
+            config.seed = preset.seed;
+            config.obstacles = preset.obstacles.clone();
+            config.preset = Some(preset);
+        }
+
+        if let Some(particles) = args.particles {
+            config.particle_count = particles;
+        }
+        if let Some(flavours) = args.flavours {
+            config.flavour_count = flavours;
+        }
+        if let Some(seed) = args.seed {
+            config.seed = seed;
+        }
+        if let Some(world_size) = args.world_size {
+            config.world_size = world_size;
+        }
+        if let Some(window_size) = args.window_size {
+            config.window_size = window_size;
+        }
+        config.three_d = args.three_d;
+        config.backend = BackendKind::select(args.backend);
+        config.vsync = !args.no_vsync;
+        config.headless = args.headless;
+        config.frame_limit = args.frames;
+        config.output_dir = args.output;
+        config.video = args.video;
+        config.fps = args.fps.unwrap_or(DEFAULT_FPS);
+        config.ffmpeg = args.ffmpeg;
+        config.record = args.record;
+        config.render_mode = args.render_mode.unwrap_or_default();
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects what can't be simulated and adjusts what needs to be. Runs again after a replay
+    /// or checkpoint replaces the arguments, since their files are just as untrusted.
+    fn validate(&mut self) -> Result<(), String> {
+        if self.particle_count == 0 {
+            return Err("there must be at least one particle".to_string());
+        }
+        if self.particle_count > MAX_PARTICLES {
+            return Err(format!(
+                "there can be at most {} particles, got {}",
+                MAX_PARTICLES, self.particle_count
+            ));
+        }
+        if self.flavour_count == 0 || self.flavour_count > MAX_FLAVOURS {
+            return Err(format!(
+                "flavours must be between 1 and {}, got {}",
+                MAX_FLAVOURS, self.flavour_count
+            ));
+        }
+
+        let (width, height) = self.world_size;
+        if width == 0 || height == 0 || width > MAX_WORLD_SIZE || height > MAX_WORLD_SIZE {
+            return Err(format!(
+                "world size must be between 1 and {} on each side, got {}x{}",
+                MAX_WORLD_SIZE, width, height
+            ));
+        }
+        if self.fps == 0 {
+            return Err("fps must be at least 1".to_string());
+        }
+        if self.video.is_some() && cfg!(target_arch = "wasm32") {
+            return Err("video export needs ffmpeg, which the web build can't run".to_string());
+        }
+        if self.record.is_some() && cfg!(target_arch = "wasm32") {
+            return Err("the web build can't write recordings".to_string());
+        }
+
+        // the render image shows the camera's view of the cube, which is square
+        if self.three_d {
+            let side = self.world_size.0.min(self.world_size.1);
+            self.world_size = (side, side);
+        }
+
+        if self.backend == BackendKind::Cpu && cfg!(target_arch = "wasm32") {
+            self.particle_count = self.particle_count.min(MAX_WEB_CPU_PARTICLES);
+        }
+
+        Ok(())
+    }
+
+    /// Starts from where `replay` did, whatever else was asked for. A batch render stops where
+    /// it did, while a window carries on live.
+    fn replay_from(&mut self, replay: Replay) {
+        let initial = &replay.initial;
+        self.particle_count = initial.particle_count as usize;
+        self.flavour_count = initial.flavour_count as usize;
+        self.seed = initial.seed;
+        self.obstacles = initial.obstacles.clone();
+        self.preset = Some(initial.clone());
+        self.world_size = replay.world_size;
+        self.three_d = replay.three_d;
+        self.fps = replay.fps;
+        if self.headless || self.recording() {
+            self.frame_limit.get_or_insert(replay.frames);
+        }
+        self.replay = Some(replay);
+    }
+
+    /// Carries on from `checkpoint`, whatever else was asked for
+    fn resume_from(&mut self, checkpoint: Checkpoint) {
+        let rules = &checkpoint.rules;
+        self.particle_count = rules.particle_count as usize;
+        self.flavour_count = rules.flavour_count as usize;
+        self.seed = rules.seed;
+        self.obstacles = rules.obstacles.clone();
+        self.preset = Some(rules.clone());
+        self.world_size = checkpoint.world_size;
+        self.three_d = checkpoint.three_d;
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Whether rendered frames are written out, as PNGs or video
+    pub fn recording(&self) -> bool {
+        self.output_dir.is_some() || self.video.is_some()
+    }
+
+    /// Simulated time per frame while writing frames, so they play back smoothly however long
+    /// each took to render, and while recording or replaying a session, so the replay steps
+    /// exactly like the original. Otherwise the simulation follows real time.
+    pub fn frame_time(&self) -> Option<f32> {
+        let fixed = self.recording() || self.record.is_some() || self.replay.is_some();
+        fixed.then(|| 1. / self.fps as f32)
+    }
+
+    pub fn world_size_f32(&self) -> (f32, f32) {
+        (self.world_size.0 as f32, self.world_size.1 as f32)
+    }
+
+    /// The simulated volume, with no depth in 2D
+    pub fn volume(&self) -> [f32; 3] {
+        let (width, height) = self.world_size_f32();
+        [width, height, if self.three_d { width } else { 0. }]
+    }
+}
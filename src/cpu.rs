@@ -0,0 +1,1052 @@
+//! CPU implementation of the particle life step. It runs the CPU backend, and the compute
+//! shader is tested against it in backend_equivalence.rs.
+
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::{
+    backend::SimulationBackend,
+    forces::ForceField,
+    lifecycle::{
+        live, respawn_position, spawn_count, spawned, spawns, Fate, Lifecycle, LifecycleRule,
+        SpawnBatch, Spawner,
+    },
+    objects::{FlavourProperties, ForceParams, Particle, Weights, MAX_FLAVOURS},
+    obstacles::ObstacleField,
+};
+
+/// Force between two particles `distance` apart, as a fraction of `max_distance`
+pub fn force(distance: f32, weight: f32, repulsion_distance: f32) -> f32 {
+    if distance < repulsion_distance {
+        distance / repulsion_distance - 1.
+    } else if distance < 1. {
+        weight * (1. - (2. * distance - 1. - repulsion_distance).abs() / (1. - repulsion_distance))
+    } else {
+        0.
+    }
+}
+
+/// Repulsion distance between flavours with `radius` and `other_radius`, as a fraction of
+/// `max_distance`
+pub fn pair_repulsion_distance(params: &ForceParams, radius: f32, other_radius: f32) -> f32 {
+    params.repulsion_distance * (radius + other_radius) / 2.
+}
+
+/// Factor each flavour's velocity decays by in a step of `dt`
+pub fn friction_factors(
+    params: &ForceParams,
+    properties: &FlavourProperties,
+    dt: f32,
+) -> [f32; MAX_FLAVOURS] {
+    properties
+        .0
+        .map(|flavour| 0.5f32.powf(dt * flavour.friction / params.friction_half_life))
+}
+
+/// Velocity after a step of `dt` with `friction` and `acceleration`, limited to `max_speed` if
+/// that's positive
+pub fn accelerate(
+    velocity: [f32; 3],
+    acceleration: [f32; 3],
+    friction: f32,
+    max_speed: f32,
+    dt: f32,
+) -> [f32; 3] {
+    let velocity: [f32; 3] =
+        std::array::from_fn(|axis| velocity[axis] * friction + acceleration[axis] * dt);
+    let speed = velocity.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if max_speed > 0. && speed > max_speed {
+        velocity.map(|v| v * max_speed / speed)
+    } else {
+        velocity
+    }
+}
+
+/// Shortest offset from `a` to `b` in a periodic world of size `size`
+pub fn wrapped_offset(a: f32, b: f32, size: f32) -> f32 {
+    let offset = b - a;
+    if offset > size / 2. {
+        offset - size
+    } else if offset < -size / 2. {
+        offset + size
+    } else {
+        offset
+    }
+}
+
+/// Particle indices sorted into square cells at least `max_distance` wide, so neighbours
+/// only need to be looked up in the surrounding 3x3 cells
+#[derive(Default, Debug, Clone)]
+pub struct SpatialGrid {
+    pub columns: usize,
+    pub rows: usize,
+    cell_width: f32,
+    cell_height: f32,
+    /// `cell_start[c]..cell_start[c + 1]` indexes into `indices` for cell `c`
+    cell_start: Vec<u32>,
+    indices: Vec<u32>,
+}
+
+impl SpatialGrid {
+    pub fn build(&mut self, particles: &[Particle], world_size: (f32, f32), max_distance: f32) {
+        self.columns = ((world_size.0 / max_distance) as usize).max(1);
+        self.rows = ((world_size.1 / max_distance) as usize).max(1);
+        self.cell_width = world_size.0 / self.columns as f32;
+        self.cell_height = world_size.1 / self.rows as f32;
+
+        let cell_count = self.columns * self.rows;
+        self.cell_start.clear();
+        self.cell_start.resize(cell_count + 1, 0);
+        self.indices.clear();
+        self.indices.resize(particles.len(), 0);
+
+        // counting sort: histogram, prefix sum, scatter
+        for particle in particles {
+            let cell = self.cell_of(particle);
+            self.cell_start[cell + 1] += 1;
+        }
+        for cell in 0..cell_count {
+            self.cell_start[cell + 1] += self.cell_start[cell];
+        }
+
+        let mut cursor = self.cell_start.clone();
+        for (i, particle) in particles.iter().enumerate() {
+            let cell = self.cell_of(particle);
+            self.indices[cursor[cell] as usize] = i as u32;
+            cursor[cell] += 1;
+        }
+    }
+
+    fn cell_of(&self, particle: &Particle) -> usize {
+        let column = ((particle.position[0] / self.cell_width) as usize).min(self.columns - 1);
+        let row = ((particle.position[1] / self.cell_height) as usize).min(self.rows - 1);
+        row * self.columns + column
+    }
+
+    pub fn cell(&self, column: usize, row: usize) -> &[u32] {
+        let cell = row * self.columns + column;
+        &self.indices[self.cell_start[cell] as usize..self.cell_start[cell + 1] as usize]
+    }
+
+    /// Indices of every particle in the 3x3 block of cells around `particle`, wrapping at the edges
+    pub fn neighbours<'a>(&'a self, particle: &Particle) -> impl Iterator<Item = u32> + 'a {
+        let cell = self.cell_of(particle);
+        let (column, row) = (cell % self.columns, cell / self.columns);
+
+        // with fewer than 3 cells on an axis the wrapped neighbours would repeat
+        let columns = neighbour_range(column, self.columns);
+        let rows = neighbour_range(row, self.rows);
+
+        rows.flat_map(move |r| columns.clone().map(move |c| (c, r)))
+            .flat_map(move |(c, r)| self.cell(c, r).iter().copied())
+    }
+}
+
+fn neighbour_range(index: usize, count: usize) -> impl Iterator<Item = usize> + Clone {
+    let span = count.min(3);
+    (0..span).map(move |i| (index + count + i - span / 2) % count)
+}
+
+/// Steps a slice of particles on the CPU, in a periodic world with its origin at the top left and
+/// y down, like the force fields. Cells only divide the world in x and y, so in 3D each covers the
+/// full depth.
+#[derive(Default, Debug, Clone)]
+pub struct CpuStepper {
+    pub grid: SpatialGrid,
+    accelerations: Vec<[f32; 3]>,
+    /// whether each particle is touching the flavour that converts it
+    touching: Vec<bool>,
+    spawner: Spawner,
+    /// walls, in a flat world
+    pub obstacles: ObstacleField,
+    pub forces: ForceField,
+}
+
+impl CpuStepper {
+    /// One step, after which the emitters' spawns fill the dead particles' slots, lowest first
+    #[allow(clippy::too_many_arguments)]
+    pub fn step(
+        &mut self,
+        particles: &mut [Particle],
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        lifecycle: &Lifecycle,
+        world_size: [f32; 3],
+        dt: f32,
+    ) {
+        let (batches, seed) = self.spawner.advance(&lifecycle.emitters, dt);
+        let rules = &lifecycle.rules;
+
+        self.grid.build(
+            particles,
+            (world_size[0], world_size[1]),
+            params.max_distance,
+        );
+        // a flat world has no depth to wrap in
+        let axes = if world_size[2] > 0. { 3 } else { 2 };
+
+        let (grid, forces) = (&self.grid, &self.forces);
+        particles
+            .par_iter()
+            .enumerate()
+            .map(|(i, particle)| {
+                if !particle.is_alive() {
+                    return ([0.; 3], false);
+                }
+                let own = &properties.0[particle.flavour()];
+                let rule = &rules[particle.flavour()];
+                let mut acceleration = [0.; 3];
+                let mut touching = false;
+
+                for j in grid.neighbours(particle) {
+                    let other = &particles[j as usize];
+                    if j as usize == i || !other.is_alive() {
+                        continue;
+                    }
+
+                    let mut offset = [0.; 3];
+                    for axis in 0..axes {
+                        offset[axis] = wrapped_offset(
+                            particle.position[axis],
+                            other.position[axis],
+                            world_size[axis],
+                        );
+                    }
+                    let distance = offset.iter().map(|d| d * d).sum::<f32>().sqrt();
+                    if distance <= 0. || distance >= params.max_distance {
+                        continue;
+                    }
+
+                    let weight = weights.0[particle.flavour()][other.flavour()];
+                    let radius = properties.0[other.flavour()].radius;
+                    let repulsion = pair_repulsion_distance(params, own.radius, radius);
+                    let f = force(distance / params.max_distance, weight, repulsion);
+                    for axis in 0..axes {
+                        acceleration[axis] += offset[axis] / distance * f;
+                    }
+                    // touching is being close enough to repel
+                    touching |= rule.converts()
+                        && other.flavour == rule.convert_by
+                        && distance / params.max_distance < repulsion;
+                }
+
+                let scale = params.max_distance * params.force_scale / own.mass;
+                let external = forces.acceleration(particle.position, own.mass, world_size);
+                let acceleration =
+                    std::array::from_fn(|axis| acceleration[axis] * scale + external[axis]);
+                (acceleration, touching)
+            })
+            .unzip_into_vecs(&mut self.accelerations, &mut self.touching);
+
+        let frictions = friction_factors(params, properties, dt);
+        let obstacles = &self.obstacles;
+        particles
+            .par_iter_mut()
+            .zip(&self.accelerations)
+            .zip(&self.touching)
+            .enumerate()
+            .for_each(|(i, ((particle, acceleration), &touching))| {
+                if !particle.is_alive() {
+                    return;
+                }
+                let flavour = particle.flavour();
+                particle.velocity = accelerate(
+                    particle.velocity,
+                    *acceleration,
+                    frictions[flavour],
+                    properties.0[flavour].max_speed,
+                    dt,
+                );
+                for ((position, velocity), size) in particle
+                    .position
+                    .iter_mut()
+                    .zip(particle.velocity)
+                    .zip(world_size)
+                    .take(axes)
+                {
+                    *position = (*position + velocity * dt).rem_euclid(size);
+                }
+                if axes == 2 {
+                    obstacles.collide(&mut particle.position, &mut particle.velocity);
+                    for (position, size) in particle.position.iter_mut().zip(world_size).take(2) {
+                        *position = position.rem_euclid(size);
+                    }
+                }
+
+                if touching {
+                    particle.contact += dt;
+                }
+                let Particle {
+                    flavour,
+                    age,
+                    contact,
+                    ..
+                } = particle;
+                match live(rules, flavour, age, contact, dt) {
+                    Fate::Lives => {}
+                    Fate::Respawns => {
+                        *particle = Particle {
+                            position: respawn_position(seed, i as u32, world_size),
+                            velocity: [0.; 3],
+                            age: 0.,
+                            contact: 0.,
+                            ..*particle
+                        };
+                    }
+                    Fate::Dies => particle.flags |= Particle::DEAD,
+                }
+            });
+
+        let dead: Vec<_> = (0..particles.len())
+            .filter(|&i| !particles[i].is_alive())
+            .collect();
+        for ((batch, index), slot) in spawns(&batches).zip(dead) {
+            particles[slot] = spawned(batch, seed, index, world_size);
+        }
+    }
+}
+
+/// Particles as separate arrays, kept sorted by grid cell so each cell is a contiguous run that
+/// the force loop can vectorise over
+#[derive(Default, Debug, Clone)]
+pub struct ParticleSoa {
+    pub x: Vec<f32>,
+    pub y: Vec<f32>,
+    pub z: Vec<f32>,
+    pub vx: Vec<f32>,
+    pub vy: Vec<f32>,
+    pub vz: Vec<f32>,
+    pub flavour: Vec<u32>,
+    pub flags: Vec<u32>,
+    pub age: Vec<f32>,
+    pub contact: Vec<f32>,
+    /// index of each particle in the slice it was converted from
+    pub id: Vec<u32>,
+}
+
+impl ParticleSoa {
+    pub fn from_particles(particles: &[Particle]) -> Self {
+        Self {
+            x: particles.iter().map(|p| p.position[0]).collect(),
+            y: particles.iter().map(|p| p.position[1]).collect(),
+            z: particles.iter().map(|p| p.position[2]).collect(),
+            vx: particles.iter().map(|p| p.velocity[0]).collect(),
+            vy: particles.iter().map(|p| p.velocity[1]).collect(),
+            vz: particles.iter().map(|p| p.velocity[2]).collect(),
+            flavour: particles.iter().map(|p| p.flavour() as u32).collect(),
+            flags: particles.iter().map(|p| p.flags).collect(),
+            age: particles.iter().map(|p| p.age).collect(),
+            contact: particles.iter().map(|p| p.contact).collect(),
+            id: (0..particles.len() as u32).collect(),
+        }
+    }
+
+    /// Back to `Particle`s, in the order they were converted from
+    pub fn to_particles(&self) -> Vec<Particle> {
+        let mut particles = vec![Particle::default(); self.len()];
+        for i in 0..self.len() {
+            particles[self.id[i] as usize] = Particle {
+                position: [self.x[i], self.y[i], self.z[i]],
+                flavour: self.flavour[i],
+                velocity: [self.vx[i], self.vy[i], self.vz[i]],
+                flags: self.flags[i],
+                age: self.age[i],
+                contact: self.contact[i],
+                ..Default::default()
+            };
+        }
+        particles
+    }
+
+    /// Replaces particle `i` with `particle`, keeping its id
+    pub fn set(&mut self, i: usize, particle: &Particle) {
+        [self.x[i], self.y[i], self.z[i]] = particle.position;
+        [self.vx[i], self.vy[i], self.vz[i]] = particle.velocity;
+        self.flavour[i] = particle.flavour;
+        self.flags[i] = particle.flags;
+        self.age[i] = particle.age;
+        self.contact[i] = particle.contact;
+    }
+
+    pub fn len(&self) -> usize {
+        self.x.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.x.is_empty()
+    }
+
+    fn resize(&mut self, len: usize) {
+        self.x.resize(len, 0.);
+        self.y.resize(len, 0.);
+        self.z.resize(len, 0.);
+        self.vx.resize(len, 0.);
+        self.vy.resize(len, 0.);
+        self.vz.resize(len, 0.);
+        self.flavour.resize(len, 0);
+        self.flags.resize(len, 0);
+        self.age.resize(len, 0.);
+        self.contact.resize(len, 0.);
+        self.id.resize(len, 0);
+    }
+
+    fn copy_from(&mut self, to: usize, other: &ParticleSoa, from: usize) {
+        self.x[to] = other.x[from];
+        self.y[to] = other.y[from];
+        self.z[to] = other.z[from];
+        self.vx[to] = other.vx[from];
+        self.vy[to] = other.vy[from];
+        self.vz[to] = other.vz[from];
+        self.flavour[to] = other.flavour[from];
+        self.flags[to] = other.flags[from];
+        self.age[to] = other.age[from];
+        self.contact[to] = other.contact[from];
+        self.id[to] = other.id[from];
+    }
+}
+
+/// The force law between one flavour and each flavour, with the divisions done up front
+#[derive(Clone, Copy, Debug)]
+struct Interactions {
+    weight: [f32; MAX_FLAVOURS],
+    repulsion_distance: [f32; MAX_FLAVOURS],
+    inverse_repulsion_distance: [f32; MAX_FLAVOURS],
+    inverse_attraction_width: [f32; MAX_FLAVOURS],
+}
+
+/// Constants of the force law for one step, shared by the scalar and SIMD kernels
+#[derive(Clone, Debug)]
+struct Kernel {
+    max_distance_squared: f32,
+    inverse_max_distance: f32,
+    /// indexed by the flavour the force acts on
+    interactions: [Interactions; MAX_FLAVOURS],
+    world_size: [f32; 3],
+    /// with fewer than 3 cells on an axis a whole cell can't be shifted by one world size, so
+    /// each offset is wrapped individually. Cells span the whole depth, so z always is, unless
+    /// the world is flat.
+    minimum_image: [bool; 3],
+}
+
+impl Kernel {
+    fn new(
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        world_size: [f32; 3],
+        cells: [usize; 2],
+    ) -> Self {
+        let interactions = std::array::from_fn(|a| {
+            let mut interactions = Interactions {
+                weight: weights.0[a],
+                repulsion_distance: [0.; MAX_FLAVOURS],
+                inverse_repulsion_distance: [0.; MAX_FLAVOURS],
+                inverse_attraction_width: [0.; MAX_FLAVOURS],
+            };
+            for b in 0..MAX_FLAVOURS {
+                let repulsion =
+                    pair_repulsion_distance(params, properties.0[a].radius, properties.0[b].radius);
+                interactions.repulsion_distance[b] = repulsion;
+                interactions.inverse_repulsion_distance[b] = 1. / repulsion;
+                interactions.inverse_attraction_width[b] = 1. / (1. - repulsion);
+            }
+            interactions
+        });
+
+        Self {
+            max_distance_squared: params.max_distance * params.max_distance,
+            inverse_max_distance: 1. / params.max_distance,
+            interactions,
+            world_size,
+            minimum_image: [cells[0] < 3, cells[1] < 3, world_size[2] > 0.],
+        }
+    }
+
+    /// Offset from `origin` to particle `i`
+    fn offset(&self, origin: [f32; 3], particles: &ParticleSoa, i: usize) -> [f32; 3] {
+        let mut offset = [
+            particles.x[i] - origin[0],
+            particles.y[i] - origin[1],
+            particles.z[i] - origin[2],
+        ];
+        for ((offset, &size), &wrap) in offset
+            .iter_mut()
+            .zip(&self.world_size)
+            .zip(&self.minimum_image)
+        {
+            if wrap {
+                *offset -= size * (*offset / size).round();
+            }
+        }
+        offset
+    }
+
+    /// Whether a particle at `origin` is close enough to repel any particle of flavour `by` in
+    /// `range`
+    fn touches(
+        &self,
+        origin: [f32; 3],
+        interactions: &Interactions,
+        by: u32,
+        particles: &ParticleSoa,
+        range: Range<usize>,
+    ) -> bool {
+        let repulsion = interactions.repulsion_distance[by as usize];
+        range.filter(|&i| particles.flavour[i] == by).any(|i| {
+            let distance_squared: f32 = self
+                .offset(origin, particles, i)
+                .iter()
+                .map(|d| d * d)
+                .sum();
+            distance_squared > 0. && distance_squared.sqrt() * self.inverse_max_distance < repulsion
+        })
+    }
+
+    /// Sum of the unscaled forces from the particles in `range` on a particle at `origin` whose
+    /// flavour's `interactions` are given
+    fn accumulate_scalar(
+        &self,
+        origin: [f32; 3],
+        interactions: &Interactions,
+        particles: &ParticleSoa,
+        range: Range<usize>,
+    ) -> [f32; 3] {
+        let mut sum = [0.; 3];
+        for i in range {
+            let [dx, dy, dz] = self.offset(origin, particles, i);
+
+            let distance_squared = dx * dx + dy * dy + dz * dz;
+            if distance_squared <= 0. || distance_squared >= self.max_distance_squared {
+                continue;
+            }
+
+            // `force`, with the divisions hoisted out and r < 1 already known
+            let distance = distance_squared.sqrt();
+            let r = distance * self.inverse_max_distance;
+            let flavour = particles.flavour[i] as usize;
+            let repulsion = interactions.repulsion_distance[flavour];
+            let f = if r < repulsion {
+                r * interactions.inverse_repulsion_distance[flavour] - 1.
+            } else {
+                interactions.weight[flavour]
+                    * (1.
+                        - (2. * r - 1. - repulsion).abs()
+                            * interactions.inverse_attraction_width[flavour])
+            } / distance;
+            sum[0] += dx * f;
+            sum[1] += dy * f;
+            sum[2] += dz * f;
+        }
+        sum
+    }
+
+    #[cfg(not(feature = "simd"))]
+    fn accumulate(
+        &self,
+        origin: [f32; 3],
+        interactions: &Interactions,
+        particles: &ParticleSoa,
+        range: Range<usize>,
+    ) -> [f32; 3] {
+        self.accumulate_scalar(origin, interactions, particles, range)
+    }
+
+    /// `accumulate_scalar` eight particles at a time, with the remainder done by the scalar loop
+    #[cfg(feature = "simd")]
+    fn accumulate(
+        &self,
+        origin: [f32; 3],
+        interactions: &Interactions,
+        particles: &ParticleSoa,
+        range: Range<usize>,
+    ) -> [f32; 3] {
+        use wide::{f32x8, CmpGt, CmpLt};
+
+        const LANES: usize = 8;
+        let splat = f32x8::splat;
+        let lanes = |values: &[f32], start: usize| {
+            f32x8::new(values[start..start + LANES].try_into().unwrap())
+        };
+        let chunks = range.len() / LANES;
+
+        let mut sum = [f32x8::ZERO; 3];
+        for chunk in 0..chunks {
+            let start = range.start + chunk * LANES;
+            let gather = |table: &[f32; MAX_FLAVOURS]| {
+                f32x8::new(std::array::from_fn(|lane| {
+                    table[particles.flavour[start + lane] as usize]
+                }))
+            };
+            let weight = gather(&interactions.weight);
+            let repulsion_distance = gather(&interactions.repulsion_distance);
+
+            let mut offset = [
+                lanes(&particles.x, start) - splat(origin[0]),
+                lanes(&particles.y, start) - splat(origin[1]),
+                lanes(&particles.z, start) - splat(origin[2]),
+            ];
+            for ((offset, &size), &wrap) in offset
+                .iter_mut()
+                .zip(&self.world_size)
+                .zip(&self.minimum_image)
+            {
+                if wrap {
+                    let size = splat(size);
+                    *offset -= size * (*offset / size).round();
+                }
+            }
+            let [dx, dy, dz] = offset;
+
+            let distance_squared = dx * dx + dy * dy + dz * dz;
+            let in_range = distance_squared.cmp_gt(f32x8::ZERO)
+                & distance_squared.cmp_lt(splat(self.max_distance_squared));
+
+            let distance = distance_squared.sqrt();
+            let r = distance * splat(self.inverse_max_distance);
+            let repulsion = r * gather(&interactions.inverse_repulsion_distance) - f32x8::ONE;
+            let attraction = weight
+                * (f32x8::ONE
+                    - (r * splat(2.) - (f32x8::ONE + repulsion_distance)).abs()
+                        * gather(&interactions.inverse_attraction_width));
+            let f = r.cmp_lt(repulsion_distance).blend(repulsion, attraction);
+            // out of range lanes may have divided by zero, but blend discards them
+            let f = in_range.blend(f / distance, f32x8::ZERO);
+
+            sum[0] += dx * f;
+            sum[1] += dy * f;
+            sum[2] += dz * f;
+        }
+
+        let tail = self.accumulate_scalar(
+            origin,
+            interactions,
+            particles,
+            range.start + chunks * LANES..range.end,
+        );
+        std::array::from_fn(|axis| sum[axis].reduce_add() + tail[axis])
+    }
+}
+
+/// A neighbouring cell's particles, and the shift that brings them into the periodic image
+/// nearest the cell being processed
+#[derive(Clone, Copy, Debug, Default)]
+struct NeighbourCell {
+    start: usize,
+    end: usize,
+    shift: [f32; 2],
+}
+
+/// Cells at least `max_distance` wide over the cell-sorted particles, each spanning the whole
+/// depth of a 3D world. The dead are sorted into an extra cell after the last row, which is no
+/// cell's neighbour.
+#[derive(Default, Debug, Clone)]
+struct CellGrid {
+    columns: usize,
+    rows: usize,
+    world_size: (f32, f32),
+    /// `cell_start[c]..cell_start[c + 1]` is cell `c` in the sorted particles
+    cell_start: Vec<u32>,
+}
+
+impl CellGrid {
+    /// The distinct cells around `(column, row)`, at most 3x3
+    fn neighbour_cells(&self, column: usize, row: usize) -> ([NeighbourCell; 9], usize) {
+        let mut cells = [NeighbourCell::default(); 9];
+        let mut count = 0;
+
+        let axis = |index: usize, count: usize, size: f32| {
+            let span = count.min(3);
+            (0..span).map(move |i| {
+                let unwrapped = index as isize + i as isize - (span / 2) as isize;
+                // whole-world shifts are only exact when the axis has at least 3 cells
+                let shift = if span < 3 {
+                    0.
+                } else if unwrapped < 0 {
+                    -size
+                } else if unwrapped >= count as isize {
+                    size
+                } else {
+                    0.
+                };
+                (unwrapped.rem_euclid(count as isize) as usize, shift)
+            })
+        };
+
+        for (r, shift_y) in axis(row, self.rows, self.world_size.1) {
+            for (c, shift_x) in axis(column, self.columns, self.world_size.0) {
+                let cell = r * self.columns + c;
+                cells[count] = NeighbourCell {
+                    start: self.cell_start[cell] as usize,
+                    end: self.cell_start[cell + 1] as usize,
+                    shift: [shift_x, shift_y],
+                };
+                count += 1;
+            }
+        }
+
+        (cells, count)
+    }
+}
+
+/// The fast CPU backend: particles in `ParticleSoa` sorted into grid cells each step, with
+/// forces accumulated in parallel over rows of cells
+#[derive(Default, Debug, Clone)]
+pub struct CpuSimulation {
+    particles: ParticleSoa,
+    /// sorting target, swapped with `particles`
+    scratch: ParticleSoa,
+    grid: CellGrid,
+    cells: Vec<u32>,
+    cursor: Vec<u32>,
+    acceleration_x: Vec<f32>,
+    acceleration_y: Vec<f32>,
+    acceleration_z: Vec<f32>,
+    /// whether each particle is touching the flavour that converts it
+    touching: Vec<bool>,
+    world_size: [f32; 3],
+    spawner: Spawner,
+    obstacles: ObstacleField,
+    forces: ForceField,
+}
+
+impl CpuSimulation {
+    pub fn len(&self) -> usize {
+        self.particles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.particles.is_empty()
+    }
+
+    /// Current positions and flavours of the living particles, in cell order
+    pub fn living(&self) -> impl Iterator<Item = ([f32; 3], usize)> + '_ {
+        self.particles
+            .x
+            .iter()
+            .zip(&self.particles.y)
+            .zip(&self.particles.z)
+            .zip(&self.particles.flavour)
+            .zip(&self.particles.flags)
+            .filter(|(_, &flags)| flags & Particle::DEAD == 0)
+            .map(|((((&x, &y), &z), &flavour), _)| ([x, y, z], flavour as usize))
+    }
+
+    pub fn to_particles(&self) -> Vec<Particle> {
+        self.particles.to_particles()
+    }
+
+    /// Counting sort of the particles into cells at least `max_distance` wide
+    fn sort(&mut self, max_distance: f32) {
+        let grid = &mut self.grid;
+        let [width, height, _] = self.world_size;
+        grid.world_size = (width, height);
+        grid.columns = ((width / max_distance) as usize).max(1);
+        grid.rows = ((height / max_distance) as usize).max(1);
+        let (columns, rows) = (grid.columns, grid.rows);
+        let cell_width = width / columns as f32;
+        let cell_height = height / rows as f32;
+        let cell_count = columns * rows;
+
+        self.cells.clear();
+        self.cells.extend(
+            self.particles
+                .x
+                .iter()
+                .zip(&self.particles.y)
+                .zip(&self.particles.flags)
+                .map(|((&x, &y), &flags)| {
+                    if flags & Particle::DEAD != 0 {
+                        return cell_count as u32;
+                    }
+                    let column = ((x / cell_width) as usize).min(columns - 1);
+                    let row = ((y / cell_height) as usize).min(rows - 1);
+                    (row * columns + column) as u32
+                }),
+        );
+
+        // counting sort: histogram, prefix sum, scatter
+        grid.cell_start.clear();
+        grid.cell_start.resize(cell_count + 2, 0);
+        for &cell in &self.cells {
+            grid.cell_start[cell as usize + 1] += 1;
+        }
+        for cell in 0..=cell_count {
+            grid.cell_start[cell + 1] += grid.cell_start[cell];
+        }
+
+        self.cursor.clear();
+        self.cursor.extend_from_slice(&grid.cell_start);
+        self.scratch.resize(self.particles.len());
+        for (i, &cell) in self.cells.iter().enumerate() {
+            let to = self.cursor[cell as usize] as usize;
+            self.cursor[cell as usize] += 1;
+            self.scratch.copy_from(to, &self.particles, i);
+        }
+        std::mem::swap(&mut self.particles, &mut self.scratch);
+    }
+
+    fn step_once(
+        &mut self,
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        rules: &[LifecycleRule; MAX_FLAVOURS],
+        seed: u32,
+        dt: f32,
+    ) {
+        self.sort(params.max_distance);
+
+        let len = self.particles.len();
+        self.acceleration_x.resize(len, 0.);
+        self.acceleration_y.resize(len, 0.);
+        self.acceleration_z.resize(len, 0.);
+        self.touching.resize(len, false);
+
+        let Self {
+            particles,
+            grid,
+            acceleration_x,
+            acceleration_y,
+            acceleration_z,
+            touching,
+            world_size,
+            forces,
+            ..
+        } = self;
+        let kernel = Kernel::new(
+            weights,
+            params,
+            properties,
+            *world_size,
+            [grid.columns, grid.rows],
+        );
+        let scales = properties
+            .0
+            .map(|flavour| params.max_distance * params.force_scale / flavour.mass);
+
+        // cells are row-major, so each row of cells is a contiguous run of particles
+        let mut rows = Vec::with_capacity(grid.rows);
+        let mut rest_x = &mut acceleration_x[..];
+        let mut rest_y = &mut acceleration_y[..];
+        let mut rest_z = &mut acceleration_z[..];
+        let mut rest_touching = &mut touching[..];
+        for row in 0..grid.rows {
+            let start = grid.cell_start[row * grid.columns] as usize;
+            let end = grid.cell_start[(row + 1) * grid.columns] as usize;
+            let (row_x, tail_x) = rest_x.split_at_mut(end - start);
+            let (row_y, tail_y) = rest_y.split_at_mut(end - start);
+            let (row_z, tail_z) = rest_z.split_at_mut(end - start);
+            let (row_touching, tail_touching) = rest_touching.split_at_mut(end - start);
+            rows.push((row, start, [row_x, row_y, row_z], row_touching));
+            rest_x = tail_x;
+            rest_y = tail_y;
+            rest_z = tail_z;
+            rest_touching = tail_touching;
+        }
+
+        let particles = &*particles;
+        let (grid, forces) = (&*grid, &*forces);
+        rows.into_par_iter()
+            .for_each(|(row, row_start, row_acceleration, row_touching)| {
+                for column in 0..grid.columns {
+                    let cell = row * grid.columns + column;
+                    let (neighbours, count) = grid.neighbour_cells(column, row);
+
+                    for i in grid.cell_start[cell] as usize..grid.cell_start[cell + 1] as usize {
+                        let flavour = particles.flavour[i] as usize;
+                        let interactions = &kernel.interactions[flavour];
+                        let rule = &rules[flavour];
+                        let mut sum = [0.; 3];
+                        let mut touching = false;
+                        for neighbour in &neighbours[..count] {
+                            let origin = [
+                                particles.x[i] - neighbour.shift[0],
+                                particles.y[i] - neighbour.shift[1],
+                                particles.z[i],
+                            ];
+                            let range = neighbour.start..neighbour.end;
+                            let acceleration =
+                                kernel.accumulate(origin, interactions, particles, range.clone());
+                            for axis in 0..3 {
+                                sum[axis] += acceleration[axis];
+                            }
+                            // only flavours with a conversion rule pay for the search
+                            touching = touching
+                                || rule.converts()
+                                    && kernel.touches(
+                                        origin,
+                                        interactions,
+                                        rule.convert_by,
+                                        particles,
+                                        range,
+                                    );
+                        }
+                        let position = [particles.x[i], particles.y[i], particles.z[i]];
+                        let mass = properties.0[flavour].mass;
+                        let external = forces.acceleration(position, mass, kernel.world_size);
+                        for axis in 0..3 {
+                            row_acceleration[axis][i - row_start] =
+                                sum[axis] * scales[flavour] + external[axis];
+                        }
+                        row_touching[i - row_start] = touching;
+                    }
+                }
+            });
+
+        let frictions = friction_factors(params, properties, dt);
+        let obstacles = &self.obstacles;
+        let [width, height, depth] = self.world_size;
+        let ParticleSoa {
+            x,
+            y,
+            z,
+            vx,
+            vy,
+            vz,
+            flavour,
+            flags,
+            age,
+            contact,
+            id,
+        } = &mut self.particles;
+        (
+            (x.par_iter_mut(), y.par_iter_mut(), z.par_iter_mut()),
+            (vx.par_iter_mut(), vy.par_iter_mut(), vz.par_iter_mut()),
+            (
+                flavour.par_iter_mut(),
+                flags.par_iter_mut(),
+                age.par_iter_mut(),
+                contact.par_iter_mut(),
+            ),
+            (
+                self.acceleration_x.par_iter(),
+                self.acceleration_y.par_iter(),
+                self.acceleration_z.par_iter(),
+            ),
+            (self.touching.par_iter(), id.par_iter()),
+        )
+            .into_par_iter()
+            .for_each(
+                |(
+                    (x, y, z),
+                    (vx, vy, vz),
+                    (flavour, flags, age, contact),
+                    (&ax, &ay, &az),
+                    (&touching, &id),
+                )| {
+                    if *flags & Particle::DEAD != 0 {
+                        return;
+                    }
+                    let own = *flavour as usize;
+                    [*vx, *vy, *vz] = accelerate(
+                        [*vx, *vy, *vz],
+                        [ax, ay, az],
+                        frictions[own],
+                        properties.0[own].max_speed,
+                        dt,
+                    );
+                    *x = (*x + *vx * dt).rem_euclid(width);
+                    *y = (*y + *vy * dt).rem_euclid(height);
+                    // a flat world has nothing to integrate in z, but may have walls
+                    if depth > 0. {
+                        *z = (*z + *vz * dt).rem_euclid(depth);
+                    } else {
+                        let (mut position, mut velocity) = ([*x, *y, *z], [*vx, *vy, *vz]);
+                        obstacles.collide(&mut position, &mut velocity);
+                        *x = position[0].rem_euclid(width);
+                        *y = position[1].rem_euclid(height);
+                        [*vx, *vy] = [velocity[0], velocity[1]];
+                    }
+
+                    if touching {
+                        *contact += dt;
+                    }
+                    match live(rules, flavour, age, contact, dt) {
+                        Fate::Lives => {}
+                        Fate::Respawns => {
+                            [*x, *y, *z] = respawn_position(seed, id, [width, height, depth]);
+                            [*vx, *vy, *vz] = [0.; 3];
+                            *age = 0.;
+                            *contact = 0.;
+                        }
+                        Fate::Dies => *flags |= Particle::DEAD,
+                    }
+                },
+            );
+    }
+
+    /// Fills the dead particles' slots with `batches`' spawns, in the order the particles were
+    /// uploaded in so it matches `CpuStepper`
+    fn spawn(&mut self, batches: &[SpawnBatch], seed: u32) {
+        if spawn_count(batches) == 0 {
+            return;
+        }
+        let particles = &mut self.particles;
+        let mut dead: Vec<_> = (0..particles.len())
+            .filter(|&i| particles.flags[i] & Particle::DEAD != 0)
+            .collect();
+        dead.sort_unstable_by_key(|&i| particles.id[i]);
+
+        for ((batch, index), slot) in spawns(batches).zip(dead) {
+            particles.set(slot, &spawned(batch, seed, index, self.world_size));
+        }
+    }
+}
+
+impl SimulationBackend for CpuSimulation {
+    fn init(&mut self, particle_count: usize, world_size: [f32; 3]) {
+        self.particles = ParticleSoa::default();
+        self.particles.resize(particle_count);
+        self.world_size = world_size;
+    }
+
+    fn upload(&mut self, particles: &[Particle]) {
+        self.particles = ParticleSoa::from_particles(particles);
+
+        let ParticleSoa { x, y, z, vz, .. } = &mut self.particles;
+        let [width, height, depth] = self.world_size;
+        for (x, y) in x.iter_mut().zip(y) {
+            *x = x.rem_euclid(width);
+            *y = y.rem_euclid(height);
+        }
+        for (z, vz) in z.iter_mut().zip(vz) {
+            if depth > 0. {
+                *z = z.rem_euclid(depth);
+            } else {
+                *z = 0.;
+                *vz = 0.;
+            }
+        }
+    }
+
+    fn step(
+        &mut self,
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        lifecycle: &Lifecycle,
+        dt: f32,
+        steps: u32,
+    ) {
+        // every step gets its own seed and spawns, as with `CpuStepper`
+        for _ in 0..steps {
+            let (batches, seed) = self.spawner.advance(&lifecycle.emitters, dt);
+            self.step_once(weights, params, properties, &lifecycle.rules, seed, dt);
+            self.spawn(&batches, seed);
+        }
+    }
+
+    fn set_obstacles(&mut self, obstacles: &ObstacleField) {
+        self.obstacles = obstacles.clone();
+    }
+
+    fn set_forces(&mut self, forces: &ForceField) {
+        self.forces = forces.clone();
+    }
+
+    fn readback(&mut self) -> Vec<Particle> {
+        self.to_particles()
+    }
+}
@@ -0,0 +1,126 @@
+//! Drawing 2D particles as a smooth density field instead of dots. Each particle splats a
+//! gaussian of its flavour's colour, and where the kernels overlap their weights add up and their
+//! colours blend, which the resolve pass shades as cell-like blobs or soft clouds. The 3D view
+//! always draws spheres.
+
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use bytemuck::{Pod, Zeroable};
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RenderMode {
+    /// a disc per particle, adding up where they overlap
+    #[default]
+    Dots,
+    /// solid blobs where the density passes the threshold, with a bright rim at the edge
+    Membrane,
+    /// the blended colours fading in with the density, fully opaque at the threshold
+    Blend,
+}
+
+impl RenderMode {
+    pub const ALL: [RenderMode; 3] = [RenderMode::Dots, RenderMode::Membrane, RenderMode::Blend];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RenderMode::Dots => "dots",
+            RenderMode::Membrane => "membrane",
+            RenderMode::Blend => "blend",
+        }
+    }
+}
+
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, PartialEq)]
+pub struct DensityField {
+    pub mode: RenderMode,
+    /// how far a particle's kernel reaches, in pixels
+    pub radius: f32,
+    /// density where `Membrane` starts and `Blend` becomes opaque. A lone particle peaks at 1.
+    pub threshold: f32,
+}
+
+impl Default for DensityField {
+    fn default() -> Self {
+        Self {
+            mode: RenderMode::default(),
+            radius: 12.,
+            threshold: 0.8,
+        }
+    }
+}
+
+/// `DensityField` as render.wgsl reads it
+#[derive(Pod, Zeroable, Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct DensityUniform {
+    /// `RenderMode` as its index
+    pub mode: u32,
+    pub radius: f32,
+    pub threshold: f32,
+    pub _padding: u32,
+}
+
+impl DensityField {
+    pub fn uniform(&self) -> DensityUniform {
+        DensityUniform {
+            mode: self.mode as u32,
+            radius: self.radius,
+            threshold: self.threshold,
+            _padding: 0,
+        }
+    }
+
+    /// `kernel` in render.wgsl: a gaussian that's 1 at the particle and cut off at `radius`, where
+    /// it's three standard deviations out
+    pub fn kernel(&self, distance_squared: f32) -> f32 {
+        if distance_squared > self.radius * self.radius {
+            return 0.;
+        }
+        let sigma = self.radius / 3.;
+        (-distance_squared / (2. * sigma * sigma)).exp()
+    }
+
+    /// `shade` in render.wgsl, for a pixel where particles added up `light` and `weight`, or
+    /// `None` to leave the background showing
+    pub fn shade(&self, light: [f32; 3], weight: f32, background: [f32; 4]) -> Option<[f32; 4]> {
+        if weight <= 0. {
+            return None;
+        }
+        let [r, g, b] = light;
+        // the flavours' colours, weighted by how much each contributes
+        let colour = [r / weight, g / weight, b / weight];
+        match self.mode {
+            RenderMode::Dots => Some([r, g, b, 1.]),
+            RenderMode::Membrane => {
+                if weight < self.threshold {
+                    return None;
+                }
+                // brighter than 1 at the edge, so the bloom outlines each blob
+                let rim = 1. - smoothstep(self.threshold, self.threshold * 1.5, weight);
+                let brightness = 0.5 + rim;
+                Some([
+                    colour[0] * brightness,
+                    colour[1] * brightness,
+                    colour[2] * brightness,
+                    1.,
+                ])
+            }
+            RenderMode::Blend => {
+                let t = smoothstep(0., self.threshold, weight);
+                let mix = |from: f32, to: f32| from + (to - from) * t;
+                Some([
+                    mix(background[0], colour[0]),
+                    mix(background[1], colour[1]),
+                    mix(background[2], colour[2]),
+                    1.,
+                ])
+            }
+        }
+    }
+}
+
+/// WGSL's `smoothstep`
+fn smoothstep(low: f32, high: f32, x: f32) -> f32 {
+    let t = ((x - low) / (high - low)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
@@ -0,0 +1,297 @@
+//! Global forces acting on every particle besides the other particles: uniform gravity, point
+//! attractors and vortices, and a flow field over the world's width and height, either imported
+//! from an image or generated from noise that drifts over time.
+//!
+//! The flow field is sampled into a coarse periodic grid on the CPU, which both backends then
+//! interpolate, so they see exactly the same vectors however the field was made.
+
+use std::f32::consts::TAU;
+
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    cpu::wrapped_offset, lifecycle::hash, objects::SimulationSteps, obstacles::decode_png,
+};
+
+/// Upper bound on attractors and vortices, which `update` loops over
+pub const MAX_SOURCES: usize = 8;
+/// Cells of the flow grid along each side of the world
+pub const FLOW_SIZE: usize = 32;
+
+/// An attractor or vortex. Its pull, or spin, is strongest at its centre and fades to nothing at
+/// `radius`.
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct FieldSource {
+    /// z is ignored in a flat world
+    pub position: [f32; 3],
+    pub kind: u32,
+    /// acceleration at the centre of a particle with a mass of 1, negative to push away or spin
+    /// the other way
+    pub strength: f32,
+    pub radius: f32,
+    pub _padding: [u32; 2],
+}
+
+impl FieldSource {
+    pub const ATTRACTOR: u32 = 0;
+    /// spins particles around the z axis
+    pub const VORTEX: u32 = 1;
+}
+
+// `struct FieldSource` in simulation.wgsl
+const _: () = assert!(std::mem::size_of::<FieldSource>() == 32);
+
+/// Where the flow field comes from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Flow {
+    #[default]
+    None,
+    /// gradient noise with `cells` features across the world, turned into directions, that drifts
+    /// `speed` features per second
+    Noise { cells: u32, speed: f32 },
+    /// `FLOW_SIZE` x `FLOW_SIZE` vectors, row-major with y down, whose x and y are encoded like
+    /// an image's red and green, 128 being 0
+    Image(Vec<[u8; 2]>),
+}
+
+/// The forces as they're edited and stored in presets. The default has none.
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub struct ExternalForces {
+    /// acceleration, the same whatever the mass
+    pub gravity: [f32; 3],
+    /// at most `MAX_SOURCES`
+    pub sources: Vec<FieldSource>,
+    pub flow: Flow,
+    /// acceleration of a particle with a mass of 1 along a flow vector of length 1
+    pub flow_strength: f32,
+}
+
+impl ExternalForces {
+    /// Whether the field changes over time even when the forces don't
+    pub fn animated(&self) -> bool {
+        matches!(self.flow, Flow::Noise { speed, .. } if speed != 0.)
+    }
+
+    /// The field `time` simulated seconds in
+    pub fn field(&self, time: f32) -> ForceField {
+        let mut uniform = ForceFieldUniform {
+            gravity: self.gravity,
+            source_count: self.sources.len().min(MAX_SOURCES) as u32,
+            flow_strength: self.flow_strength,
+            ..Zeroable::zeroed()
+        };
+        for (slot, source) in uniform.sources.iter_mut().zip(&self.sources) {
+            *slot = *source;
+        }
+
+        let flow = match &self.flow {
+            Flow::None => {
+                uniform.flow_strength = 0.;
+                vec![[0.; 2]; FLOW_SIZE * FLOW_SIZE]
+            }
+            Flow::Noise { cells, speed } => noise_flow((*cells).max(1), time * speed),
+            Flow::Image(vectors) => vectors
+                .iter()
+                .map(|vector| vector.map(|c| c as f32 / 127.5 - 1.))
+                .collect(),
+        };
+        ForceField { uniform, flow }
+    }
+}
+
+/// A flow field from a PNG, stretched over the world, with red and green as the vectors' x and y
+pub fn flow_from_png(bytes: &[u8]) -> Result<Flow, String> {
+    let image = decode_png(bytes)?;
+    let (width, height) = (
+        image.texture_descriptor.size.width as usize,
+        image.texture_descriptor.size.height as usize,
+    );
+    let mut vectors = Vec::with_capacity(FLOW_SIZE * FLOW_SIZE);
+    for j in 0..FLOW_SIZE {
+        for i in 0..FLOW_SIZE {
+            // the pixel under the cell's centre
+            let x = (2 * i + 1) * width / (2 * FLOW_SIZE);
+            let y = (2 * j + 1) * height / (2 * FLOW_SIZE);
+            let pixel = (y * width + x) * 4;
+            vectors.push([image.data[pixel], image.data[pixel + 1]]);
+        }
+    }
+    Ok(Flow::Image(vectors))
+}
+
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1., 1., 0.],
+    [-1., 1., 0.],
+    [1., -1., 0.],
+    [-1., -1., 0.],
+    [1., 0., 1.],
+    [-1., 0., 1.],
+    [1., 0., -1.],
+    [-1., 0., -1.],
+    [0., 1., 1.],
+    [0., -1., 1.],
+    [0., 1., -1.],
+    [0., -1., -1.],
+];
+
+/// Perlin's gradient noise, roughly in -1..1, repeating every `period` in x and y so the flow
+/// field wraps like the world
+fn noise(point: [f32; 3], period: u32) -> f32 {
+    let cell = point.map(f32::floor);
+    let t: [f32; 3] = std::array::from_fn(|axis| point[axis] - cell[axis]);
+    let fade = t.map(|t| t * t * t * (t * (t * 6. - 15.) + 10.));
+    let lattice = |axis: usize, offset: usize| {
+        let index = cell[axis] as i64 + offset as i64;
+        if axis < 2 {
+            index.rem_euclid(period as i64) as u32
+        } else {
+            index as u32
+        }
+    };
+
+    // corner bits are x, y and z from the lowest
+    let corners: [f32; 8] = std::array::from_fn(|corner| {
+        let offset = [corner & 1, corner >> 1 & 1, corner >> 2];
+        let gradient = GRADIENTS[hash(
+            lattice(0, offset[0]) ^ hash(lattice(1, offset[1]) ^ hash(lattice(2, offset[2]))),
+        ) as usize
+            % GRADIENTS.len()];
+        (0..3)
+            .map(|axis| gradient[axis] * (t[axis] - offset[axis] as f32))
+            .sum()
+    });
+
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x: [f32; 4] =
+        std::array::from_fn(|pair| lerp(corners[2 * pair], corners[2 * pair + 1], fade[0]));
+    let y = [lerp(x[0], x[1], fade[1]), lerp(x[2], x[3], fade[1])];
+    lerp(y[0], y[1], fade[2])
+}
+
+/// Unit vectors pointing along the noise, sampled at the flow grid's cell centres
+fn noise_flow(cells: u32, time: f32) -> Vec<[f32; 2]> {
+    let scale = cells as f32 / FLOW_SIZE as f32;
+    let mut vectors = Vec::with_capacity(FLOW_SIZE * FLOW_SIZE);
+    for j in 0..FLOW_SIZE {
+        for i in 0..FLOW_SIZE {
+            let point = [(i as f32 + 0.5) * scale, (j as f32 + 0.5) * scale, time];
+            let angle = noise(point, cells) * TAU;
+            vectors.push([angle.cos(), angle.sin()]);
+        }
+    }
+    vectors
+}
+
+/// Everything but the flow grid, as `update` reads it
+#[derive(Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
+#[repr(C)]
+pub struct ForceFieldUniform {
+    pub gravity: [f32; 3],
+    pub source_count: u32,
+    pub flow_strength: f32,
+    pub _padding: [u32; 3],
+    pub sources: [FieldSource; MAX_SOURCES],
+}
+
+// `struct ForceField` in simulation.wgsl
+const _: () = assert!(std::mem::size_of::<ForceFieldUniform>() == 32 + 32 * MAX_SOURCES);
+
+/// The forces as the simulation applies them. The default has none.
+#[derive(Resource, ExtractResource, Clone, Debug, PartialEq)]
+pub struct ForceField {
+    pub uniform: ForceFieldUniform,
+    /// `FLOW_SIZE` x `FLOW_SIZE` vectors over the world's width and height, row-major
+    pub flow: Vec<[f32; 2]>,
+}
+
+impl Default for ForceField {
+    fn default() -> Self {
+        ExternalForces::default().field(0.)
+    }
+}
+
+impl ForceField {
+    /// The flow grid's vector in cell `(i, j)`, wrapping around the world
+    fn flow_vector(&self, i: i32, j: i32) -> [f32; 2] {
+        let n = FLOW_SIZE as i32;
+        self.flow[(j.rem_euclid(n) * n + i.rem_euclid(n)) as usize]
+    }
+
+    /// The flow grid interpolated between cell centres, see `sample_flow` in simulation.wgsl
+    pub fn sample_flow(&self, position: [f32; 2], world_size: [f32; 3]) -> [f32; 2] {
+        let cell = [0, 1].map(|axis| position[axis] / world_size[axis] * FLOW_SIZE as f32 - 0.5);
+        let base = cell.map(f32::floor);
+        let t = [cell[0] - base[0], cell[1] - base[1]];
+        let (i, j) = (base[0] as i32, base[1] as i32);
+        // like WGSL's mix
+        let mix = |a: [f32; 2], b: [f32; 2], t: f32| [0, 1].map(|c| a[c] * (1. - t) + b[c] * t);
+        let bottom = mix(self.flow_vector(i, j), self.flow_vector(i + 1, j), t[0]);
+        let top = mix(
+            self.flow_vector(i, j + 1),
+            self.flow_vector(i + 1, j + 1),
+            t[0],
+        );
+        mix(bottom, top, t[1])
+    }
+
+    /// Acceleration of a particle of `mass` at `position`, with z ignored in a flat world. See
+    /// `external_acceleration` in simulation.wgsl.
+    pub fn acceleration(&self, position: [f32; 3], mass: f32, world_size: [f32; 3]) -> [f32; 3] {
+        let axes = if world_size[2] > 0. { 3 } else { 2 };
+        let uniform = &self.uniform;
+        let mut acceleration = [0.; 3];
+        acceleration[..axes].copy_from_slice(&uniform.gravity[..axes]);
+
+        for source in &uniform.sources[..uniform.source_count as usize] {
+            let mut offset = [0.; 3];
+            for axis in 0..axes {
+                offset[axis] =
+                    wrapped_offset(position[axis], source.position[axis], world_size[axis]);
+            }
+            let distance = offset.iter().map(|d| d * d).sum::<f32>().sqrt();
+            if distance <= 0. || distance >= source.radius {
+                continue;
+            }
+
+            let strength = source.strength * (1. - distance / source.radius) / mass;
+            let direction = if source.kind == FieldSource::VORTEX {
+                [-offset[1], offset[0], 0.]
+            } else {
+                offset
+            };
+            for axis in 0..axes {
+                acceleration[axis] += direction[axis] / distance * strength;
+            }
+        }
+
+        let flow = self.sample_flow([position[0], position[1]], world_size);
+        for axis in 0..2 {
+            acceleration[axis] += flow[axis] * uniform.flow_strength / mass;
+        }
+        acceleration
+    }
+}
+
+/// Keeps the field in step with the forces, and the noise drifting with simulated time
+pub struct Forces;
+impl Plugin for Forces {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ExternalForces>()
+            .init_resource::<ForceField>()
+            .add_systems(PostUpdate, update_field);
+    }
+}
+
+pub(crate) fn update_field(
+    forces: Res<ExternalForces>,
+    steps: Res<SimulationSteps>,
+    mut time: Local<f32>,
+    mut field: ResMut<ForceField>,
+) {
+    *time += steps.dt * steps.count as f32;
+    if forces.is_changed() || forces.animated() {
+        *field = forces.field(*time);
+    }
+}
@@ -0,0 +1,238 @@
+//! The render image is HDR, so where particles overlap their light adds up past 1. The window's
+//! camera blooms what's brighter than a threshold, making dense clusters glow, then tonemaps the
+//! result back into the display's range.
+//!
+//! The CPU backend writes the image's half floats itself, and frame capture reads them back and
+//! tonemaps them like the camera, so the conversions live here too.
+
+use bevy::{
+    core_pipeline::{
+        bloom::{BloomCompositeMode, BloomPrefilterSettings, BloomSettings},
+        tonemapping::Tonemapping,
+    },
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::TextureFormat},
+};
+
+/// Format of the render image
+pub const RENDER_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+/// Bytes of one pixel of the render image
+pub const PIXEL_SIZE: usize = 8;
+
+/// The tonemappers that don't need Bevy's `tonemapping_luts` feature, which isn't enabled
+pub const TONEMAPPERS: [(Tonemapping, &str); 5] = [
+    (Tonemapping::None, "none (clip)"),
+    (Tonemapping::Reinhard, "Reinhard"),
+    (Tonemapping::ReinhardLuminance, "Reinhard luminance"),
+    (Tonemapping::AcesFitted, "ACES fitted"),
+    (
+        Tonemapping::SomewhatBoringDisplayTransform,
+        "somewhat boring",
+    ),
+];
+
+/// How the window's camera post-processes the render image. Bloom isn't available on WebGL2.
+/// Captured frames are tonemapped the same way, but without the bloom.
+#[derive(Resource, ExtractResource, Clone, Copy, Debug, PartialEq)]
+pub struct Glow {
+    /// how much of the scattered light is added back, 0 for no bloom
+    pub intensity: f32,
+    /// brightness above which light scatters. A single particle is at most 1, so the default
+    /// only blooms where they overlap.
+    pub threshold: f32,
+    pub tonemapping: Tonemapping,
+}
+
+impl Default for Glow {
+    fn default() -> Self {
+        Self {
+            intensity: 0.3,
+            threshold: 1.5,
+            tonemapping: Tonemapping::AcesFitted,
+        }
+    }
+}
+
+impl Glow {
+    pub fn bloom(&self) -> BloomSettings {
+        BloomSettings {
+            intensity: self.intensity,
+            prefilter_settings: BloomPrefilterSettings {
+                threshold: self.threshold,
+                threshold_softness: 0.3,
+            },
+            // thresholding isn't energy conserving, so Bevy recommends adding the bloom
+            composite_mode: BloomCompositeMode::Additive,
+            ..BloomSettings::NATURAL
+        }
+    }
+}
+
+/// Keeps the window's camera in step with `Glow`
+pub struct Hdr;
+impl Plugin for Hdr {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Glow>().add_systems(Update, apply_glow);
+    }
+}
+
+fn apply_glow(
+    glow: Res<Glow>,
+    mut cameras: Query<(&mut Tonemapping, Option<&mut BloomSettings>), With<Camera2d>>,
+) {
+    if !glow.is_changed() {
+        return;
+    }
+    for (mut tonemapping, bloom) in &mut cameras {
+        *tonemapping = glow.tonemapping;
+        if let Some(mut bloom) = bloom {
+            *bloom = glow.bloom();
+        }
+    }
+}
+
+/// The nearest half float, saturating to infinity. Values too small for a subnormal are flushed
+/// to zero.
+pub fn to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = (bits >> 16) as u16 & 0x8000;
+    if value.is_nan() {
+        return sign | 0x7e00;
+    }
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent >= 31 {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // subnormal, with the implicit leading 1 shifted in
+        return sign | ((mantissa | 0x80_0000) >> (14 - exponent)) as u16;
+    }
+    // rounding up can carry into the exponent, which is still the nearest
+    let half = sign | (exponent as u16) << 10 | (mantissa >> 13) as u16;
+    half + ((mantissa >> 12) & 1) as u16
+}
+
+pub fn from_f16(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1. } else { 1. };
+    let exponent = (half >> 10 & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        31 if mantissa == 0. => f32::INFINITY,
+        31 => f32::NAN,
+        _ => (1. + mantissa / 1024.) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// One pixel of the render image
+pub fn encode_pixel(colour: [f32; 4]) -> [u8; PIXEL_SIZE] {
+    let mut pixel = [0; PIXEL_SIZE];
+    for (bytes, channel) in pixel.chunks_exact_mut(2).zip(colour) {
+        bytes.copy_from_slice(&to_f16(channel).to_le_bytes());
+    }
+    pixel
+}
+
+/// Render image pixels as sRGB RGBA8, tonemapped by the same curve as the window's camera but
+/// without the bloom, which only the camera applies. Alpha is clipped.
+pub fn to_rgba8(data: &[u8], tonemapping: Tonemapping) -> Vec<u8> {
+    let quantise = |channel: f32| (channel.clamp(0., 1.) * 255.).round() as u8;
+    data.chunks_exact(PIXEL_SIZE)
+        .flat_map(|pixel| {
+            let [r, g, b, a] =
+                [0, 2, 4, 6].map(|i| from_f16(u16::from_le_bytes([pixel[i], pixel[i + 1]])));
+            let [r, g, b] =
+                tonemap([r, g, b], tonemapping).map(|channel| quantise(to_srgb(channel)));
+            [r, g, b, quantise(a)]
+        })
+        .collect()
+}
+
+/// Bevy's tonemapping curves, from `tonemapping_shared.wgsl`. The ones needing lookup tables
+/// aren't offered, so they clip like `None`.
+pub fn tonemap(colour: [f32; 3], tonemapping: Tonemapping) -> [f32; 3] {
+    // the shader also drops negatives first
+    let colour = colour.map(|channel| channel.max(0.));
+    match tonemapping {
+        Tonemapping::Reinhard => colour.map(|channel| channel / (1. + channel)),
+        // scaling to the new luminance, old / (1 + old), without dividing by the old one
+        Tonemapping::ReinhardLuminance => {
+            let old = luminance(colour);
+            colour.map(|channel| channel / (1. + old))
+        }
+        Tonemapping::AcesFitted => aces_fitted(colour),
+        Tonemapping::SomewhatBoringDisplayTransform => somewhat_boring_display_transform(colour),
+        _ => colour,
+    }
+}
+
+/// Rec. 709 luminance
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Multiplies a row vector by a matrix given as rows, as WGSL's `vector * matrix` does with
+/// Bevy's column-major constructors
+fn mul(colour: [f32; 3], matrix: [[f32; 3]; 3]) -> [f32; 3] {
+    matrix.map(|row| row.iter().zip(colour).map(|(m, c)| m * c).sum())
+}
+
+fn aces_fitted(colour: [f32; 3]) -> [f32; 3] {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    const RGB_TO_RRT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    const ODT_TO_RGB: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let fitted = mul(colour, RGB_TO_RRT).map(|v| {
+        let a = v * (v + 0.0245786) - 0.000090537;
+        let b = v * (0.983729 * v + 0.432951) + 0.238081;
+        a / b
+    });
+    mul(fitted, ODT_TO_RGB).map(|channel| channel.clamp(0., 1.))
+}
+
+fn somewhat_boring_display_transform(colour: [f32; 3]) -> [f32; 3] {
+    let curve = |v: f32| 1. - (-v).exp();
+    let mix = |a: f32, b: f32, t: f32| a + (b - a) * t;
+
+    let [y, cb, cr] = mul(
+        colour,
+        [
+            [0.2126, 0.7152, 0.0722],
+            [-0.1146, -0.3854, 0.5],
+            [0.5, -0.4542, -0.0458],
+        ],
+    );
+    let bt = curve((cb * cb + cr * cr).sqrt() * 2.4);
+    let desat = ((bt - 0.7) * 0.8).max(0.).powi(2);
+
+    let scale = (curve(y) / luminance(colour).max(1e-5)).max(0.);
+    let mut mapped = [0.; 3];
+    for (mapped, channel) in mapped.iter_mut().zip(colour) {
+        let saturated = channel * scale;
+        let desaturated = curve(mix(channel, y, desat));
+        *mapped = mix(saturated, desaturated, bt * bt) * 0.97;
+    }
+    mapped
+}
+
+/// Encodes linear light for an sRGB display, as the window's swapchain does
+pub fn to_srgb(linear: f32) -> f32 {
+    if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1. / 2.4) - 0.055
+    }
+}
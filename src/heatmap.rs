@@ -0,0 +1,131 @@
+//! The weights matrix as an egui heatmap in the side panel, red for attraction and blue for
+//! repulsion. Rows are the flavour feeling the force and columns the flavour it's towards, each
+//! labelled with the flavour's colour. Clicking or dragging over the cells paints them.
+//!
+//! The cells shrink to fit the panel's width, down to `MIN_CELL_SIZE`, and past that the matrix
+//! scrolls.
+
+use bevy_egui::egui::{self, Color32, Pos2, Rect, Sense, Stroke};
+
+use crate::objects::{ParticleColours, Weights};
+
+/// Side of a cell in points, when there's room
+const CELL_SIZE: f32 = 18.;
+/// Side of a cell in points when there are too many flavours to fit, small but still paintable
+const MIN_CELL_SIZE: f32 = 6.;
+/// Width of the colour swatches labelling the rows and columns
+const SWATCH_SIZE: f32 = 10.;
+/// Height past which the matrix scrolls
+const MAX_HEIGHT: f32 = 400.;
+
+/// Ends and middle of the diverging colour map, for -1, 0 and 1
+const REPULSION: [f32; 3] = [59., 76., 192.];
+const NEUTRAL: [f32; 3] = [221., 221., 221.];
+const ATTRACTION: [f32; 3] = [180., 4., 38.];
+
+/// `weight` on the diverging colour map, clamped to -1..1
+pub fn diverging(weight: f32) -> Color32 {
+    let t = weight.clamp(-1., 1.);
+    let (end, t) = if t < 0. {
+        (REPULSION, -t)
+    } else {
+        (ATTRACTION, t)
+    };
+    let [r, g, b] = std::array::from_fn(|c| (NEUTRAL[c] + (end[c] - NEUTRAL[c]) * t).round() as u8);
+    Color32::from_rgb(r, g, b)
+}
+
+fn swatch(colour: [f32; 4]) -> Color32 {
+    let [r, g, b] = std::array::from_fn(|c| (colour[c].clamp(0., 1.) * 255.).round() as u8);
+    Color32::from_rgb(r, g, b)
+}
+
+/// The first `flavours` rows and columns of `weights`, with a slider for the value clicks paint.
+/// Only writes to `weights` where the user paints.
+pub fn weights_heatmap(
+    ui: &mut egui::Ui,
+    weights: &mut Weights,
+    colours: &ParticleColours,
+    flavours: usize,
+) {
+    let paint_id = ui.id().with("paint weight");
+    let mut paint = ui.data_mut(|data| *data.get_temp_mut_or(paint_id, 0.5f32));
+    ui.add(egui::Slider::new(&mut paint, -1.0..=1.0).text("paint"));
+    ui.data_mut(|data| data.insert_temp(paint_id, paint));
+
+    let fit = (ui.available_width() - SWATCH_SIZE) / flavours as f32;
+    let cell_size = fit.clamp(MIN_CELL_SIZE, CELL_SIZE);
+    // dragging paints rather than scrolls, the scroll bars still work
+    egui::ScrollArea::both()
+        .max_height(MAX_HEIGHT)
+        .drag_to_scroll(false)
+        .show(ui, |ui| {
+            matrix(ui, weights, colours, flavours, cell_size, paint)
+        });
+}
+
+/// The labelled cells, `cell_size` points square
+fn matrix(
+    ui: &mut egui::Ui,
+    weights: &mut Weights,
+    colours: &ParticleColours,
+    flavours: usize,
+    cell_size: f32,
+    paint: f32,
+) {
+    let side = SWATCH_SIZE + cell_size * flavours as f32;
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(side, side), Sense::click_and_drag());
+    let grid = rect.min + egui::vec2(SWATCH_SIZE, SWATCH_SIZE);
+    let cell_rect = |row: usize, column: usize| {
+        Rect::from_min_size(
+            grid + egui::vec2(column as f32, row as f32) * cell_size,
+            egui::vec2(cell_size, cell_size),
+        )
+    };
+    let cell_at = |position: Pos2| {
+        let cell = (position - grid) / cell_size;
+        let in_grid = cell.x >= 0. && cell.y >= 0.;
+        let (row, column) = (cell.y as usize, cell.x as usize);
+        (in_grid && row < flavours && column < flavours).then_some((row, column))
+    };
+
+    if response.is_pointer_button_down_on() {
+        if let Some((row, column)) = response.interact_pointer_pos().and_then(cell_at) {
+            weights.0[row][column] = paint;
+        }
+    }
+
+    let painter = ui.painter_at(rect);
+    for flavour in 0..flavours {
+        let colour = swatch(colours.0[flavour]);
+        let offset = flavour as f32 * cell_size;
+        let row = Rect::from_min_size(
+            Pos2::new(rect.min.x, grid.y + offset),
+            egui::vec2(SWATCH_SIZE, cell_size),
+        );
+        let column = Rect::from_min_size(
+            Pos2::new(grid.x + offset, rect.min.y),
+            egui::vec2(cell_size, SWATCH_SIZE),
+        );
+        painter.rect_filled(row.shrink(1.), 0., colour);
+        painter.rect_filled(column.shrink(1.), 0., colour);
+    }
+    for row in 0..flavours {
+        for column in 0..flavours {
+            painter.rect_filled(
+                cell_rect(row, column),
+                0.,
+                diverging(weights.0[row][column]),
+            );
+        }
+    }
+
+    if let Some((row, column)) = response.hover_pos().and_then(cell_at) {
+        let stroke = Stroke::new(1.5, ui.visuals().strong_text_color());
+        painter.rect_stroke(cell_rect(row, column), 0., stroke);
+        response.on_hover_text_at_pointer(format!(
+            "{} towards {}: {:.3}",
+            row, column, weights.0[row][column]
+        ));
+    }
+}
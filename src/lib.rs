@@ -0,0 +1,231 @@
+use analysis::Analysis;
+use backend::{Backend, BackendKind};
+use bevy::{
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureUsages},
+    window::{ExitCondition, PresentMode, Window},
+};
+use camera::{OrbitCamera, OrbitControls};
+use capture::Capture;
+use config::Config;
+use density::DensityField;
+use forces::Forces;
+use hdr::{Glow, Hdr, RENDER_FORMAT};
+use lifecycle::Lifecycle;
+use menu::Menu;
+use objects::*;
+use obstacles::{ObstacleMap, Obstacles};
+use palette::Palette;
+use perf::Perf;
+use rand::{rngs::StdRng, SeedableRng};
+use replay::Replays;
+use timestep::Timestep;
+
+pub mod analysis;
+pub mod backend;
+pub mod camera;
+pub mod capture;
+pub mod checkpoint;
+pub mod config;
+pub mod cpu;
+pub mod density;
+pub mod forces;
+pub mod hdr;
+pub mod heatmap;
+pub mod lifecycle;
+pub mod menu;
+pub mod objects;
+pub mod obstacles;
+pub mod palette;
+pub mod perf;
+pub mod preset;
+pub mod readback;
+pub mod render;
+pub mod render_shader_pipeline;
+pub mod replay;
+pub mod sim_shader_pipeline;
+pub mod timestep;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod video;
+
+#[derive(States, Debug, Default, Clone, Eq, PartialEq, Hash)]
+enum AppState {
+    #[default]
+    Waiting,
+    Running,
+    Done,
+    Reset,
+}
+
+pub const WORKGROUP_SIZE: (u32, u32, u32) = (8, 8, 1);
+
+pub fn run(mut config: Config) {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    // taken so the render world's copy of the config doesn't carry every particle
+    let (particles, lifecycle) = match config.checkpoint.take() {
+        Some(checkpoint) => (Particles(checkpoint.particles), checkpoint.lifecycle),
+        None => (
+            Particles::new(
+                config.particle_count,
+                config.flavour_count,
+                config.volume(),
+                &mut rng,
+            ),
+            Lifecycle::default(),
+        ),
+    };
+    let (weights, colours, params, properties) = match &config.preset {
+        Some(preset) => (
+            preset.weights,
+            preset.colours,
+            preset.params,
+            preset.properties,
+        ),
+        None => (
+            Weights::random(&mut rng),
+            ParticleColours::default(),
+            ForceParams::default(),
+            FlavourProperties::default(),
+        ),
+    };
+    // colours nobody picked are all black, as in presets saved before they could be edited
+    let colours = if colours == ParticleColours::default() {
+        Palette::Rainbow.colours(config.flavour_count)
+    } else {
+        colours
+    };
+    let forces = config
+        .preset
+        .as_ref()
+        .map(|preset| preset.forces.clone())
+        .unwrap_or_default();
+    let (width, height) = config.world_size;
+    let obstacles = match &config.obstacles {
+        Some(obstacles) => obstacles.resized(width, height),
+        None => ObstacleMap::new(width, height),
+    };
+
+    let window_plugin = if config.headless {
+        WindowPlugin {
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            close_when_requested: false,
+        }
+    } else {
+        WindowPlugin {
+            primary_window: Some(Window {
+                resolution: (config.window_size.0 as f32, config.window_size.1 as f32).into(),
+                present_mode: if config.vsync {
+                    PresentMode::AutoVsync
+                } else {
+                    PresentMode::AutoNoVsync
+                },
+                ..default()
+            }),
+            ..default()
+        }
+    };
+
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins.set(window_plugin),
+        Backend(config.backend),
+        Timestep,
+        Capture,
+        Obstacles,
+        Forces,
+        Replays,
+    ));
+
+    // everything that draws egui needs a window
+    if !config.headless {
+        app.add_plugins((Menu, Perf, Analysis, Hdr));
+        // the web build can't read or write files
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_plugins(checkpoint::Checkpoints);
+        if config.three_d {
+            app.add_plugins(OrbitControls);
+        }
+    }
+
+    app.add_systems(Startup, setup)
+        .add_state::<AppState>()
+        .insert_resource(particles)
+        .insert_resource(weights)
+        .insert_resource(colours)
+        .insert_resource(params)
+        .insert_resource(properties)
+        .insert_resource(lifecycle)
+        .insert_resource(obstacles)
+        .insert_resource(forces)
+        .insert_resource(OrbitCamera::default())
+        .insert_resource(DensityField {
+            mode: config.render_mode,
+            ..default()
+        })
+        .insert_resource(config)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut images: ResMut<Assets<Image>>,
+    mut next_state: ResMut<NextState<AppState>>,
+    config: Res<Config>,
+) {
+    // nothing to wait for once the world exists; a replay moves on to Done when it ends
+    next_state.set(AppState::Running);
+
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: config.world_size.0,
+            height: config.world_size.1,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &hdr::encode_pixel([0., 0., 0., 1.]),
+        RENDER_FORMAT,
+    );
+    // WebGL2 has no storage textures, and the CPU backend writes the image data directly
+    let storage = match config.backend {
+        BackendKind::Gpu => TextureUsages::STORAGE_BINDING,
+        BackendKind::Cpu => TextureUsages::empty(),
+    };
+    image.texture_descriptor.usage = TextureUsages::COPY_DST
+        | TextureUsages::COPY_SRC
+        | TextureUsages::TEXTURE_BINDING
+        | storage;
+
+    let image_handle = images.add(image);
+
+    commands.insert_resource(RenderImage {
+        image: image_handle.clone(),
+    });
+
+    if config.headless {
+        return;
+    }
+
+    // particles add up past 1 in the HDR image, which the camera blooms and tonemaps
+    let glow = Glow::default();
+    let mut camera = commands.spawn(Camera2dBundle {
+        camera: Camera {
+            hdr: true,
+            ..default()
+        },
+        tonemapping: glow.tonemapping,
+        ..default()
+    });
+    // Bevy's bloom doesn't work on WebGL2
+    if cfg!(not(feature = "webgl2")) {
+        camera.insert(glow.bloom());
+    }
+
+    commands
+        .spawn(SpriteBundle {
+            texture: image_handle.clone(),
+            sprite: Sprite { ..default() },
+            ..default()
+        })
+        .insert(Name::new("Render Sprite"));
+}
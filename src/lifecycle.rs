@@ -0,0 +1,261 @@
+//! Optional rules for particles being born, dying and changing flavour. Every flavour can have a
+//! lifespan, after which its particles either respawn somewhere random or die, and can be
+//! converted into another flavour by spending long enough touching a third. Emitters spawn new
+//! particles into the slots the dead leave behind, so the particle count never changes.
+//!
+//! Both backends follow the same rules with the same random numbers, which are hashed from a seed
+//! per `step` call and the particle's index, so only the choice of free slot differs between them.
+
+use bevy::{prelude::Resource, reflect::Reflect, render::extract_resource::ExtractResource};
+use bytemuck::{Pod, Zeroable};
+
+use crate::objects::{Particle, MAX_FLAVOURS};
+
+/// Upper bound on emitters, which the spawn pass loops over
+pub const MAX_EMITTERS: usize = 8;
+
+/// What happens to one flavour over time. The default changes nothing.
+#[derive(Reflect, Clone, Copy, Pod, Zeroable, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct LifecycleRule {
+    /// seconds a particle lives, 0 to live forever
+    pub lifespan: f32,
+    /// non-zero for particles to respawn at a random position at the end of their lifespan,
+    /// rather than die and free their slot for the emitters
+    pub respawn: u32,
+    /// seconds of touching `convert_by` before a particle becomes `convert_to`, 0 to never convert
+    pub conversion_time: f32,
+    pub convert_by: u32,
+    pub convert_to: u32,
+}
+
+impl LifecycleRule {
+    pub fn converts(&self) -> bool {
+        self.conversion_time > 0.
+    }
+}
+
+/// Spawns particles of one flavour at a steady rate, within `radius` of `position` on each axis
+#[derive(Reflect, Clone, Copy, Debug, PartialEq)]
+pub struct Emitter {
+    pub position: [f32; 3],
+    pub flavour: u32,
+    /// particles per second
+    pub rate: f32,
+    pub radius: f32,
+}
+
+#[derive(Resource, Reflect, ExtractResource, Clone, Debug, PartialEq)]
+pub struct Lifecycle {
+    pub rules: [LifecycleRule; MAX_FLAVOURS],
+    /// at most `MAX_EMITTERS`
+    pub emitters: Vec<Emitter>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self {
+            rules: [LifecycleRule::default(); MAX_FLAVOURS],
+            emitters: Vec::new(),
+        }
+    }
+}
+
+impl Lifecycle {
+    /// The first `flavours` rules, then the emitters, as replays and checkpoints store them
+    pub fn to_bytes(&self, flavours: usize) -> Vec<u8> {
+        let mut bytes = bytemuck::cast_slice(&self.rules[..flavours]).to_vec();
+        bytes.extend_from_slice(&(self.emitters.len() as u32).to_le_bytes());
+        for emitter in &self.emitters {
+            for value in emitter.position {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            bytes.extend_from_slice(&emitter.flavour.to_le_bytes());
+            bytes.extend_from_slice(&emitter.rate.to_le_bytes());
+            bytes.extend_from_slice(&emitter.radius.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads what `to_bytes` wrote for `flavour_count` flavours off the front of `bytes`. `None`
+    /// if it's cut short, or names flavours or emitters that can't exist, since both backends
+    /// index by them.
+    pub fn read(bytes: &mut &[u8], flavour_count: u32) -> Option<Self> {
+        fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+            let head = bytes.get(..N)?.try_into().ok()?;
+            *bytes = &bytes[N..];
+            Some(head)
+        }
+        let f32 = |bytes: &mut &[u8]| take(bytes).map(f32::from_le_bytes);
+        let u32 = |bytes: &mut &[u8]| take(bytes).map(u32::from_le_bytes);
+
+        let mut lifecycle = Lifecycle::default();
+        for rule in lifecycle.rules.iter_mut().take(flavour_count as usize) {
+            *rule = bytemuck::pod_read_unaligned(
+                &take::<{ std::mem::size_of::<LifecycleRule>() }>(bytes)?,
+            );
+            if rule.convert_by >= flavour_count || rule.convert_to >= flavour_count {
+                return None;
+            }
+        }
+        let emitter_count = u32(bytes)?;
+        if emitter_count as usize > MAX_EMITTERS {
+            return None;
+        }
+        for _ in 0..emitter_count {
+            let emitter = Emitter {
+                position: [f32(bytes)?, f32(bytes)?, f32(bytes)?],
+                flavour: u32(bytes)?,
+                rate: f32(bytes)?,
+                radius: f32(bytes)?,
+            };
+            if emitter.flavour >= flavour_count {
+                return None;
+            }
+            lifecycle.emitters.push(emitter);
+        }
+        Some(lifecycle)
+    }
+}
+
+/// One emitter's spawns in a `step` call, as the spawn pass reads them. Spawn `i` of the call
+/// belongs to the batch with `first <= i < first + count`.
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct SpawnBatch {
+    pub position: [f32; 3],
+    pub flavour: u32,
+    pub radius: f32,
+    pub first: u32,
+    pub count: u32,
+    pub _padding: u32,
+}
+
+// `struct SpawnBatch` in simulation.wgsl
+const _: () = assert!(std::mem::size_of::<SpawnBatch>() == 32);
+
+/// Turns the emitters' rates into whole spawns, carrying the fractions over between calls, and
+/// hands out the seed for each call's random numbers
+#[derive(Clone, Debug, Default)]
+pub struct Spawner {
+    owed: Vec<f32>,
+    seed: u32,
+}
+
+impl Spawner {
+    /// The spawns due from each emitter after `elapsed` seconds, padded to `MAX_EMITTERS`, and
+    /// the seed for the call
+    pub fn advance(
+        &mut self,
+        emitters: &[Emitter],
+        elapsed: f32,
+    ) -> ([SpawnBatch; MAX_EMITTERS], u32) {
+        self.seed = self.seed.wrapping_add(1);
+        self.owed.resize(emitters.len(), 0.);
+
+        let mut batches = [SpawnBatch::default(); MAX_EMITTERS];
+        let mut first = 0;
+        for ((batch, emitter), owed) in batches.iter_mut().zip(emitters).zip(&mut self.owed) {
+            *owed += emitter.rate * elapsed;
+            let count = owed.floor();
+            *owed -= count;
+            *batch = SpawnBatch {
+                position: emitter.position,
+                flavour: emitter.flavour,
+                radius: emitter.radius,
+                first,
+                count: count as u32,
+                _padding: 0,
+            };
+            first += count as u32;
+        }
+
+        (batches, self.seed)
+    }
+}
+
+/// Total spawns in a call's batches
+pub fn spawn_count(batches: &[SpawnBatch]) -> u32 {
+    batches.iter().map(|batch| batch.count).sum()
+}
+
+/// PCG hash, see `hash` in simulation.wgsl
+pub fn hash(input: u32) -> u32 {
+    let state = input.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Uniform in 0..1, exactly representable so the GPU gets the same value
+pub fn random(seed: u32, index: u32) -> f32 {
+    (hash(seed ^ hash(index)) >> 8) as f32 / 16777216.
+}
+
+/// Three numbers uniform in 0..1 for the particle or spawn `index`
+pub fn random_vector(seed: u32, index: u32) -> [f32; 3] {
+    std::array::from_fn(|axis| random(seed, 3 * index + axis as u32))
+}
+
+/// Where a particle that reached its lifespan respawns, with z = 0 in a flat world
+pub fn respawn_position(seed: u32, index: u32, world_size: [f32; 3]) -> [f32; 3] {
+    let random = random_vector(seed, index);
+    std::array::from_fn(|axis| random[axis] * world_size[axis])
+}
+
+/// Each spawn of a call, in order, with the batch it belongs to
+pub fn spawns(batches: &[SpawnBatch]) -> impl Iterator<Item = (&SpawnBatch, u32)> {
+    batches
+        .iter()
+        .flat_map(|batch| (batch.first..batch.first + batch.count).map(move |index| (batch, index)))
+}
+
+/// Spawn `index` of a call whose batches came with `seed`, see `spawn` in simulation.wgsl
+pub fn spawned(batch: &SpawnBatch, seed: u32, index: u32, world_size: [f32; 3]) -> Particle {
+    // the seed is inverted so spawns don't share numbers with respawns
+    let random = random_vector(!seed, index);
+    let position = std::array::from_fn(|axis| {
+        if world_size[axis] > 0. {
+            (batch.position[axis] + (random[axis] * 2. - 1.) * batch.radius)
+                .rem_euclid(world_size[axis])
+        } else {
+            0.
+        }
+    });
+    Particle::new(position, [0.; 3], batch.flavour as usize)
+}
+
+/// What becomes of a living particle at the end of a step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fate {
+    Lives,
+    Respawns,
+    Dies,
+}
+
+/// Ages a living particle by `dt`, converting it if it's touched its converter for long enough,
+/// and decides whether it's reached its lifespan. See `integrate` in simulation.wgsl.
+pub fn live(
+    rules: &[LifecycleRule; MAX_FLAVOURS],
+    flavour: &mut u32,
+    age: &mut f32,
+    contact: &mut f32,
+    dt: f32,
+) -> Fate {
+    *age += dt;
+
+    let conversion = &rules[*flavour as usize];
+    if conversion.converts() && *contact >= conversion.conversion_time {
+        *flavour = conversion.convert_to;
+        *age = 0.;
+        *contact = 0.;
+    }
+
+    let rule = &rules[*flavour as usize];
+    if rule.lifespan <= 0. || *age < rule.lifespan {
+        Fate::Lives
+    } else if rule.respawn != 0 {
+        Fate::Respawns
+    } else {
+        Fate::Dies
+    }
+}
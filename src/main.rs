@@ -1,34 +1,6 @@
-use crate::render::RenderPlugin;
-use bevy::{
-    prelude::*,
-    render::render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages},
-    sprite::Anchor,
-    window::{PrimaryWindow, Window},
-};
-use menu::Menu;
-use objects::*;
-
-pub mod menu;
-pub mod objects;
-pub mod render;
-pub mod render_shader_pipeline;
-pub mod sim_shader_pipeline;
-
-#[derive(States, Debug, Default, Clone, Eq, PartialEq, Hash)]
-enum AppState {
-    #[default]
-    Waiting,
-    Running,
-    Done,
-    Reset,
-}
-
-const SIZE: (u32, u32) = (512, 512);
-const WORKGROUP_SIZE: (u32, u32, u32) = (8, 8, 1);
-
 #[cfg(target_arch = "wasm32")]
 fn main() {
-    use js_sys::Object;
+    use rusty_particle_life::{backend::BackendKind, config::Config};
     use web_sys::console;
 
     // Use `web_sys`'s global `window` function to get a handle on the global
@@ -37,13 +9,9 @@ fn main() {
     let document = window.document().expect("should have a document on window");
     let body = document.body().expect("document should have a body");
 
-    // https://developer.mozilla.org/en-US/docs/Web/API/GPU
-    // Firefox has navigator.gpu, despite the docs saying it's nightly only
-    let gpu = web_sys::window().unwrap().navigator().gpu();
-    if gpu.is_undefined()
-        || !Object::get_prototype_of(&gpu).has_own_property(&"wgslLanguageFeatures".into())
-    {
-        let error_msg = "Sorry, WebGPU is not supported on this browser. Only Chrome 113 and later is supported.";
+    if !BackendKind::renderer_available() {
+        // only the WebGPU build gets here, the WebGL2 one renders in any browser
+        let error_msg = "Sorry, this build needs WebGPU, which this browser doesn't support. Try Chrome 113 or later, or the WebGL2 build of this page, which runs in Firefox and Safari.";
         console::log_1(&error_msg.into());
 
         let val = document.create_element("p").expect("no element");
@@ -51,105 +19,22 @@ fn main() {
 
         body.append_child(&val).expect("couldn't add node");
     } else {
-        run();
+        let location = window.location();
+        let query = location.search().unwrap_or_default();
+        let fragment = location.hash().unwrap_or_default();
+        let config = Config::from_url(&query, &fragment).unwrap_or_else(|err| {
+            console::log_1(&format!("ignoring URL parameters: {}", err).into());
+            // still picks a backend this browser can run
+            Config::from_query("").expect("the defaults are valid")
+        });
+
+        rusty_particle_life::run(config);
     }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 fn main() {
-    run();
-}
-
-fn run() {
-    App::new()
-        .add_plugins((DefaultPlugins, Menu, RenderPlugin))
-        .add_systems(Startup, setup)
-        .add_state::<AppState>()
-        .init_resource::<Particles>()
-        .init_resource::<ParticleColours>()
-        .run();
-}
-
-fn setup(
-    mut commands: Commands,
-    mut images: ResMut<Assets<Image>>,
-    window_query: Query<&Window, With<PrimaryWindow>>,
-) {
-    commands.spawn(Camera2dBundle::default());
-
-    let window = window_query
-        .get_single()
-        .expect("Expected to have a primary window");
-
-    let resolution = window.resolution.clone();
-
-    let mut image = Image::new_fill(
-        Extent3d {
-            width: SIZE.0,
-            height: SIZE.1,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        &[0, 0, 0, 255],
-        TextureFormat::Rgba8Unorm,
-    );
-    image.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
-
-    let image_handle = images.add(image);
-
-    commands
-        .spawn(SpriteBundle {
-            texture: image_handle.clone(),
-            sprite: Sprite { ..default() },
-            ..default()
-        })
-        .insert(Name::new("Render Sprite"));
-
-    commands.insert_resource(RenderImage {
-        image: image_handle.clone(),
-    });
-
-    let mut weights = Image::new_fill(
-        Extent3d {
-            width: MAX_FLAVOURS as u32,
-            height: MAX_FLAVOURS as u32,
-            depth_or_array_layers: 1,
-        },
-        TextureDimension::D2,
-        &[128, 0, 0, 255],
-        TextureFormat::Rgba8Unorm,
-    );
-    weights.texture_descriptor.usage =
-        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
-
-    let weights_handle = images.add(weights);
-
-    commands
-        .spawn(SpriteBundle {
-            texture: weights_handle.clone(),
-            transform: Transform {
-                translation: Vec3 {
-                    x: resolution.width() / 2. - 10.,
-                    y: -resolution.height() / 2. + 10.,
-                    z: 0.,
-                },
-                rotation: Quat::IDENTITY,
-                scale: Vec3 {
-                    x: 10.,
-                    y: 10.,
-                    z: 1.,
-                },
-            },
-            sprite: Sprite {
-                anchor: Anchor::BottomRight,
-                ..default()
-            },
-            ..default()
-        })
-        .insert(Name::new("Weights Sprite"));
+    use rusty_particle_life::config::Config;
 
-    commands.insert_resource(WeightsImage {
-        image: weights_handle.clone(),
-    });
+    rusty_particle_life::run(Config::from_args());
 }
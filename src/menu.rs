@@ -1,18 +1,102 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, window::PrimaryWindow};
 use bevy_egui::{
     egui::{self},
     EguiContexts, EguiPlugin,
 };
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    config::Config,
+    density::{DensityField, RenderMode},
+    forces::{ExternalForces, FieldSource, Flow, MAX_SOURCES},
+    hdr::{Glow, TONEMAPPERS},
+    heatmap::weights_heatmap,
+    lifecycle::{Emitter, Lifecycle, LifecycleRule, MAX_EMITTERS},
+    objects::{
+        FlavourProperties, ParticleColours, Particles, SimulationSettings, SimulationSteps,
+        Weights, MAX_FLAVOURS,
+    },
+    obstacles::ObstacleMap,
+    palette::{evenly_spaced_hues, parse_palette, repeated, Palette},
+};
+#[cfg(target_arch = "wasm32")]
+use crate::{objects::ForceParams, preset::Preset};
+
 const PANEL_WIDTH: f32 = 200.;
 pub struct Menu;
 impl Plugin for Menu {
     fn build(&self, app: &mut App) {
-        app.add_plugins(EguiPlugin).add_systems(Update, ui_system);
+        app.add_plugins(EguiPlugin)
+            .init_resource::<Brush>()
+            .init_resource::<Imports>()
+            .add_systems(
+                Update,
+                (ui_system, change_flavour_count, paint_obstacles).chain(),
+            );
     }
 }
 
+/// How dragging over the world edits the walls
+#[derive(Resource)]
+struct Brush {
+    painting: bool,
+    radius: f32,
+    erase: bool,
+    /// PNG to import walls from
+    #[cfg(not(target_arch = "wasm32"))]
+    path: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    error: Option<String>,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            painting: false,
+            radius: 8.,
+            erase: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            path: String::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            error: None,
+        }
+    }
+}
+
+/// A file to import from, and why the last import failed
+#[derive(Default)]
+struct FileImport {
+    #[cfg(not(target_arch = "wasm32"))]
+    path: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    error: Option<String>,
+}
+
+#[derive(Resource, Default)]
+struct Imports {
+    /// PNG
+    flow: FileImport,
+    /// `.hex` or `.gpl`
+    palette: FileImport,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn ui_system(
     mut contexts: EguiContexts,
+    mut settings: ResMut<SimulationSettings>,
+    steps: Res<SimulationSteps>,
+    mut weights: ResMut<Weights>,
+    mut colours: ResMut<ParticleColours>,
+    mut properties: ResMut<FlavourProperties>,
+    mut lifecycle: ResMut<Lifecycle>,
+    mut obstacles: ResMut<ObstacleMap>,
+    mut brush: ResMut<Brush>,
+    mut forces: ResMut<ExternalForces>,
+    mut imports: ResMut<Imports>,
+    mut glow: ResMut<Glow>,
+    mut density: ResMut<DensityField>,
+    mut config: ResMut<Config>,
+    #[cfg(target_arch = "wasm32")] params: Res<ForceParams>,
     // mut next_state: ResMut<NextState<AppState>>,
     // state: Res<State<AppState>>,
     // type_registry: Res<AppTypeRegistry>,
@@ -22,7 +106,567 @@ fn ui_system(
     egui::SidePanel::left("side_panel")
         .resizable(false)
         .min_width(PANEL_WIDTH)
-        .show(ctx, |_ui| {
-            // TODO: controls
+        .show(ctx, |ui| {
+            // with many flavours the sections outgrow the window
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.heading("Simulation");
+                ui.add(egui::Slider::new(&mut settings.substeps, 1..=16).text("substeps"));
+                ui.add_enabled(
+                    !settings.fast_forward,
+                    egui::Slider::new(&mut settings.time_scale, 0.0..=4.0).text("time scale"),
+                );
+                ui.checkbox(&mut settings.fast_forward, "fast-forward");
+                if settings.fast_forward {
+                    ui.add(
+                        egui::Slider::new(&mut settings.frame_budget_ms, 4.0..=33.0)
+                            .text("frame budget (ms)"),
+                    );
+                }
+                ui.label(format!("steps this frame: {}", steps.count));
+                // a replay's timeline can't respawn the particles, so recording one fixes the count
+                let replaying = config.record.is_some() || config.replay.is_some();
+                let mut flavours = config.flavour_count;
+                ui.add_enabled(
+                    !replaying,
+                    egui::Slider::new(&mut flavours, 1..=MAX_FLAVOURS).text("flavours"),
+                )
+                .on_hover_text("respawns the particles");
+                if flavours != config.flavour_count {
+                    config.flavour_count = flavours;
+                }
+
+                ui.separator();
+                egui::CollapsingHeader::new("Weights")
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        // edited on a copy so the weights are only re-uploaded when painted
+                        let mut edited = *weights;
+                        weights_heatmap(ui, &mut edited, &colours, config.flavour_count);
+                        weights.set_if_neq(edited);
+                    });
+                egui::CollapsingHeader::new("Colours").show(ui, |ui| {
+                    // edited on a copy so the colours are only re-uploaded when changed
+                    let mut edited = *colours;
+                    palette_ui(ui, &mut edited, &mut imports.palette, config.flavour_count);
+                    colours.set_if_neq(edited);
+                });
+                egui::CollapsingHeader::new("Glow").show(ui, |ui| {
+                    let mut edited = *glow;
+                    glow_ui(ui, &mut edited);
+                    glow.set_if_neq(edited);
+                });
+                // the 3D view always draws spheres
+                if !config.three_d {
+                    egui::CollapsingHeader::new("Render mode").show(ui, |ui| {
+                        let mut edited = *density;
+                        density_ui(ui, &mut edited);
+                        density.set_if_neq(edited);
+                    });
+                }
+                egui::CollapsingHeader::new("Flavours").show(ui, |ui| {
+                    // edited on a copy so the properties are only re-uploaded when changed
+                    let mut edited = *properties;
+                    let flavours = edited.0.iter_mut().take(config.flavour_count);
+                    for (flavour, properties) in flavours.enumerate() {
+                        egui::CollapsingHeader::new(format!("flavour {}", flavour)).show(
+                            ui,
+                            |ui| {
+                                ui.add(
+                                    egui::Slider::new(&mut properties.mass, 0.1..=10.0)
+                                        .logarithmic(true)
+                                        .text("mass"),
+                                );
+                                ui.add(
+                                    egui::Slider::new(&mut properties.radius, 0.1..=3.0)
+                                        .text("radius"),
+                                );
+                                ui.add(
+                                    egui::Slider::new(&mut properties.friction, 0.0..=4.0)
+                                        .text("friction"),
+                                );
+                                ui.add(
+                                    egui::Slider::new(&mut properties.max_speed, 0.0..=500.0)
+                                        .text("max speed (0 = none)"),
+                                );
+                            },
+                        );
+                    }
+                    properties.set_if_neq(edited);
+                });
+                egui::CollapsingHeader::new("Lifecycle").show(ui, |ui| {
+                    let mut edited = lifecycle.clone();
+                    lifecycle_ui(ui, &mut edited, &config);
+                    lifecycle.set_if_neq(edited);
+                });
+                // walls only exist in a flat world
+                if !config.three_d {
+                    egui::CollapsingHeader::new("Obstacles").show(ui, |ui| {
+                        obstacles_ui(ui, &mut obstacles, &mut brush);
+                    });
+                }
+                egui::CollapsingHeader::new("Force fields").show(ui, |ui| {
+                    // edited on a copy so the field is only rebuilt when something changes
+                    let mut edited = forces.clone();
+                    forces_ui(ui, &mut edited, &mut imports.flow, &config);
+                    forces.set_if_neq(edited);
+                });
+
+                #[cfg(target_arch = "wasm32")]
+                {
+                    ui.separator();
+                    if ui.button("Copy link").clicked() {
+                        copy_link(&Preset {
+                            seed: config.seed,
+                            particle_count: config.particle_count as u32,
+                            flavour_count: config.flavour_count as u32,
+                            weights: *weights,
+                            colours: *colours,
+                            params: *params,
+                            properties: *properties,
+                            obstacles: (!obstacles.is_empty()).then(|| obstacles.clone()),
+                            forces: forces.clone(),
+                        });
+                    }
+                }
+            });
+        });
+}
+
+/// Respawns the particles when the menu changes the flavour count, as if the app had started with
+/// it, and drops what refers to flavours that are gone. The GPU backend resizes its flavour
+/// tables from the config.
+fn change_flavour_count(
+    config: Res<Config>,
+    mut last: Local<Option<usize>>,
+    mut particles: ResMut<Particles>,
+    mut colours: ResMut<ParticleColours>,
+    mut lifecycle: ResMut<Lifecycle>,
+) {
+    let flavours = config.flavour_count;
+    // the first run only notes the count, so a resumed checkpoint isn't respawned
+    let Some(old) = last.replace(flavours) else {
+        return;
+    };
+    if old == flavours {
+        return;
+    }
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    *particles = Particles::new(config.particle_count, flavours, config.volume(), &mut rng);
+
+    // untouched rainbow colours are spread over the new count, and new flavours get its colours
+    let rainbow = Palette::Rainbow.colours(flavours);
+    let mut edited = *colours;
+    if edited == Palette::Rainbow.colours(old) {
+        edited = rainbow;
+    } else {
+        for (colour, fallback) in edited.0.iter_mut().zip(rainbow.0).take(flavours).skip(old) {
+            if *colour == [0.; 4] {
+                *colour = fallback;
+            }
+        }
+    }
+    colours.set_if_neq(edited);
+
+    let mut edited = lifecycle.clone();
+    let flavours = flavours as u32;
+    for rule in &mut edited.rules {
+        if rule.convert_by >= flavours || rule.convert_to >= flavours {
+            *rule = LifecycleRule {
+                conversion_time: 0.,
+                convert_by: 0,
+                convert_to: 0,
+                ..*rule
+            };
+        }
+    }
+    edited.emitters.retain(|emitter| emitter.flavour < flavours);
+    lifecycle.set_if_neq(edited);
+}
+
+fn lifecycle_ui(ui: &mut egui::Ui, lifecycle: &mut Lifecycle, config: &Config) {
+    let flavours = config.flavour_count;
+    for (flavour, rule) in lifecycle.rules.iter_mut().take(flavours).enumerate() {
+        egui::CollapsingHeader::new(format!("flavour {}", flavour))
+            .id_source(("lifecycle", flavour))
+            .show(ui, |ui| {
+                ui.add(
+                    egui::Slider::new(&mut rule.lifespan, 0.0..=60.0)
+                        .text("lifespan (0 = forever)"),
+                );
+                let mut respawn = rule.respawn != 0;
+                if ui.checkbox(&mut respawn, "respawn").changed() {
+                    rule.respawn = respawn as u32;
+                }
+                ui.add(
+                    egui::Slider::new(&mut rule.conversion_time, 0.0..=10.0)
+                        .text("conversion time (0 = never)"),
+                );
+                ui.add_enabled_ui(rule.converts(), |ui| {
+                    let id = ("convert by", flavour);
+                    flavour_picker(ui, id, "converted by", &mut rule.convert_by, flavours);
+                    let id = ("convert to", flavour);
+                    flavour_picker(ui, id, "converts to", &mut rule.convert_to, flavours);
+                });
+            });
+    }
+
+    ui.label("emitters");
+    let world_size = config.volume();
+    let axes = if config.three_d { 3 } else { 2 };
+    let mut removed = None;
+    for (i, emitter) in lifecycle.emitters.iter_mut().enumerate() {
+        egui::CollapsingHeader::new(format!("emitter {}", i)).show(ui, |ui| {
+            let id = ("emitter", i);
+            flavour_picker(ui, id, "flavour", &mut emitter.flavour, flavours);
+            for (axis, name) in ["x", "y", "z"].into_iter().enumerate().take(axes) {
+                ui.add(
+                    egui::Slider::new(&mut emitter.position[axis], 0.0..=world_size[axis])
+                        .text(name),
+                );
+            }
+            ui.add(egui::Slider::new(&mut emitter.rate, 0.0..=200.0).text("rate (per second)"));
+            ui.add(egui::Slider::new(&mut emitter.radius, 0.0..=100.0).text("radius"));
+            if ui.button("Remove").clicked() {
+                removed = Some(i);
+            }
+        });
+    }
+    if let Some(i) = removed {
+        lifecycle.emitters.remove(i);
+    }
+
+    // emitters only fill the slots of particles that die without respawning
+    if lifecycle.emitters.len() < MAX_EMITTERS && ui.button("Add emitter").clicked() {
+        lifecycle.emitters.push(Emitter {
+            position: world_size.map(|size| size / 2.),
+            flavour: 0,
+            rate: 10.,
+            radius: 16.,
+        });
+    }
+}
+
+fn forces_ui(
+    ui: &mut egui::Ui,
+    forces: &mut ExternalForces,
+    flow_import: &mut FileImport,
+    config: &Config,
+) {
+    let world_size = config.volume();
+    let axes = if config.three_d { 3 } else { 2 };
+    let names = ["x", "y", "z"].into_iter().enumerate().take(axes);
+
+    ui.label("gravity");
+    for (axis, name) in names.clone() {
+        ui.add(egui::Slider::new(&mut forces.gravity[axis], -500.0..=500.0).text(name));
+    }
+
+    ui.label("attractors and vortices");
+    let mut removed = None;
+    for (i, source) in forces.sources.iter_mut().enumerate() {
+        let kind = if source.kind == FieldSource::VORTEX {
+            "vortex"
+        } else {
+            "attractor"
+        };
+        egui::CollapsingHeader::new(format!("{} {}", kind, i))
+            .id_source(("source", i))
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut source.kind, FieldSource::ATTRACTOR, "attractor");
+                    ui.radio_value(&mut source.kind, FieldSource::VORTEX, "vortex");
+                });
+                for (axis, name) in names.clone() {
+                    ui.add(
+                        egui::Slider::new(&mut source.position[axis], 0.0..=world_size[axis])
+                            .text(name),
+                    );
+                }
+                ui.add(
+                    egui::Slider::new(&mut source.strength, -2000.0..=2000.0)
+                        .text("strength (< 0 pushes)"),
+                );
+                ui.add(egui::Slider::new(&mut source.radius, 1.0..=512.0).text("radius"));
+                if ui.button("Remove").clicked() {
+                    removed = Some(i);
+                }
+            });
+    }
+    if let Some(i) = removed {
+        forces.sources.remove(i);
+    }
+    if forces.sources.len() < MAX_SOURCES && ui.button("Add attractor").clicked() {
+        forces.sources.push(FieldSource {
+            position: world_size.map(|size| size / 2.),
+            kind: FieldSource::ATTRACTOR,
+            strength: 500.,
+            radius: 128.,
+            ..Default::default()
         });
+    }
+
+    ui.label("flow field");
+    ui.horizontal(|ui| {
+        if ui
+            .radio(matches!(forces.flow, Flow::None), "none")
+            .clicked()
+        {
+            forces.flow = Flow::None;
+        }
+        let noise = matches!(forces.flow, Flow::Noise { .. });
+        if ui.radio(noise, "noise").clicked() && !noise {
+            forces.flow = Flow::Noise {
+                cells: 4,
+                speed: 0.2,
+            };
+        }
+        ui.add_enabled(
+            false,
+            egui::RadioButton::new(matches!(forces.flow, Flow::Image(_)), "image"),
+        );
+    });
+    if let Flow::Noise { cells, speed } = &mut forces.flow {
+        ui.add(egui::Slider::new(cells, 1..=16).text("noise cells"));
+        ui.add(egui::Slider::new(speed, 0.0..=2.0).text("drift (per second)"));
+    }
+    ui.add_enabled(
+        forces.flow != Flow::None,
+        egui::Slider::new(&mut forces.flow_strength, 0.0..=1000.0).text("flow strength"),
+    );
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        ui.label("import PNG, red and green are x and y");
+        ui.text_edit_singleline(&mut flow_import.path);
+        if ui.button("Import").clicked() {
+            let imported = std::fs::read(&flow_import.path)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| crate::forces::flow_from_png(&bytes));
+            match imported {
+                Ok(flow) => {
+                    forces.flow = flow;
+                    flow_import.error = None;
+                }
+                Err(err) => flow_import.error = Some(err),
+            }
+        }
+        if let Some(error) = &flow_import.error {
+            ui.label(format!("couldn't import: {}", error));
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = flow_import;
+}
+
+fn palette_ui(
+    ui: &mut egui::Ui,
+    colours: &mut ParticleColours,
+    palette_import: &mut FileImport,
+    flavours: usize,
+) {
+    // wrapped rather than a row each, which would be a long list with many flavours
+    ui.horizontal_wrapped(|ui| {
+        for (flavour, colour) in colours.0.iter_mut().enumerate().take(flavours) {
+            let mut rgb = [colour[0], colour[1], colour[2]];
+            let response = ui.color_edit_button_rgb(&mut rgb);
+            if response.changed() {
+                *colour = [rgb[0], rgb[1], rgb[2], 1.];
+            }
+            response.on_hover_text(format!("flavour {}", flavour));
+        }
+    });
+
+    ui.label("presets");
+    ui.horizontal_wrapped(|ui| {
+        for palette in Palette::ALL {
+            if ui.button(palette.name()).clicked() {
+                *colours = palette.colours(flavours);
+            }
+        }
+    });
+
+    // the generator's settings only live as long as the panel
+    let id = ui.id().with("spread hues");
+    let [mut offset, mut saturation, mut value] =
+        ui.data_mut(|data| *data.get_temp_mut_or(id, [0f32, 1., 1.]));
+    ui.label("evenly spaced hues");
+    ui.add(egui::Slider::new(&mut offset, 0.0..=1.0).text("first hue"));
+    ui.add(egui::Slider::new(&mut saturation, 0.0..=1.0).text("saturation"));
+    ui.add(egui::Slider::new(&mut value, 0.0..=1.0).text("value"));
+    ui.data_mut(|data| data.insert_temp(id, [offset, saturation, value]));
+    if ui.button("Spread hues").clicked() {
+        *colours = evenly_spaced_hues(flavours, offset, saturation, value);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        ui.label("import .hex or .gpl palette");
+        ui.text_edit_singleline(&mut palette_import.path);
+        if ui.button("Import").clicked() {
+            let path = &palette_import.path;
+            let imported = std::fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| parse_palette(path, &text));
+            match imported {
+                Ok(palette) => {
+                    *colours = repeated(&palette);
+                    palette_import.error = None;
+                }
+                Err(err) => palette_import.error = Some(err),
+            }
+        }
+        if let Some(error) = &palette_import.error {
+            ui.label(format!("couldn't import: {}", error));
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = palette_import;
+}
+
+/// Bloom and tonemapping of the window's camera
+fn glow_ui(ui: &mut egui::Ui, glow: &mut Glow) {
+    // Bevy's bloom doesn't work on WebGL2, so only the tonemapping applies there
+    ui.add_enabled_ui(cfg!(not(feature = "webgl2")), |ui| {
+        ui.add(egui::Slider::new(&mut glow.intensity, 0.0..=1.0).text("intensity"));
+        ui.add(egui::Slider::new(&mut glow.threshold, 0.0..=4.0).text("threshold"))
+            .on_hover_text("brightness that starts to glow, one particle is at most 1");
+    });
+    let name = |tonemapping| {
+        TONEMAPPERS
+            .iter()
+            .find(|(candidate, _)| *candidate == tonemapping)
+            .map_or("", |(_, name)| name)
+    };
+    egui::ComboBox::from_label("tonemapping")
+        .selected_text(name(glow.tonemapping))
+        .show_ui(ui, |ui| {
+            for (tonemapping, name) in TONEMAPPERS {
+                ui.selectable_value(&mut glow.tonemapping, tonemapping, name);
+            }
+        });
+}
+
+fn density_ui(ui: &mut egui::Ui, density: &mut DensityField) {
+    ui.horizontal_wrapped(|ui| {
+        for mode in RenderMode::ALL {
+            ui.selectable_value(&mut density.mode, mode, mode.name());
+        }
+    });
+    ui.add_enabled_ui(density.mode != RenderMode::Dots, |ui| {
+        ui.add(egui::Slider::new(&mut density.radius, 2.0..=48.0).text("kernel radius"));
+        ui.add(egui::Slider::new(&mut density.threshold, 0.05..=4.0).text("threshold"))
+            .on_hover_text("density where blobs start, a lone particle peaks at 1");
+    });
+}
+
+/// Takes the map's `ResMut` so it's only marked changed, and its field rebuilt, when it's edited
+fn obstacles_ui(ui: &mut egui::Ui, obstacles: &mut ResMut<ObstacleMap>, brush: &mut Brush) {
+    ui.checkbox(&mut brush.painting, "draw with the mouse");
+    ui.add_enabled_ui(brush.painting, |ui| {
+        ui.add(egui::Slider::new(&mut brush.radius, 1.0..=64.0).text("brush radius"));
+        ui.checkbox(&mut brush.erase, "erase");
+    });
+    if ui.button("Clear").clicked() {
+        obstacles.clear();
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        ui.label("import PNG, dark pixels are walls");
+        ui.text_edit_singleline(&mut brush.path);
+        if ui.button("Import").clicked() {
+            let imported = std::fs::read(&brush.path)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| ObstacleMap::from_png(&bytes));
+            match imported {
+                Ok(imported) => {
+                    let (width, height) = (obstacles.width, obstacles.height);
+                    **obstacles = imported.resized(width, height);
+                    brush.error = None;
+                }
+                Err(err) => brush.error = Some(err),
+            }
+        }
+        if let Some(error) = &brush.error {
+            ui.label(format!("couldn't import: {}", error));
+        }
+    }
+}
+
+/// Paints or erases walls under the cursor while the left button is held, unless egui is using
+/// the pointer
+fn paint_obstacles(
+    mut contexts: EguiContexts,
+    brush: Res<Brush>,
+    mouse: Res<Input<MouseButton>>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    config: Res<Config>,
+    mut obstacles: ResMut<ObstacleMap>,
+) {
+    if !brush.painting || config.three_d || !mouse.pressed(MouseButton::Left) {
+        return;
+    }
+    let ctx = contexts.ctx_mut();
+    if ctx.wants_pointer_input() || ctx.is_pointer_over_area() {
+        return;
+    }
+    let Ok(window) = window_query.get_single() else {
+        return;
+    };
+    let Some(cursor) = window.cursor_position() else {
+        return;
+    };
+
+    // the render sprite is centred in the window at a pixel per logical pixel, with y down like
+    // the cursor's position
+    let (width, height) = config.world_size_f32();
+    let centre = [
+        cursor.x - (window.width() - width) / 2.,
+        cursor.y - (window.height() - height) / 2.,
+    ];
+    obstacles.paint(centre, brush.radius, !brush.erase);
+}
+
+fn flavour_picker(
+    ui: &mut egui::Ui,
+    id: impl std::hash::Hash,
+    label: &str,
+    flavour: &mut u32,
+    flavours: usize,
+) {
+    egui::ComboBox::new(id, label)
+        .selected_text(flavour.to_string())
+        .show_ui(ui, |ui| {
+            for other in 0..flavours as u32 {
+                ui.selectable_value(flavour, other, other.to_string());
+            }
+        });
+}
+
+/// Puts the preset in the page's fragment and copies the resulting URL, keeping the query string
+/// so settings that aren't part of the preset carry over too
+#[cfg(target_arch = "wasm32")]
+fn copy_link(preset: &Preset) {
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let location = window.location();
+    let fragment = preset.to_base64();
+
+    // reloading the page should give the same simulation too
+    if location.set_hash(&fragment).is_err() {
+        return;
+    }
+    let Ok(link) = location.href() else {
+        return;
+    };
+
+    match window.navigator().clipboard() {
+        Some(clipboard) => {
+            // the promise only fails if the page doesn't have focus
+            let _ = clipboard.write_text(&link);
+        }
+        None => web_sys::console::log_1(&link.into()),
+    }
 }
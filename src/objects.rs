@@ -6,60 +6,311 @@ use bevy::{
 use bytemuck::{Pod, Zeroable};
 use rand::prelude::*;
 
-use crate::SIZE;
+pub const MAX_FLAVOURS: usize = 64;
 
-pub const MAX_FLAVOURS: usize = 10;
-pub const MAX_PARTICLES: usize = 64;
+/// Length of one simulation step in seconds, independent of the display refresh rate
+pub const FIXED_TIMESTEP: f32 = 1. / 60.;
+/// Upper bound on fixed steps taken in one frame, so a slow frame can't snowball
+pub const MAX_STEPS_PER_FRAME: u32 = 8;
+/// Upper bound on dispatches per frame in fast-forward mode
+pub const MAX_FAST_FORWARD_STEPS: u32 = 1024;
 
+/// A particle as the CPU backends and readback see it, and as it's stored on the GPU in 3D. The
+/// fields are ordered so each vec3 is followed by a u32 filling its 16 byte alignment.
 #[derive(
     ShaderType, Pod, Zeroable, Clone, Copy, Resource, Reflect, ExtractResource, Debug, Default,
 )]
 #[repr(C)]
 pub struct Particle {
-    position: [f32; 3],
-    _padding1: f32, // https://stackoverflow.com/a/75525055
-    velocity: [f32; 3],
-    _padding2: f32,
-    acceleration: [f32; 3], // TODO: is this needed
-    index: f32,
+    pub position: [f32; 3],
+    pub flavour: u32,
+    pub velocity: [f32; 3],
+    /// per-particle state bits, see `Particle::DEAD`
+    pub flags: u32,
+    /// seconds since the particle was spawned, respawned or converted
+    pub age: f32,
+    /// seconds spent touching the flavour that converts this one, see `LifecycleRule`
+    pub contact: f32,
+    /// the shader's struct is rounded up to its 16 byte alignment
+    pub _padding: [u32; 2],
 }
 
-// #[derive(Resource, Reflect, ExtractResource, Clone, Copy, Default, Pod, Zeroable)]
-// #[repr(C)]
-// pub struct Weights([[f32; MAX_FLAVOURS]; MAX_FLAVOURS]);
+impl Particle {
+    /// Set on particles that have died and not respawned. Their slots are left for emitters to
+    /// reuse, and they neither move, interact nor get drawn until then.
+    pub const DEAD: u32 = 1;
 
-#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Pod, Zeroable)]
+    pub fn new(position: [f32; 3], velocity: [f32; 3], flavour: usize) -> Self {
+        Self {
+            position,
+            flavour: flavour as u32,
+            velocity,
+            ..Default::default()
+        }
+    }
+
+    pub fn flavour(&self) -> usize {
+        self.flavour as usize
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.flags & Self::DEAD == 0
+    }
+}
+
+/// `Particle` without z, which is how flat worlds are stored on the GPU, in 32 bytes rather than 48
+#[derive(Pod, Zeroable, Clone, Copy, Debug, Default)]
 #[repr(C)]
-pub struct Particles([Particle; MAX_PARTICLES]);
-impl Default for Particles {
+pub struct PackedParticle {
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    pub flavour: u32,
+    pub flags: u32,
+    pub age: f32,
+    pub contact: f32,
+}
+
+impl From<&Particle> for PackedParticle {
+    fn from(particle: &Particle) -> Self {
+        Self {
+            position: [particle.position[0], particle.position[1]],
+            velocity: [particle.velocity[0], particle.velocity[1]],
+            flavour: particle.flavour,
+            flags: particle.flags,
+            age: particle.age,
+            contact: particle.contact,
+        }
+    }
+}
+
+impl From<&PackedParticle> for Particle {
+    fn from(particle: &PackedParticle) -> Self {
+        Self {
+            position: [particle.position[0], particle.position[1], 0.],
+            flavour: particle.flavour,
+            velocity: [particle.velocity[0], particle.velocity[1], 0.],
+            flags: particle.flags,
+            age: particle.age,
+            contact: particle.contact,
+            _padding: [0; 2],
+        }
+    }
+}
+
+// `struct Particle` in the shaders, with and without THREE_D, laid out by WGSL's rules
+const _: () = {
+    use std::mem::{offset_of, size_of};
+
+    assert!(size_of::<Particle>() == 48);
+    assert!(offset_of!(Particle, position) == 0);
+    assert!(offset_of!(Particle, flavour) == 12);
+    assert!(offset_of!(Particle, velocity) == 16);
+    assert!(offset_of!(Particle, flags) == 28);
+    assert!(offset_of!(Particle, age) == 32);
+    assert!(offset_of!(Particle, contact) == 36);
+
+    assert!(size_of::<PackedParticle>() == 32);
+    assert!(offset_of!(PackedParticle, position) == 0);
+    assert!(offset_of!(PackedParticle, velocity) == 8);
+    assert!(offset_of!(PackedParticle, flavour) == 16);
+    assert!(offset_of!(PackedParticle, flags) == 20);
+    assert!(offset_of!(PackedParticle, age) == 24);
+    assert!(offset_of!(PackedParticle, contact) == 28);
+};
+
+/// Attraction of flavour `[a]` towards flavour `[b]`, in -1..1
+#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
+#[repr(C)]
+pub struct Weights(pub [[f32; MAX_FLAVOURS]; MAX_FLAVOURS]);
+
+// std only implements Default for arrays of up to 32
+impl Default for Weights {
     fn default() -> Self {
-        let mut particles = [Particle::default(); MAX_PARTICLES];
+        Zeroable::zeroed()
+    }
+}
+
+impl Weights {
+    pub fn random(rng: &mut impl Rng) -> Self {
+        let mut weights = [[0.; MAX_FLAVOURS]; MAX_FLAVOURS];
+        for row in weights.iter_mut() {
+            for weight in row.iter_mut() {
+                *weight = rng.gen::<f32>() * 2. - 1.;
+            }
+        }
+        Self(weights)
+    }
+}
 
-        for i in 0..MAX_PARTICLES {
-            particles[i].position = [
-                (random::<f32>() * (SIZE.0 as f32)) - (SIZE.0 as f32 / 2.),
-                (random::<f32>() * (SIZE.1 as f32)) - (SIZE.1 as f32 / 2.),
-                0.,
-            ];
+/// Shape of the particle life force curve
+#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Debug)]
+pub struct ForceParams {
+    /// interaction range in pixels
+    pub max_distance: f32,
+    /// fraction of `max_distance` inside which particles always repel
+    pub repulsion_distance: f32,
+    /// acceleration at full attraction, in `max_distance`s per second squared
+    pub force_scale: f32,
+    /// seconds for velocity to halve with no forces applied
+    pub friction_half_life: f32,
+}
 
-            particles[i].velocity = [random::<f32>() - 0.5, random::<f32>() - 0.5, 0.]
+impl Default for ForceParams {
+    fn default() -> Self {
+        Self {
+            max_distance: 48.,
+            repulsion_distance: 0.3,
+            force_scale: 10.,
+            friction_half_life: 0.04,
         }
+    }
+}
+
+#[derive(Resource, Reflect, ExtractResource, Clone, Default)]
+pub struct Particles(pub Vec<Particle>);
+impl Particles {
+    /// Particles spread uniformly over a world with its origin at the top left and y down, like
+    /// the walls. A world with no depth is flat, and its particles stay at z = 0.
+    pub fn new(count: usize, flavours: usize, world_size: [f32; 3], rng: &mut impl Rng) -> Self {
+        let depth = world_size[2];
+        let particles = (0..count)
+            .map(|_| {
+                let position = [
+                    rng.gen::<f32>() * world_size[0],
+                    rng.gen::<f32>() * world_size[1],
+                    rng.gen::<f32>() * depth,
+                ];
+
+                // velocity is in pixels per second
+                let velocity = [
+                    (rng.gen::<f32>() - 0.5) / FIXED_TIMESTEP,
+                    (rng.gen::<f32>() - 0.5) / FIXED_TIMESTEP,
+                    if depth > 0. {
+                        (rng.gen::<f32>() - 0.5) / FIXED_TIMESTEP
+                    } else {
+                        0.
+                    },
+                ];
+
+                Particle::new(position, velocity, rng.gen_range(0..flavours))
+            })
+            .collect();
 
-        println!("{:?}", particles);
         Self(particles)
     }
 }
 
-#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Default, Pod, Zeroable)]
+/// What each flavour is drawn in, as red, green, blue and an unused alpha in 0..1, stored in the
+/// render image as they are. The default is all black, see `palette` for ways of filling it.
+#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
 #[repr(C)]
-pub struct ParticleColours([[f32; 4]; MAX_FLAVOURS]);
+pub struct ParticleColours(pub [[f32; 4]; MAX_FLAVOURS]);
 
-#[derive(Resource, Clone, Deref, ExtractResource, Reflect)]
-pub struct RenderImage {
-    pub image: Handle<Image>,
+impl Default for ParticleColours {
+    fn default() -> Self {
+        Zeroable::zeroed()
+    }
+}
+
+/// How one flavour moves, relative to `ForceParams` so the defaults change nothing
+#[derive(Reflect, Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
+#[repr(C)]
+pub struct PhysicalProperties {
+    /// divides the acceleration from forces on this flavour
+    pub mass: f32,
+    /// multiplies `repulsion_distance`, which for a pair is scaled by the mean of their radii
+    pub radius: f32,
+    /// multiplies the rate velocity decays at, so 2 halves it in half `friction_half_life`
+    pub friction: f32,
+    /// in pixels per second, 0 for no limit
+    pub max_speed: f32,
+}
+
+impl Default for PhysicalProperties {
+    fn default() -> Self {
+        Self {
+            mass: 1.,
+            radius: 1.,
+            friction: 1.,
+            max_speed: 0.,
+        }
+    }
+}
+
+#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Pod, Zeroable, Debug, PartialEq)]
+#[repr(C)]
+pub struct FlavourProperties(pub [PhysicalProperties; MAX_FLAVOURS]);
+
+impl Default for FlavourProperties {
+    fn default() -> Self {
+        Self([PhysicalProperties::default(); MAX_FLAVOURS])
+    }
+}
+
+#[derive(Resource, Reflect, Clone, Copy, Debug)]
+pub struct SimulationSettings {
+    /// number of update dispatches per fixed step, each advancing FIXED_TIMESTEP / substeps
+    pub substeps: u32,
+    /// simulated seconds per real second
+    pub time_scale: f32,
+    /// ignore real time and run as many steps as fit in `frame_budget_ms`
+    pub fast_forward: bool,
+    pub frame_budget_ms: f32,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        Self {
+            substeps: 1,
+            time_scale: 1.,
+            fast_forward: false,
+            frame_budget_ms: 16.,
+        }
+    }
+}
+
+/// Work for the simulation node this frame, computed from `SimulationSettings`
+#[derive(Resource, Reflect, ExtractResource, Clone, Copy, Debug, Default)]
+pub struct SimulationSteps {
+    pub count: u32,
+    pub dt: f32,
+    pub accumulator: f32,
+}
+
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SimulationParams {
+    /// a depth of 0 is the flat 2D world
+    pub world_size: [f32; 3],
+    pub dt: f32,
+    pub max_distance: f32,
+    pub repulsion_distance: f32,
+    pub force_scale: f32,
+    pub friction_half_life: f32,
+    /// varies the random numbers of respawns and spawns between frames
+    pub seed: u32,
+    /// particles the spawn pass adds from the emitters, if there are free slots for them
+    pub spawn_count: u32,
+    /// rows of the flavour tables the GPU holds, see `GpuSimulation`
+    pub flavour_count: u32,
+    pub _padding: u32,
+}
+
+impl SimulationParams {
+    pub fn new(dt: f32, world_size: [f32; 3], params: &ForceParams) -> Self {
+        Self {
+            world_size,
+            dt,
+            max_distance: params.max_distance,
+            repulsion_distance: params.repulsion_distance,
+            force_scale: params.force_scale,
+            friction_half_life: params.friction_half_life,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Resource, Clone, Deref, ExtractResource, Reflect)]
-pub struct WeightsImage {
+pub struct RenderImage {
     pub image: Handle<Image>,
 }
@@ -0,0 +1,319 @@
+//! Static walls in a flat world, drawn with the brush in the menu or imported from a PNG. The
+//! walls are edited as an occupancy map with a cell per pixel of the world, from which a signed
+//! distance field is derived for the simulation to push particles out of the walls along, and for
+//! the renderers to draw the walls from.
+//!
+//! The walls don't wrap like the world does, and a 3D world ignores them.
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::TextureFormat,
+        texture::{CompressedImageFormats, ImageType},
+    },
+};
+
+/// Distance to the nearest wall, or free cell, when there isn't one
+pub const FAR: f32 = 1e6;
+
+/// Which cells of the world are walls, row-major with y down like the render image
+#[derive(Resource, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObstacleMap {
+    pub width: u32,
+    pub height: u32,
+    pub cells: Vec<bool>,
+}
+
+impl ObstacleMap {
+    /// No walls
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![false; width as usize * height as usize],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.cells.contains(&true)
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        self.cells[(y * self.width + x) as usize]
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.fill(false);
+    }
+
+    /// Fills the cells whose centres are within `radius` of `centre` with walls, or removes them
+    pub fn paint(&mut self, centre: [f32; 2], radius: f32, wall: bool) {
+        let lower = centre.map(|c| (c - radius).floor().max(0.) as u32);
+        let upper = [
+            ((centre[0] + radius).ceil().max(0.) as u32).min(self.width),
+            ((centre[1] + radius).ceil().max(0.) as u32).min(self.height),
+        ];
+        for y in lower[1]..upper[1] {
+            for x in lower[0]..upper[0] {
+                let offset = [x as f32 + 0.5 - centre[0], y as f32 + 0.5 - centre[1]];
+                if offset[0] * offset[0] + offset[1] * offset[1] <= radius * radius {
+                    self.cells[(y * self.width + x) as usize] = wall;
+                }
+            }
+        }
+    }
+
+    /// Scaled to `width` x `height` by taking the nearest cell
+    pub fn resized(&self, width: u32, height: u32) -> Self {
+        if (width, height) == (self.width, self.height) {
+            return self.clone();
+        }
+        let mut resized = Self::new(width, height);
+        if self.cells.is_empty() {
+            return resized;
+        }
+        for y in 0..height {
+            let from_y = (y as u64 * self.height as u64 / height as u64) as u32;
+            for x in 0..width {
+                let from_x = (x as u64 * self.width as u64 / width as u64) as u32;
+                resized.cells[(y * width + x) as usize] = self.get(from_x, from_y);
+            }
+        }
+        resized
+    }
+
+    /// Walls where a PNG is dark and opaque, at the PNG's size
+    pub fn from_png(bytes: &[u8]) -> Result<Self, String> {
+        let image = decode_png(bytes)?;
+        let cells = image
+            .data
+            .chunks_exact(4)
+            .map(|pixel| {
+                let [r, g, b, a] = [pixel[0], pixel[1], pixel[2], pixel[3]].map(f32::from);
+                let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+                a >= 128. && luminance < 128.
+            })
+            .collect();
+        Ok(Self {
+            width: image.texture_descriptor.size.width,
+            height: image.texture_descriptor.size.height,
+            cells,
+        })
+    }
+
+    /// Lengths of alternating runs of free cells and walls, starting with free cells, in row-major
+    /// order
+    pub fn runs(&self) -> Vec<u32> {
+        let mut runs = vec![0];
+        let mut wall = false;
+        for &cell in &self.cells {
+            if cell != wall {
+                runs.push(0);
+                wall = cell;
+            }
+            *runs.last_mut().unwrap() += 1;
+        }
+        runs
+    }
+
+    /// The inverse of `runs`, if they cover exactly `width` x `height` cells
+    pub fn from_runs(width: u32, height: u32, runs: &[u32]) -> Option<Self> {
+        let len = width as u64 * height as u64;
+        if runs.iter().map(|&run| run as u64).sum::<u64>() != len {
+            return None;
+        }
+        let mut cells = Vec::with_capacity(len as usize);
+        for (i, &run) in runs.iter().enumerate() {
+            cells.resize(cells.len() + run as usize, i % 2 == 1);
+        }
+        Some(Self {
+            width,
+            height,
+            cells,
+        })
+    }
+
+    /// Signed distance from each cell's centre to the walls' edges, negative inside them, so the
+    /// edges are half way between a wall and a free cell
+    pub fn field(&self) -> ObstacleField {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let to_walls = distance_transform(&self.cells, width, height);
+        let free: Vec<bool> = self.cells.iter().map(|&wall| !wall).collect();
+        let to_free = distance_transform(&free, width, height);
+
+        let distance = self
+            .cells
+            .iter()
+            .zip(to_walls.iter().zip(&to_free))
+            .map(|(&wall, (&to_wall, &to_free))| {
+                if wall {
+                    -(to_free.sqrt() as f32 - 0.5).min(FAR)
+                } else {
+                    (to_wall.sqrt() as f32 - 0.5).min(FAR)
+                }
+            })
+            .collect();
+        ObstacleField {
+            width: self.width,
+            height: self.height,
+            distance,
+        }
+    }
+}
+
+/// A PNG as 8 bit RGBA, whatever its pixel format
+pub(crate) fn decode_png(bytes: &[u8]) -> Result<Image, String> {
+    // sRGB is only so greyscale and RGB images can be converted, the bytes are kept as they are
+    let image = Image::from_buffer(
+        bytes,
+        ImageType::Extension("png"),
+        CompressedImageFormats::NONE,
+        true,
+    )
+    .map_err(|err| err.to_string())?;
+    image
+        .convert(TextureFormat::Rgba8UnormSrgb)
+        .ok_or_else(|| "unsupported pixel format".to_string())
+}
+
+/// Squared euclidean distance from each cell to the nearest cell in `targets`, as separable
+/// lower envelopes of parabolas (Felzenszwalb and Huttenlocher)
+fn distance_transform(targets: &[bool], width: usize, height: usize) -> Vec<f64> {
+    let mut distances: Vec<f64> = targets
+        .iter()
+        .map(|&target| if target { 0. } else { INFINITY })
+        .collect();
+
+    let mut line = Vec::new();
+    let mut transformed = vec![0.; width.max(height)];
+    for x in 0..width {
+        line.clear();
+        line.extend((0..height).map(|y| distances[y * width + x]));
+        transform_line(&line, &mut transformed);
+        for y in 0..height {
+            distances[y * width + x] = transformed[y];
+        }
+    }
+    for row in distances.chunks_exact_mut(width.max(1)) {
+        line.clear();
+        line.extend_from_slice(row);
+        transform_line(&line, &mut transformed);
+        row.copy_from_slice(&transformed[..width]);
+    }
+    distances
+}
+
+/// Larger than any squared distance in a world, but finite so the envelope's intersections are
+const INFINITY: f64 = 1e20;
+
+/// `output[q]` is the minimum over `p` of `(q - p)^2 + f[p]`
+fn transform_line(f: &[f64], output: &mut [f64]) {
+    let n = f.len();
+    if n == 0 {
+        return;
+    }
+    // the parabolas in the envelope and where each starts being the lowest
+    let mut vertices = vec![0; n];
+    let mut starts = vec![0.; n + 1];
+    let mut k = 0;
+    starts[0] = -INFINITY;
+    starts[1] = INFINITY;
+    let intersection = |q: usize, p: usize| {
+        ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2. * q as f64 - 2. * p as f64)
+    };
+    for q in 1..n {
+        let mut s = intersection(q, vertices[k]);
+        while s <= starts[k] {
+            k -= 1;
+            s = intersection(q, vertices[k]);
+        }
+        k += 1;
+        vertices[k] = q;
+        starts[k] = s;
+        starts[k + 1] = INFINITY;
+    }
+
+    k = 0;
+    for (q, output) in output.iter_mut().enumerate().take(n) {
+        while starts[k + 1] < q as f64 {
+            k += 1;
+        }
+        let p = vertices[k];
+        *output = (q as f64 - p as f64).powi(2) + f[p];
+    }
+}
+
+/// The walls as the simulation and renderers use them, uploaded to the GPU as an `R32Float`
+/// texture. The default has no walls.
+#[derive(Resource, ExtractResource, Clone, Debug, PartialEq)]
+pub struct ObstacleField {
+    pub width: u32,
+    pub height: u32,
+    /// row-major like `ObstacleMap`, in pixels
+    pub distance: Vec<f32>,
+}
+
+impl Default for ObstacleField {
+    fn default() -> Self {
+        Self {
+            width: 1,
+            height: 1,
+            distance: vec![FAR],
+        }
+    }
+}
+
+impl ObstacleField {
+    /// The distance at the nearest cell to `(x, y)`
+    pub fn at(&self, x: i32, y: i32) -> f32 {
+        let x = x.clamp(0, self.width as i32 - 1) as u32;
+        let y = y.clamp(0, self.height as i32 - 1) as u32;
+        self.distance[(y * self.width + x) as usize]
+    }
+
+    /// Pushes a particle that's inside a wall back out along the field's slope, to half a cell
+    /// past the edge, and reflects its velocity if it's heading further in. The position may need
+    /// wrapping afterwards. See `collide` in simulation.wgsl.
+    pub fn collide(&self, position: &mut [f32; 3], velocity: &mut [f32; 3]) {
+        let (x, y) = (position[0].floor() as i32, position[1].floor() as i32);
+        let distance = self.at(x, y);
+        if distance >= 0. {
+            return;
+        }
+
+        let slope = [
+            self.at(x + 1, y) - self.at(x - 1, y),
+            self.at(x, y + 1) - self.at(x, y - 1),
+        ];
+        let length = (slope[0] * slope[0] + slope[1] * slope[1]).sqrt();
+        if length <= 0. {
+            return;
+        }
+        let normal = slope.map(|s| s / length);
+
+        let push = 0.5 - distance;
+        let along = velocity[0] * normal[0] + velocity[1] * normal[1];
+        for axis in 0..2 {
+            position[axis] += normal[axis] * push;
+            if along < 0. {
+                velocity[axis] -= 2. * along * normal[axis];
+            }
+        }
+    }
+}
+
+/// Keeps the field in step with the map
+pub struct Obstacles;
+impl Plugin for Obstacles {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ObstacleField>()
+            .add_systems(PostUpdate, update_field);
+    }
+}
+
+pub(crate) fn update_field(map: Res<ObstacleMap>, mut field: ResMut<ObstacleField>) {
+    if map.is_changed() {
+        *field = map.field();
+    }
+}
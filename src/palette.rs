@@ -0,0 +1,174 @@
+//! Ways of filling `ParticleColours`: built-in palettes, hues spaced evenly around the colour
+//! wheel, and palettes imported from `.hex` files (one `RRGGBB` per line, as Lospec exports them)
+//! or GIMP's `.gpl` files. Palettes shorter than the flavours repeat.
+
+use crate::objects::{ParticleColours, MAX_FLAVOURS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+    Rainbow,
+    Pastel,
+    /// Okabe and Ito's, distinguishable with every common kind of colour blindness
+    ColourBlindSafe,
+    /// white to black, avoiding the background's grey
+    Monochrome,
+}
+
+/// Okabe and Ito's palette, with white in place of black, which the render image's background is
+/// closer to
+const OKABE_ITO: [u32; 8] = [
+    0xe69f00, 0x56b4e9, 0x009e73, 0xf0e442, 0x0072b2, 0xd55e00, 0xcc79a7, 0xffffff,
+];
+
+impl Palette {
+    pub const ALL: [Palette; 4] = [
+        Palette::Rainbow,
+        Palette::Pastel,
+        Palette::ColourBlindSafe,
+        Palette::Monochrome,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Palette::Rainbow => "rainbow",
+            Palette::Pastel => "pastel",
+            Palette::ColourBlindSafe => "colour-blind safe",
+            Palette::Monochrome => "monochrome",
+        }
+    }
+
+    /// Colours for the first `flavours` flavours
+    pub fn colours(self, flavours: usize) -> ParticleColours {
+        match self {
+            Palette::Rainbow => evenly_spaced_hues(flavours, 0., 1., 1.),
+            Palette::Pastel => evenly_spaced_hues(flavours, 0., 0.4, 1.),
+            Palette::ColourBlindSafe => repeated(&OKABE_ITO.map(from_rgb)),
+            Palette::Monochrome => {
+                let mut colours = ParticleColours::default();
+                let steps = flavours.saturating_sub(1).max(1) as f32;
+                for (flavour, colour) in colours.0.iter_mut().enumerate().take(flavours) {
+                    // skipping the greys near the background's
+                    let t = flavour as f32 / steps;
+                    let value = if t <= 0.5 {
+                        1. - 0.7 * t
+                    } else {
+                        0.7 * (1. - t)
+                    };
+                    *colour = [value, value, value, 1.];
+                }
+                colours
+            }
+        }
+    }
+}
+
+/// `palette` over every flavour, starting again from its first colour when it runs out
+pub fn repeated(palette: &[[f32; 4]]) -> ParticleColours {
+    let mut colours = ParticleColours::default();
+    if !palette.is_empty() {
+        for (colour, from) in colours.0.iter_mut().zip(palette.iter().cycle()) {
+            *colour = *from;
+        }
+    }
+    colours
+}
+
+/// The first `flavours` flavours spread evenly around the hue circle from `offset`, in turns
+pub fn evenly_spaced_hues(
+    flavours: usize,
+    offset: f32,
+    saturation: f32,
+    value: f32,
+) -> ParticleColours {
+    let mut colours = ParticleColours::default();
+    for (flavour, colour) in colours.0.iter_mut().enumerate().take(flavours) {
+        let hue = offset + flavour as f32 / flavours as f32;
+        *colour = hsv(hue, saturation, value);
+    }
+    colours
+}
+
+/// An opaque colour from a hue in turns, and saturation and value in 0..1
+pub fn hsv(hue: f32, saturation: f32, value: f32) -> [f32; 4] {
+    let channel = |n: f32| {
+        let k = (n + hue.rem_euclid(1.) * 6.) % 6.;
+        value - value * saturation * k.min(4. - k).clamp(0., 1.)
+    };
+    [channel(5.), channel(3.), channel(1.), 1.]
+}
+
+fn from_rgb(rgb: u32) -> [f32; 4] {
+    let channel = |shift: u32| ((rgb >> shift) & 0xff) as f32 / 255.;
+    [channel(16), channel(8), channel(0), 1.]
+}
+
+/// The colours in a `.gpl` file if `name` says it is one, otherwise a `.hex` file
+pub fn parse_palette(name: &str, text: &str) -> Result<Vec<[f32; 4]>, String> {
+    let colours = if name.to_ascii_lowercase().ends_with(".gpl") {
+        parse_gpl(text)?
+    } else {
+        parse_hex(text)?
+    };
+    if colours.is_empty() {
+        return Err("the palette has no colours".to_string());
+    }
+    if colours.len() > MAX_FLAVOURS {
+        bevy::log::warn!(
+            "the palette has {} colours, only the first {} are used",
+            colours.len(),
+            MAX_FLAVOURS
+        );
+    }
+    Ok(colours)
+}
+
+/// `RRGGBB` per line, optionally after a `#`
+pub fn parse_hex(text: &str) -> Result<Vec<[f32; 4]>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim().trim_start_matches('#')))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            // from_str_radix alone would take a sign
+            let hex = line.len() == 6 && line.bytes().all(|b| b.is_ascii_hexdigit());
+            let rgb = hex
+                .then(|| u32::from_str_radix(line, 16).ok())
+                .flatten()
+                .ok_or_else(|| format!("line {} isn't a hex colour", i + 1))?;
+            Ok(from_rgb(rgb))
+        })
+        .collect()
+}
+
+/// GIMP's palette format: a `GIMP Palette` header, optional `Name:` and `Columns:` lines and `#`
+/// comments, then one colour per line as decimal red, green and blue, maybe followed by a name
+pub fn parse_gpl(text: &str) -> Result<Vec<[f32; 4]>, String> {
+    let mut lines = text.lines().enumerate();
+    if lines.next().map(|(_, line)| line.trim()) != Some("GIMP Palette") {
+        return Err("not a GIMP palette".to_string());
+    }
+
+    let mut colours = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        let channels: Vec<_> = line
+            .split_whitespace()
+            .take(3)
+            .map(|channel| channel.parse::<u8>().ok())
+            .collect();
+        match channels[..] {
+            [Some(r), Some(g), Some(b)] => {
+                colours.push(from_rgb(u32::from_be_bytes([0, r, g, b])));
+            }
+            _ => return Err(format!("line {} isn't a colour", i + 1)),
+        }
+    }
+    Ok(colours)
+}
@@ -0,0 +1,334 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Buffer, BufferDescriptor, BufferUsages, MapMode},
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+};
+use bevy_egui::{
+    egui::{
+        self,
+        plot::{Legend, Line, Plot, PlotPoints},
+    },
+    EguiContexts,
+};
+use bytemuck::cast_slice;
+use wgpu::{Features, QuerySet, QuerySetDescriptor, QueryType};
+
+use crate::{config::Config, objects::SimulationSteps};
+
+pub const SIMULATION_START: u32 = 0;
+pub const SIMULATION_END: u32 = 1;
+pub const RENDER_START: u32 = 2;
+pub const RENDER_END: u32 = 3;
+const TIMESTAMP_COUNT: u32 = 4;
+
+const MAX_SAMPLES: usize = 300;
+
+// readback state, shared with the map_async callback
+const IDLE: u8 = 0;
+const COPIED: u8 = 1;
+const MAPPING: u8 = 2;
+const MAPPED: u8 = 3;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GpuTiming {
+    pub simulation_ms: f32,
+    pub render_ms: f32,
+}
+
+/// Latest GPU pass timings, shared between the main and render worlds
+#[derive(Resource, Clone, Default)]
+pub struct GpuTimings(pub Arc<Mutex<Option<GpuTiming>>>);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PerfSample {
+    pub frame_ms: f32,
+    pub fps: f32,
+    pub particles_per_second: f32,
+    pub gpu: Option<GpuTiming>,
+}
+
+#[derive(Resource, Default)]
+pub struct PerfStats {
+    pub samples: VecDeque<PerfSample>,
+}
+
+#[derive(Resource)]
+pub struct TimestampQueries {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+    state: Arc<AtomicU8>,
+}
+
+pub struct Perf;
+impl Plugin for Perf {
+    fn build(&self, app: &mut App) {
+        let timings = GpuTimings::default();
+
+        app.insert_resource(timings.clone())
+            .init_resource::<PerfStats>()
+            .add_systems(Update, (record_perf, perf_ui).chain());
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(timings)
+            .add_systems(Render, read_timestamps.in_set(RenderSet::Prepare))
+            .add_systems(Render, map_timestamps.in_set(RenderSet::Cleanup));
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        let render_device = render_app.world.resource::<RenderDevice>();
+
+        // without timestamp queries the overlay falls back to CPU frame times
+        if !render_device.features().contains(Features::TIMESTAMP_QUERY) {
+            return;
+        }
+
+        let size = (TIMESTAMP_COUNT as usize * std::mem::size_of::<u64>()) as u64;
+        let queries = TimestampQueries {
+            query_set: render_device
+                .wgpu_device()
+                .create_query_set(&QuerySetDescriptor {
+                    label: Some("timestamp queries"),
+                    ty: QueryType::Timestamp,
+                    count: TIMESTAMP_COUNT,
+                }),
+            resolve_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("timestamp resolve buffer"),
+                size,
+                usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("timestamp readback buffer"),
+                size,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            state: Arc::new(AtomicU8::new(IDLE)),
+        };
+
+        render_app.insert_resource(queries);
+    }
+}
+
+/// Record a GPU timestamp between passes, if the adapter supports it
+pub fn write_timestamp(world: &World, render_context: &mut RenderContext, index: u32) {
+    if let Some(queries) = world.get_resource::<TimestampQueries>() {
+        render_context
+            .command_encoder()
+            .write_timestamp(&queries.query_set, index);
+    }
+}
+
+/// Resolve this frame's timestamps, and queue them for readback if the previous read has finished.
+/// Called from the last node in our chain.
+pub fn resolve_timestamps(world: &World, render_context: &mut RenderContext) {
+    let Some(queries) = world.get_resource::<TimestampQueries>() else {
+        return;
+    };
+
+    let encoder = render_context.command_encoder();
+    encoder.resolve_query_set(
+        &queries.query_set,
+        0..TIMESTAMP_COUNT,
+        &queries.resolve_buffer,
+        0,
+    );
+
+    if queries
+        .state
+        .compare_exchange(IDLE, COPIED, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        encoder.copy_buffer_to_buffer(
+            &queries.resolve_buffer,
+            0,
+            &queries.readback_buffer,
+            0,
+            queries.readback_buffer.size(),
+        );
+    }
+}
+
+fn map_timestamps(queries: Option<Res<TimestampQueries>>) {
+    let Some(queries) = queries else {
+        return;
+    };
+
+    // the copy has been submitted by now
+    if queries
+        .state
+        .compare_exchange(COPIED, MAPPING, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+    {
+        let state = queries.state.clone();
+        queries
+            .readback_buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |result| {
+                state.store(
+                    if result.is_ok() { MAPPED } else { IDLE },
+                    Ordering::Release,
+                );
+            });
+    }
+}
+
+fn read_timestamps(
+    queries: Option<Res<TimestampQueries>>,
+    timings: Res<GpuTimings>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(queries) = queries else {
+        return;
+    };
+
+    render_device.poll(wgpu::Maintain::Poll);
+    if queries.state.load(Ordering::Acquire) != MAPPED {
+        return;
+    }
+
+    {
+        let range = queries.readback_buffer.slice(..).get_mapped_range();
+        let ticks: &[u64] = cast_slice(&range);
+        let ms_per_tick = render_queue.get_timestamp_period() as f64 / 1_000_000.;
+        let elapsed = |start: u32, end: u32| {
+            (ticks[end as usize].wrapping_sub(ticks[start as usize]) as f64 * ms_per_tick) as f32
+        };
+
+        *timings.0.lock().unwrap() = Some(GpuTiming {
+            simulation_ms: elapsed(SIMULATION_START, SIMULATION_END),
+            render_ms: elapsed(RENDER_START, RENDER_END),
+        });
+    }
+
+    queries.readback_buffer.unmap();
+    queries.state.store(IDLE, Ordering::Release);
+}
+
+fn record_perf(
+    time: Res<Time>,
+    steps: Res<SimulationSteps>,
+    config: Res<Config>,
+    timings: Res<GpuTimings>,
+    mut stats: ResMut<PerfStats>,
+) {
+    let frame_seconds = time.raw_delta_seconds();
+    if frame_seconds <= 0. {
+        return;
+    }
+
+    let sample = PerfSample {
+        frame_ms: frame_seconds * 1000.,
+        fps: 1. / frame_seconds,
+        particles_per_second: (config.particle_count * steps.count as usize) as f32 / frame_seconds,
+        gpu: *timings.0.lock().unwrap(),
+    };
+
+    if stats.samples.len() == MAX_SAMPLES {
+        stats.samples.pop_front();
+    }
+    stats.samples.push_back(sample);
+}
+
+fn perf_ui(mut contexts: EguiContexts, stats: Res<PerfStats>) {
+    let Some(latest) = stats.samples.back() else {
+        return;
+    };
+
+    egui::Window::new("Performance")
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(format!("fps: {:.0}", latest.fps));
+            ui.label(format!("frame: {:.2} ms", latest.frame_ms));
+            match latest.gpu {
+                Some(gpu) => {
+                    ui.label(format!("simulation pass: {:.3} ms", gpu.simulation_ms));
+                    ui.label(format!("render pass: {:.3} ms", gpu.render_ms));
+                }
+                None => {
+                    ui.label("GPU timestamps unavailable, showing frame time only");
+                }
+            }
+            ui.label(format!(
+                "particles/s: {:.2}M",
+                latest.particles_per_second / 1_000_000.
+            ));
+
+            let series = |value: fn(&PerfSample) -> Option<f32>| -> PlotPoints {
+                stats
+                    .samples
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, sample)| value(sample).map(|v| [i as f64, v as f64]))
+                    .collect()
+            };
+
+            Plot::new("perf_plot")
+                .height(120.)
+                .include_y(0.)
+                .allow_drag(false)
+                .allow_zoom(false)
+                .allow_scroll(false)
+                .legend(Legend::default())
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(series(|s| Some(s.frame_ms))).name("frame ms"));
+                    plot_ui.line(
+                        Line::new(series(|s| s.gpu.map(|g| g.simulation_ms))).name("simulation ms"),
+                    );
+                    plot_ui
+                        .line(Line::new(series(|s| s.gpu.map(|g| g.render_ms))).name("render ms"));
+                });
+
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui.button("Export CSV").clicked() {
+                let path = format!(
+                    "perf-{}.csv",
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default()
+                );
+                match export_csv(&stats, std::path::Path::new(&path)) {
+                    Ok(()) => info!("wrote {}", path),
+                    Err(err) => error!("couldn't write {}: {}", path, err),
+                }
+            }
+        });
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn export_csv(stats: &PerfStats, path: &std::path::Path) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    writeln!(
+        file,
+        "sample,frame_ms,fps,particles_per_second,simulation_ms,render_ms"
+    )?;
+    for (i, sample) in stats.samples.iter().enumerate() {
+        let (simulation_ms, render_ms) = match sample.gpu {
+            Some(gpu) => (gpu.simulation_ms.to_string(), gpu.render_ms.to_string()),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            file,
+            "{},{},{},{},{},{}",
+            i, sample.frame_ms, sample.fps, sample.particles_per_second, simulation_ms, render_ms
+        )?;
+    }
+    file.flush()
+}
@@ -0,0 +1,392 @@
+//! Compact, versioned binary encoding of everything needed to recreate a simulation
+
+use std::fmt;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+use crate::{
+    config::MAX_WORLD_SIZE,
+    forces::{ExternalForces, FieldSource, Flow, FLOW_SIZE, MAX_SOURCES},
+    objects::{
+        FlavourProperties, ForceParams, ParticleColours, PhysicalProperties, Weights, MAX_FLAVOURS,
+    },
+    obstacles::ObstacleMap,
+};
+
+const MAGIC: &[u8; 4] = b"RPLP";
+/// Version 2 added flavour properties, which version 1 presets get the defaults for. Version 3
+/// added walls, which earlier versions have none of, and version 4 external forces, likewise.
+pub const PRESET_VERSION: u16 = 4;
+
+/// How each kind of `Flow` is tagged
+const NO_FLOW: u8 = 0;
+const NOISE_FLOW: u8 = 1;
+const IMAGE_FLOW: u8 = 2;
+
+#[derive(Clone, Debug)]
+pub struct Preset {
+    pub seed: u64,
+    pub particle_count: u32,
+    pub flavour_count: u32,
+    pub weights: Weights,
+    pub colours: ParticleColours,
+    pub params: ForceParams,
+    pub properties: FlavourProperties,
+    pub obstacles: Option<ObstacleMap>,
+    pub forces: ExternalForces,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PresetError {
+    BadEncoding,
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    TooManyFlavours(u32),
+    BadObstacles,
+    BadForces,
+    BadParams,
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::BadEncoding => write!(f, "preset isn't valid base64"),
+            PresetError::BadMagic => write!(f, "not a preset"),
+            PresetError::UnsupportedVersion(version) => {
+                write!(f, "unsupported preset version {}", version)
+            }
+            PresetError::Truncated => write!(f, "preset is truncated"),
+            PresetError::TooManyFlavours(count) => write!(
+                f,
+                "preset has {} flavours, at most {} are supported",
+                count, MAX_FLAVOURS
+            ),
+            PresetError::BadObstacles => write!(
+                f,
+                "preset's walls don't fill their map or are larger than {}x{}",
+                MAX_WORLD_SIZE, MAX_WORLD_SIZE
+            ),
+            PresetError::BadForces => write!(
+                f,
+                "preset has more than {} attractors and vortices or an unknown flow",
+                MAX_SOURCES
+            ),
+            PresetError::BadParams => write!(
+                f,
+                "preset has a force parameter or flavour property that isn't a finite number in range"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl Preset {
+    /// Only the first `flavour_count` rows/columns of weights, colours and properties are stored,
+    /// the walls as runs of free cells and walls, and an imported flow field as bytes like the image
+    /// it came from
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flavours = self.flavour_count as usize;
+        let mut bytes = Vec::with_capacity(64 + flavours * (flavours + 8) * 4);
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&PRESET_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.particle_count.to_le_bytes());
+        bytes.extend_from_slice(&self.flavour_count.to_le_bytes());
+
+        for value in [
+            self.params.max_distance,
+            self.params.repulsion_distance,
+            self.params.force_scale,
+            self.params.friction_half_life,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for row in &self.weights.0[..flavours] {
+            for weight in &row[..flavours] {
+                bytes.extend_from_slice(&weight.to_le_bytes());
+            }
+        }
+        for colour in &self.colours.0[..flavours] {
+            for channel in colour {
+                bytes.extend_from_slice(&channel.to_le_bytes());
+            }
+        }
+        for properties in &self.properties.0[..flavours] {
+            for value in [
+                properties.mass,
+                properties.radius,
+                properties.friction,
+                properties.max_speed,
+            ] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        write_obstacles(&mut bytes, self.obstacles.as_ref());
+        write_forces(&mut bytes, &self.forces);
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PresetError> {
+        let mut reader = Reader(bytes);
+
+        if reader.take::<4>()? != *MAGIC {
+            return Err(PresetError::BadMagic);
+        }
+        let version = u16::from_le_bytes(reader.take()?);
+        if !(1..=PRESET_VERSION).contains(&version) {
+            return Err(PresetError::UnsupportedVersion(version));
+        }
+
+        let seed = u64::from_le_bytes(reader.take()?);
+        let particle_count = u32::from_le_bytes(reader.take()?);
+        let flavour_count = u32::from_le_bytes(reader.take()?);
+        if flavour_count as usize > MAX_FLAVOURS {
+            return Err(PresetError::TooManyFlavours(flavour_count));
+        }
+
+        let params = ForceParams {
+            max_distance: reader.f32()?,
+            repulsion_distance: reader.f32()?,
+            force_scale: reader.f32()?,
+            friction_half_life: reader.f32()?,
+        };
+        if !params_valid(&params) {
+            return Err(PresetError::BadParams);
+        }
+
+        let flavours = flavour_count as usize;
+        let mut weights = Weights::default();
+        for row in &mut weights.0[..flavours] {
+            for weight in &mut row[..flavours] {
+                *weight = reader.f32()?;
+            }
+        }
+        let mut colours = ParticleColours::default();
+        for colour in &mut colours.0[..flavours] {
+            for channel in colour {
+                *channel = reader.f32()?;
+            }
+        }
+        let mut properties = FlavourProperties::default();
+        if version >= 2 {
+            for properties in &mut properties.0[..flavours] {
+                *properties = PhysicalProperties {
+                    mass: reader.f32()?,
+                    radius: reader.f32()?,
+                    friction: reader.f32()?,
+                    max_speed: reader.f32()?,
+                };
+                if !properties_valid(properties) {
+                    return Err(PresetError::BadParams);
+                }
+            }
+        }
+
+        let obstacles = if version >= 3 {
+            read_obstacles(&mut reader)?
+        } else {
+            None
+        };
+        let forces = if version >= 4 {
+            read_forces(&mut reader)?
+        } else {
+            ExternalForces::default()
+        };
+
+        Ok(Self {
+            seed,
+            particle_count,
+            flavour_count,
+            weights,
+            colours,
+            params,
+            properties,
+            obstacles,
+            forces,
+        })
+    }
+
+    /// URL-safe base64 without padding, for putting a preset in a link's fragment
+    pub fn to_base64(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.to_bytes())
+    }
+
+    pub fn from_base64(text: &str) -> Result<Self, PresetError> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(text.trim_start_matches('#'))
+            .map_err(|_| PresetError::BadEncoding)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Values the simulation can run with, so a corrupt preset can't fill the world with NaNs
+fn params_valid(params: &ForceParams) -> bool {
+    [
+        params.max_distance,
+        params.repulsion_distance,
+        params.force_scale,
+        params.friction_half_life,
+    ]
+    .iter()
+    .all(|value| value.is_finite())
+        && params.max_distance > 0.
+        && (0. ..1.).contains(&params.repulsion_distance)
+}
+
+fn properties_valid(properties: &PhysicalProperties) -> bool {
+    [
+        properties.mass,
+        properties.radius,
+        properties.friction,
+        properties.max_speed,
+    ]
+    .iter()
+    .all(|value| value.is_finite())
+        && properties.mass > 0.
+        && properties.radius >= 0.
+        && properties.friction >= 0.
+        && properties.max_speed >= 0.
+}
+
+/// Walls in the preset format, for recording them apart from the rest of the rules
+pub fn obstacles_to_bytes(obstacles: Option<&ObstacleMap>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_obstacles(&mut bytes, obstacles);
+    bytes
+}
+
+/// `None` for a map without walls
+pub fn obstacles_from_bytes(bytes: &[u8]) -> Result<Option<ObstacleMap>, PresetError> {
+    read_obstacles(&mut Reader(bytes))
+}
+
+/// External forces in the preset format, for recording them apart from the rest of the rules
+pub fn forces_to_bytes(forces: &ExternalForces) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_forces(&mut bytes, forces);
+    bytes
+}
+
+pub fn forces_from_bytes(bytes: &[u8]) -> Result<ExternalForces, PresetError> {
+    read_forces(&mut Reader(bytes))
+}
+
+/// Walls as runs of free cells and walls
+fn write_obstacles(bytes: &mut Vec<u8>, obstacles: Option<&ObstacleMap>) {
+    // no walls is an empty map
+    let empty = ObstacleMap::default();
+    let obstacles = obstacles.unwrap_or(&empty);
+    let runs = obstacles.runs();
+    for value in [obstacles.width, obstacles.height, runs.len() as u32] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for run in runs {
+        bytes.extend_from_slice(&run.to_le_bytes());
+    }
+}
+
+fn read_obstacles(reader: &mut Reader) -> Result<Option<ObstacleMap>, PresetError> {
+    let width = u32::from_le_bytes(reader.take()?);
+    let height = u32::from_le_bytes(reader.take()?);
+    // no bigger than any world, so a short preset can't claim billions of cells
+    if width > MAX_WORLD_SIZE || height > MAX_WORLD_SIZE {
+        return Err(PresetError::BadObstacles);
+    }
+    let run_count = u32::from_le_bytes(reader.take()?);
+    // each run takes 4 bytes, so a bad count can't allocate more than the preset's size
+    if run_count as usize > reader.0.len() / 4 {
+        return Err(PresetError::Truncated);
+    }
+    let runs = (0..run_count)
+        .map(|_| Ok(u32::from_le_bytes(reader.take()?)))
+        .collect::<Result<Vec<_>, PresetError>>()?;
+    let map = ObstacleMap::from_runs(width, height, &runs).ok_or(PresetError::BadObstacles)?;
+    Ok((!map.is_empty()).then_some(map))
+}
+
+/// An imported flow field is stored as bytes like the image it came from
+fn write_forces(bytes: &mut Vec<u8>, forces: &ExternalForces) {
+    for value in forces.gravity {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(forces.sources.len() as u32).to_le_bytes());
+    for source in &forces.sources {
+        bytes.extend_from_slice(&source.kind.to_le_bytes());
+        for value in source.position {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&source.strength.to_le_bytes());
+        bytes.extend_from_slice(&source.radius.to_le_bytes());
+    }
+    bytes.extend_from_slice(&forces.flow_strength.to_le_bytes());
+    match &forces.flow {
+        Flow::None => bytes.push(NO_FLOW),
+        Flow::Noise { cells, speed } => {
+            bytes.push(NOISE_FLOW);
+            bytes.extend_from_slice(&cells.to_le_bytes());
+            bytes.extend_from_slice(&speed.to_le_bytes());
+        }
+        Flow::Image(vectors) => {
+            bytes.push(IMAGE_FLOW);
+            bytes.extend(vectors.iter().flatten());
+        }
+    }
+}
+
+fn read_forces(reader: &mut Reader) -> Result<ExternalForces, PresetError> {
+    let mut forces = ExternalForces {
+        gravity: [reader.f32()?, reader.f32()?, reader.f32()?],
+        ..Default::default()
+    };
+    let source_count = u32::from_le_bytes(reader.take()?);
+    if source_count as usize > MAX_SOURCES {
+        return Err(PresetError::BadForces);
+    }
+    for _ in 0..source_count {
+        forces.sources.push(FieldSource {
+            kind: u32::from_le_bytes(reader.take()?),
+            position: [reader.f32()?, reader.f32()?, reader.f32()?],
+            strength: reader.f32()?,
+            radius: reader.f32()?,
+            ..Default::default()
+        });
+    }
+    forces.flow_strength = reader.f32()?;
+    let [flow] = reader.take()?;
+    forces.flow = match flow {
+        NO_FLOW => Flow::None,
+        NOISE_FLOW => Flow::Noise {
+            cells: u32::from_le_bytes(reader.take()?),
+            speed: reader.f32()?,
+        },
+        IMAGE_FLOW => Flow::Image(
+            (0..FLOW_SIZE * FLOW_SIZE)
+                .map(|_| reader.take())
+                .collect::<Result<_, _>>()?,
+        ),
+        _ => return Err(PresetError::BadForces),
+    };
+    Ok(forces)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], PresetError> {
+        if self.0.len() < N {
+            return Err(PresetError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn f32(&mut self) -> Result<f32, PresetError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+}
@@ -0,0 +1,137 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU8, Ordering},
+    Arc, Mutex,
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, CommandEncoderDescriptor, MapMode,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+};
+
+use crate::{objects::Particle, sim_shader_pipeline::GpuSimulation};
+
+// readback state, shared with the map_async callback
+const IDLE: u8 = 0;
+const MAPPING: u8 = 1;
+const MAPPED: u8 = 2;
+
+/// Copies of the GPU particle buffer, requested from the main world and filled in by the render world
+#[derive(Resource, Clone, Default)]
+pub struct ParticleSnapshot {
+    requested: Arc<AtomicBool>,
+    latest: Arc<Mutex<Option<Vec<Particle>>>>,
+}
+
+impl ParticleSnapshot {
+    /// Ask for a copy of the particle buffer, which arrives a frame or two later
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+
+    /// Answer a pending request straight away, for backends that keep the particles on the CPU
+    pub fn fulfil(&self, particles: impl FnOnce() -> Vec<Particle>) {
+        if self.requested.swap(false, Ordering::AcqRel) {
+            *self.latest.lock().unwrap() = Some(particles());
+        }
+    }
+
+    pub fn take(&self) -> Option<Vec<Particle>> {
+        self.latest.lock().unwrap().take()
+    }
+}
+
+#[derive(Resource, Default)]
+struct ReadbackBuffer {
+    /// sized to match the particle buffer when the first snapshot is taken
+    buffer: Option<Buffer>,
+    state: Arc<AtomicU8>,
+}
+
+pub struct Readback;
+impl Plugin for Readback {
+    fn build(&self, app: &mut App) {
+        let snapshot = ParticleSnapshot::default();
+
+        app.insert_resource(snapshot.clone());
+
+        app.sub_app_mut(RenderApp)
+            .insert_resource(snapshot)
+            .init_resource::<ReadbackBuffer>()
+            .add_systems(Render, read_particles.in_set(RenderSet::Prepare))
+            .add_systems(Render, copy_particles.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// Runs after the frame has been submitted, so the copy sees this frame's simulation
+fn copy_particles(
+    snapshot: Res<ParticleSnapshot>,
+    mut readback: ResMut<ReadbackBuffer>,
+    simulation: Res<GpuSimulation>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(particles_buffer) = simulation.particle_buffer() else {
+        return;
+    };
+
+    if readback.state.load(Ordering::Acquire) != IDLE
+        || !snapshot.requested.swap(false, Ordering::AcqRel)
+    {
+        return;
+    }
+
+    let buffer = readback.buffer.get_or_insert_with(|| {
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("particles readback buffer"),
+            size: particles_buffer.size(),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    });
+
+    let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("particles readback"),
+    });
+    encoder.copy_buffer_to_buffer(particles_buffer, 0, buffer, 0, buffer.size());
+    render_queue.submit([encoder.finish()]);
+
+    let buffer = buffer.clone();
+    readback.state.store(MAPPING, Ordering::Release);
+    let state = readback.state.clone();
+    buffer.slice(..).map_async(MapMode::Read, move |result| {
+        state.store(
+            if result.is_ok() { MAPPED } else { IDLE },
+            Ordering::Release,
+        );
+    });
+}
+
+fn read_particles(
+    snapshot: Res<ParticleSnapshot>,
+    readback: Res<ReadbackBuffer>,
+    simulation: Res<GpuSimulation>,
+    render_device: Res<RenderDevice>,
+) {
+    let Some(buffer) = &readback.buffer else {
+        return;
+    };
+
+    render_device.poll(wgpu::Maintain::Poll);
+    if readback.state.load(Ordering::Acquire) != MAPPED {
+        return;
+    }
+
+    {
+        let range = buffer.slice(..).get_mapped_range();
+        *snapshot.latest.lock().unwrap() = Some(simulation.decode(&range));
+    }
+
+    buffer.unmap();
+    readback.state.store(IDLE, Ordering::Release);
+}
@@ -8,22 +8,48 @@ use bevy::{
         Render, RenderApp, RenderSet,
     },
 };
-use bytemuck::bytes_of;
+use bytemuck::{bytes_of, cast_slice};
 
 use crate::{
-    objects::{ParticleColours, Particles, RenderImage, WeightsImage},
+    camera::{CameraUniform, OrbitCamera},
+    config::Config,
+    density::{DensityField, DensityUniform},
+    forces::ForceField,
+    lifecycle::Lifecycle,
+    objects::{
+        FlavourProperties, ForceParams, ParticleColours, Particles, RenderImage, SimulationSteps,
+        Weights,
+    },
+    obstacles::ObstacleField,
     render_shader_pipeline::{RenderShaderNode, RenderShaderPipeline},
-    sim_shader_pipeline::{SimulationShaderNode, SimulationShaderPipeline},
+    sim_shader_pipeline::{
+        prepare_simulation, update_simulation_pipelines, GpuSimulation, SimulationPipelineIds,
+        SimulationShaderNode,
+    },
+    WORKGROUP_SIZE,
 };
 
 #[derive(Resource, Debug)]
-pub struct ParticleBuffer {
+pub struct ParticleColourBuffer {
     pub buffer: Option<Buffer>,
 }
 
-#[derive(Resource, Debug)]
-pub struct ParticleColourBuffer {
-    pub buffer: Option<Buffer>,
+/// What the render shader needs to add up the particles in 2D, unused in 3D
+#[derive(Resource, Debug, Default)]
+pub struct AccumulationBuffers {
+    /// the light particles add to each pixel of the render image, red, green and blue, then
+    /// their total weight, in fixed point
+    pub accumulation: Option<Buffer>,
+    /// `DensityUniform`
+    pub density: Option<Buffer>,
+}
+
+/// What the render shader needs to draw a 3D world, unused in 2D
+#[derive(Resource, Debug, Default)]
+pub struct CameraBuffers {
+    pub camera: Option<Buffer>,
+    /// the nearest sphere in each pixel of the render image
+    pub depth: Option<Buffer>,
 }
 
 pub enum ComputeShaderState {
@@ -34,6 +60,12 @@ pub enum ComputeShaderState {
 
 pub struct RenderPlugin;
 
+/// Workgroups needed to cover `count` particles, one invocation each
+pub fn particle_workgroups(count: usize) -> u32 {
+    let workgroup_size = (WORKGROUP_SIZE.0 * WORKGROUP_SIZE.1 * WORKGROUP_SIZE.2) as usize;
+    ((count + workgroup_size - 1) / workgroup_size) as u32
+}
+
 const SIMULATION: &str = "simulation";
 const RENDER: &str = "render";
 
@@ -41,28 +73,45 @@ impl Plugin for RenderPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins((
             ExtractResourcePlugin::<RenderImage>::default(),
-            ExtractResourcePlugin::<WeightsImage>::default(),
             ExtractResourcePlugin::<Particles>::default(),
+            ExtractResourcePlugin::<Weights>::default(),
+            ExtractResourcePlugin::<ForceParams>::default(),
             ExtractResourcePlugin::<ParticleColours>::default(),
+            ExtractResourcePlugin::<FlavourProperties>::default(),
+            ExtractResourcePlugin::<Lifecycle>::default(),
+            ExtractResourcePlugin::<ObstacleField>::default(),
+            ExtractResourcePlugin::<ForceField>::default(),
+            ExtractResourcePlugin::<SimulationSteps>::default(),
+            ExtractResourcePlugin::<Config>::default(),
+            ExtractResourcePlugin::<OrbitCamera>::default(),
+            ExtractResourcePlugin::<DensityField>::default(),
         ));
 
         let render_app = app.sub_app_mut(RenderApp);
 
         render_app
+            .add_systems(
+                Render,
+                crate::render_shader_pipeline::queue_bind_group.in_set(RenderSet::Queue),
+            )
             .add_systems(
                 Render,
                 (
-                    crate::sim_shader_pipeline::queue_bind_group,
-                    crate::render_shader_pipeline::queue_bind_group,
+                    prepare_buffers,
+                    prepare_camera,
+                    prepare_accumulation,
+                    // an upload waiting for the pipelines is initialised before the step's
+                    // uniforms are written
+                    (update_simulation_pipelines, prepare_simulation).chain(),
                 )
-                    .in_set(RenderSet::Queue),
+                    .in_set(RenderSet::Prepare),
             )
-            .add_systems(Render, prepare_buffers.in_set(RenderSet::Prepare))
-            .insert_resource(ParticleBuffer { buffer: None })
-            .insert_resource(ParticleColourBuffer { buffer: None });
+            .insert_resource(ParticleColourBuffer { buffer: None })
+            .init_resource::<CameraBuffers>()
+            .init_resource::<AccumulationBuffers>();
 
         let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
-        render_graph.add_node(SIMULATION, SimulationShaderNode::default());
+        render_graph.add_node(SIMULATION, SimulationShaderNode);
         render_graph.add_node(RENDER, RenderShaderNode::default());
 
         render_graph.add_node_edge(SIMULATION, bevy::render::main_graph::node::CAMERA_DRIVER);
@@ -70,51 +119,124 @@ impl Plugin for RenderPlugin {
     }
 
     fn finish(&self, app: &mut App) {
+        // the pipelines are specialised for 2D or 3D, and the flavour tables sized, before the
+        // first extraction
+        let config = app.world.resource::<Config>().clone();
         let render_app = app.sub_app_mut(RenderApp);
+        let simulation = GpuSimulation::without_pipelines(
+            render_app.world.resource::<RenderDevice>(),
+            render_app.world.resource::<RenderQueue>(),
+            config.three_d,
+            config.flavour_count,
+        );
         render_app
-            .init_resource::<SimulationShaderPipeline>()
+            .insert_resource(simulation)
+            .insert_resource(config)
+            .init_resource::<SimulationPipelineIds>()
             .init_resource::<RenderShaderPipeline>();
     }
 }
 
 fn prepare_buffers(
-    particles: Res<Particles>,
+    config: Res<Config>,
     particle_colours: Res<ParticleColours>,
-    mut particles_buffer: ResMut<ParticleBuffer>,
     mut particle_colours_buffer: ResMut<ParticleColourBuffer>,
     render_queue: Res<RenderQueue>,
     render_device: Res<RenderDevice>,
 ) {
-    if particles_buffer.buffer.is_none() {
-        particles_buffer.buffer = Some(render_device.create_buffer(&BufferDescriptor {
-            label: Some("particles buffer"),
-            size: std::mem::size_of::<Particles>() as u64,
-            usage: BufferUsages::STORAGE
-                // | BufferUsages::MAP_READ
-                // | BufferUsages::MAP_WRITE
-                | BufferUsages::COPY_DST,
+    // sized by the flavours in use, which the menu can change
+    let size = (config.flavour_count * std::mem::size_of::<[f32; 4]>()) as u64;
+    if let Some(buffer) = &particle_colours_buffer.buffer {
+        if buffer.size() != size {
+            particle_colours_buffer.buffer = None;
+        }
+    }
+    let created = particle_colours_buffer.buffer.is_none();
+    let buffer = particle_colours_buffer.buffer.get_or_insert_with(|| {
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("particle colours buffer"),
+            size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    });
+
+    // the colours are only extracted when they're edited
+    if created || particle_colours.is_changed() {
+        let colours = &particle_colours.0[..config.flavour_count];
+        render_queue.write_buffer(buffer, 0, cast_slice(colours));
+    }
+}
+
+fn prepare_camera(
+    config: Res<Config>,
+    camera: Res<OrbitCamera>,
+    mut buffers: ResMut<CameraBuffers>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    if !config.three_d {
+        return;
+    }
+
+    let (width, height) = config.world_size;
+    if buffers.depth.is_none() {
+        buffers.depth = Some(render_device.create_buffer(&BufferDescriptor {
+            label: Some("depth buffer"),
+            size: width as u64 * height as u64 * 4,
+            usage: BufferUsages::STORAGE,
             mapped_at_creation: false,
         }));
+    }
+    let buffer = buffers.camera.get_or_insert_with(|| {
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("camera buffer"),
+            size: std::mem::size_of::<CameraUniform>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    });
 
+    if camera.is_changed() {
         render_queue.write_buffer(
-            &particles_buffer.buffer.as_ref().unwrap(),
+            buffer,
             0,
-            bytes_of(particles.as_ref()),
+            bytes_of(&camera.uniform(config.volume(), config.world_size)),
         );
     }
+}
 
-    if particle_colours_buffer.buffer.is_none() {
-        particle_colours_buffer.buffer = Some(render_device.create_buffer(&BufferDescriptor {
-            label: Some("spheres buffer"),
-            size: std::mem::size_of::<ParticleColours>() as u64,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+fn prepare_accumulation(
+    config: Res<Config>,
+    density: Res<DensityField>,
+    mut buffers: ResMut<AccumulationBuffers>,
+    render_queue: Res<RenderQueue>,
+    render_device: Res<RenderDevice>,
+) {
+    if config.three_d {
+        return;
+    }
+
+    let (width, height) = config.world_size;
+    if buffers.accumulation.is_none() {
+        buffers.accumulation = Some(render_device.create_buffer(&BufferDescriptor {
+            label: Some("accumulation buffer"),
+            size: width as u64 * height as u64 * 4 * 4,
+            usage: BufferUsages::STORAGE,
             mapped_at_creation: false,
         }));
     }
+    let created = buffers.density.is_none();
+    let buffer = buffers.density.get_or_insert_with(|| {
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("density buffer"),
+            size: std::mem::size_of::<DensityUniform>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    });
 
-    render_queue.write_buffer(
-        &particle_colours_buffer.buffer.as_ref().unwrap(),
-        0,
-        bytes_of(particle_colours.as_ref()),
-    );
+    if created || density.is_changed() {
+        render_queue.write_buffer(buffer, 0, bytes_of(&density.uniform()));
+    }
 }
@@ -10,16 +10,25 @@ use bevy::{
             BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
             BufferBindingType, BufferSize, CachedComputePipelineId, CachedPipelineState,
             ComputePassDescriptor, ComputePipelineDescriptor, PipelineCache, ShaderStages,
-            StorageTextureAccess, TextureFormat, TextureViewDimension,
+            StorageTextureAccess, TextureSampleType, TextureViewDimension,
         },
         renderer::{RenderContext, RenderDevice},
     },
 };
 
 use crate::{
-    objects::{ParticleColours, Particles, RenderImage},
-    render::{ComputeShaderState, ParticleBuffer, ParticleColourBuffer},
-    SIZE, WORKGROUP_SIZE,
+    camera::CameraUniform,
+    config::Config,
+    density::{DensityField, DensityUniform, RenderMode},
+    hdr::RENDER_FORMAT,
+    objects::RenderImage,
+    perf,
+    render::{
+        particle_workgroups, AccumulationBuffers, CameraBuffers, ComputeShaderState,
+        ParticleColourBuffer,
+    },
+    sim_shader_pipeline::{particle_size, GpuSimulation},
+    WORKGROUP_SIZE,
 };
 
 #[derive(Resource)]
@@ -29,133 +38,249 @@ pub struct RenderBindGroup(pub BindGroup);
 pub struct RenderShaderPipeline {
     texture_bind_group_layout: BindGroupLayout,
     init_pipeline: CachedComputePipelineId,
+    /// only in 3D, finding the nearest sphere in each pixel before `update` draws it
+    depth_pipeline: Option<CachedComputePipelineId>,
     update_pipeline: CachedComputePipelineId,
+    /// only in 2D, adding up density kernels instead of `update`'s dots
+    splat_pipeline: Option<CachedComputePipelineId>,
+    /// only in 2D, shading what the particles added up into the image
+    resolve_pipeline: Option<CachedComputePipelineId>,
+}
+
+impl RenderShaderPipeline {
+    /// Bindings of render.wgsl, compiled for 3D or not
+    pub fn bind_group_layout_entries(three_d: bool) -> Vec<BindGroupLayoutEntry> {
+        let mut entries = vec![
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: RENDER_FORMAT,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(particle_size(three_d) as u64),
+                },
+                count: None,
+            },
+            // BindGroupLayoutEntry {
+            //     binding: 1,
+            //     visibility: ShaderStages::COMPUTE,
+            //     ty: BindingType::Buffer {
+            //         ty: BufferBindingType::Uniform,
+            //         has_dynamic_offset: false,
+            //         min_binding_size: BufferSize::new(
+            //             std::mem::size_of::<Weights>() as u64
+            //         ),
+            //     },
+            //     count: None,
+            // },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(std::mem::size_of::<[f32; 4]>() as u64),
+                },
+                count: None,
+            },
+        ];
+        if three_d {
+            entries.extend([
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(
+                            std::mem::size_of::<CameraUniform>() as u64
+                        ),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(4),
+                    },
+                    count: None,
+                },
+            ]);
+        } else {
+            entries.extend([
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(4 * 4),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(
+                            std::mem::size_of::<DensityUniform>() as u64
+                        ),
+                    },
+                    count: None,
+                },
+            ]);
+        }
+        entries
+    }
 }
 
 impl FromWorld for RenderShaderPipeline {
     fn from_world(world: &mut World) -> Self {
+        let three_d = world.resource::<Config>().three_d;
+        let entries = Self::bind_group_layout_entries(three_d);
         let texture_bind_group_layout =
             world
                 .resource::<RenderDevice>()
                 .create_bind_group_layout(&BindGroupLayoutDescriptor {
                     label: Some("render bind group"),
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::StorageTexture {
-                                access: StorageTextureAccess::WriteOnly,
-                                format: TextureFormat::Rgba8Unorm,
-                                view_dimension: TextureViewDimension::D2,
-                            },
-                            count: None,
-                        },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: false,
-                                min_binding_size: BufferSize::new(
-                                    std::mem::size_of::<Particles>() as u64
-                                ),
-                            },
-                            count: None,
-                        },
-                        // BindGroupLayoutEntry {
-                        //     binding: 1,
-                        //     visibility: ShaderStages::COMPUTE,
-                        //     ty: BindingType::Buffer {
-                        //         ty: BufferBindingType::Uniform,
-                        //         has_dynamic_offset: false,
-                        //         min_binding_size: BufferSize::new(
-                        //             std::mem::size_of::<Weights>() as u64
-                        //         ),
-                        //     },
-                        //     count: None,
-                        // },
-                        BindGroupLayoutEntry {
-                            binding: 2,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Uniform,
-                                has_dynamic_offset: false,
-                                min_binding_size: BufferSize::new(
-                                    std::mem::size_of::<ParticleColours>() as u64,
-                                ),
-                            },
-                            count: None,
-                        },
-                    ],
+                    entries: &entries,
                 });
         let shader = world.resource::<AssetServer>().load("shaders/render.wgsl");
+        let shader_defs = if three_d {
+            vec!["THREE_D".into()]
+        } else {
+            vec![]
+        };
         let pipeline_cache = world.resource_mut::<PipelineCache>();
-        let init_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("render init pipeline")),
-            layout: vec![texture_bind_group_layout.clone()],
-            shader: shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("init"),
-            push_constant_ranges: vec![],
-        });
-        let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("render update pipeline")),
-            layout: vec![texture_bind_group_layout.clone()],
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("update"),
-            push_constant_ranges: vec![],
-        });
+        let pipeline = |label: &'static str, entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(Cow::from(label)),
+                layout: vec![texture_bind_group_layout.clone()],
+                shader: shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: Cow::from(entry_point),
+                push_constant_ranges: vec![],
+            })
+        };
+        let init_pipeline = pipeline("render init pipeline", "init");
+        let depth_pipeline = three_d.then(|| pipeline("render depth pipeline", "depth"));
+        let update_pipeline = pipeline("render update pipeline", "update");
+        let splat_pipeline = (!three_d).then(|| pipeline("render splat pipeline", "splat"));
+        let resolve_pipeline = (!three_d).then(|| pipeline("render resolve pipeline", "resolve"));
 
         RenderShaderPipeline {
             texture_bind_group_layout,
             init_pipeline,
+            depth_pipeline,
             update_pipeline,
+            splat_pipeline,
+            resolve_pipeline,
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn queue_bind_group(
     mut commands: Commands,
     pipeline: Res<RenderShaderPipeline>,
     gpu_images: Res<RenderAssets<Image>>,
     output_image: Res<RenderImage>,
     render_device: Res<RenderDevice>,
-    particles_buffer: Res<ParticleBuffer>,
+    simulation: Res<GpuSimulation>,
     // weights_buffer: Res<WeightsBuffer>,
     particle_colours_buffer: Res<ParticleColourBuffer>,
+    camera_buffers: Res<CameraBuffers>,
+    accumulation_buffers: Res<AccumulationBuffers>,
 ) {
     let output_view: &bevy::render::texture::GpuImage = &gpu_images[&output_image.image];
 
-    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-        label: Some("render bind group"),
-        layout: &pipeline.texture_bind_group_layout,
-        entries: &[
+    let mut entries = vec![
+        BindGroupEntry {
+            binding: 0,
+            resource: BindingResource::TextureView(&output_view.texture_view),
+        },
+        BindGroupEntry {
+            binding: 1,
+            resource: simulation.particle_buffer().unwrap().as_entire_binding(),
+        },
+        // BindGroupEntry {
+        //     binding: 1,
+        //     resource: weights_buffer.buffer.as_ref().unwrap().as_entire_binding(),
+        // },
+        BindGroupEntry {
+            binding: 2,
+            resource: particle_colours_buffer
+                .buffer
+                .as_ref()
+                .unwrap()
+                .as_entire_binding(),
+        },
+    ];
+    if let (Some(camera), Some(depth)) = (&camera_buffers.camera, &camera_buffers.depth) {
+        entries.extend([
             BindGroupEntry {
-                binding: 0,
-                resource: BindingResource::TextureView(&output_view.texture_view),
+                binding: 3,
+                resource: camera.as_entire_binding(),
             },
             BindGroupEntry {
-                binding: 1,
-                resource: particles_buffer
-                    .buffer
+                binding: 4,
+                resource: depth.as_entire_binding(),
+            },
+        ]);
+    } else {
+        entries.extend([
+            BindGroupEntry {
+                binding: 5,
+                resource: BindingResource::TextureView(simulation.obstacle_view()),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: accumulation_buffers
+                    .accumulation
                     .as_ref()
                     .unwrap()
                     .as_entire_binding(),
             },
-            // BindGroupEntry {
-            //     binding: 1,
-            //     resource: weights_buffer.buffer.as_ref().unwrap().as_entire_binding(),
-            // },
             BindGroupEntry {
-                binding: 2,
-                resource: particle_colours_buffer
-                    .buffer
+                binding: 7,
+                resource: accumulation_buffers
+                    .density
                     .as_ref()
                     .unwrap()
                     .as_entire_binding(),
             },
-        ],
+        ]);
+    }
+
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("render bind group"),
+        layout: &pipeline.texture_bind_group_layout,
+        entries: &entries,
     });
     commands.insert_resource(RenderBindGroup(bind_group));
 }
@@ -187,9 +312,19 @@ impl render_graph::Node for RenderShaderNode {
                 }
             }
             ComputeShaderState::Init => {
-                if let CachedPipelineState::Ok(_) =
-                    pipeline_cache.get_compute_pipeline_state(pipeline.update_pipeline)
-                {
+                let ready = |id| {
+                    matches!(
+                        pipeline_cache.get_compute_pipeline_state(id),
+                        CachedPipelineState::Ok(_)
+                    )
+                };
+                let pipelines = [
+                    Some(pipeline.update_pipeline),
+                    pipeline.depth_pipeline,
+                    pipeline.splat_pipeline,
+                    pipeline.resolve_pipeline,
+                ];
+                if pipelines.into_iter().flatten().all(ready) {
                     self.state = ComputeShaderState::Update;
                 }
             }
@@ -206,6 +341,15 @@ impl render_graph::Node for RenderShaderNode {
         let texture_bind_group = &world.resource::<RenderBindGroup>().0;
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<RenderShaderPipeline>();
+        let config = world.resource::<Config>();
+        let density = world.resource::<DensityField>();
+        let size = config.world_size;
+        let image_workgroups = (
+            (size.0 + WORKGROUP_SIZE.0 - 1) / WORKGROUP_SIZE.0,
+            (size.1 + WORKGROUP_SIZE.1 - 1) / WORKGROUP_SIZE.1,
+        );
+
+        perf::write_timestamp(world, render_context, perf::RENDER_START);
 
         let mut pass = render_context
             .command_encoder()
@@ -221,23 +365,46 @@ impl render_graph::Node for RenderShaderNode {
                     .get_compute_pipeline(pipeline.init_pipeline)
                     .unwrap();
                 pass.set_pipeline(init_pipeline);
-                pass.dispatch_workgroups(SIZE.0 / WORKGROUP_SIZE.0, SIZE.1 / WORKGROUP_SIZE.1, 1);
+                pass.dispatch_workgroups(image_workgroups.0, image_workgroups.1, 1);
             }
             ComputeShaderState::Update => {
                 let init_pipeline = pipeline_cache
                     .get_compute_pipeline(pipeline.init_pipeline)
                     .unwrap();
                 pass.set_pipeline(init_pipeline);
-                pass.dispatch_workgroups(SIZE.0 / WORKGROUP_SIZE.0, SIZE.1 / WORKGROUP_SIZE.1, 1);
+                pass.dispatch_workgroups(image_workgroups.0, image_workgroups.1, 1);
 
+                if let Some(depth_pipeline) = pipeline.depth_pipeline {
+                    let depth_pipeline =
+                        pipeline_cache.get_compute_pipeline(depth_pipeline).unwrap();
+                    pass.set_pipeline(depth_pipeline);
+                    pass.dispatch_workgroups(particle_workgroups(config.particle_count), 1, 1);
+                }
+
+                // the density field modes splat kernels instead of dots
+                let update_pipeline = match pipeline.splat_pipeline {
+                    Some(splat_pipeline) if density.mode != RenderMode::Dots => splat_pipeline,
+                    _ => pipeline.update_pipeline,
+                };
                 let update_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.update_pipeline)
+                    .get_compute_pipeline(update_pipeline)
                     .unwrap();
                 pass.set_pipeline(update_pipeline);
-                pass.dispatch_workgroups(1, 1, 1); // TODO adjust based on particle size
+                pass.dispatch_workgroups(particle_workgroups(config.particle_count), 1, 1);
+
+                if let Some(resolve_pipeline) = pipeline.resolve_pipeline {
+                    let resolve_pipeline = pipeline_cache
+                        .get_compute_pipeline(resolve_pipeline)
+                        .unwrap();
+                    pass.set_pipeline(resolve_pipeline);
+                    pass.dispatch_workgroups(image_workgroups.0, image_workgroups.1, 1);
+                }
             }
         }
 
+        drop(pass);
+        perf::write_timestamp(world, render_context, perf::RENDER_END);
+
         Ok(())
     }
 }
@@ -0,0 +1,499 @@
+//! Recording a session as the preset it started from plus a timeline of every edit to what's
+//! simulated or shown, and playing it back frame for frame. Both step the simulation by a fixed
+//! time per frame (see `Config::frame_time`), so a replay reproduces the run on the same backend,
+//! for reproducing bugs or rendering demos with `--video`.
+//!
+//! Edits are recorded by the frame they were made in, after everything in `Update`, and replayed
+//! before it, so the simulation sees them on the same frame either way. The walls the brush
+//! paints and the external forces are recorded apart from the rest of the rules, so painting
+//! doesn't store every weight each frame, and dragging the 3D view is recorded as the camera.
+
+use std::{fmt, path::PathBuf};
+
+use bevy::{app::AppExit, core::FrameCount, prelude::*};
+
+use crate::{
+    camera::OrbitCamera,
+    config::Config,
+    forces::ExternalForces,
+    lifecycle::Lifecycle,
+    objects::{FlavourProperties, ForceParams, ParticleColours, SimulationSettings, Weights},
+    obstacles::ObstacleMap,
+    preset::{
+        forces_from_bytes, forces_to_bytes, obstacles_from_bytes, obstacles_to_bytes, Preset,
+        PresetError,
+    },
+    AppState,
+};
+
+const MAGIC: &[u8; 4] = b"RPLR";
+/// Version 2 recorded walls and external forces as changes of their own. Version 1's rules
+/// changes included them, and are split up when loaded.
+pub const REPLAY_VERSION: u16 = 2;
+
+/// How each kind of `Change` is tagged
+const RULES: u8 = 0;
+const SETTINGS: u8 = 1;
+const LIFECYCLE: u8 = 2;
+const CAMERA: u8 = 3;
+const OBSTACLES: u8 = 4;
+const FORCES: u8 = 5;
+
+#[derive(Clone, Debug)]
+pub enum Change {
+    /// weights, colours, force parameters and flavour properties, in the preset format. Its walls
+    /// and forces are left out, and ignored if there are any.
+    Rules(Box<Preset>),
+    Settings(SimulationSettings),
+    Lifecycle(Box<Lifecycle>),
+    Camera(OrbitCamera),
+    /// the walls, at the world's size
+    Obstacles(ObstacleMap),
+    Forces(ExternalForces),
+}
+
+#[derive(Clone, Debug)]
+pub struct Replay {
+    /// the seed and counts the particles were created from, and the rules at the start
+    pub initial: Preset,
+    pub world_size: (u32, u32),
+    pub three_d: bool,
+    pub fps: u32,
+    /// frames the session ran for
+    pub frames: u32,
+    /// in frame order
+    pub timeline: Vec<(u32, Change)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    BadPreset(PresetError),
+    /// the tag of a change of unknown kind, naming flavours or emitters that can't exist, or a
+    /// lifecycle cut short
+    BadChange(u8),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::BadMagic => write!(f, "not a replay"),
+            ReplayError::UnsupportedVersion(version) => {
+                write!(f, "unsupported replay version {}", version)
+            }
+            ReplayError::Truncated => write!(f, "replay is truncated"),
+            ReplayError::BadPreset(err) => write!(f, "replay's rules are invalid: {}", err),
+            ReplayError::BadChange(tag) => write!(f, "replay has an invalid change {}", tag),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Replay {
+    /// Lifecycle rules are stored for the preset's flavours only, like the rest of the rules
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flavours = self.initial.flavour_count as usize;
+        let mut bytes = Vec::new();
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&REPLAY_VERSION.to_le_bytes());
+        write_preset(&mut bytes, &self.initial);
+        for value in [
+            self.world_size.0,
+            self.world_size.1,
+            self.three_d as u32,
+            self.fps,
+            self.frames,
+            self.timeline.len() as u32,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        for (frame, change) in &self.timeline {
+            bytes.extend_from_slice(&frame.to_le_bytes());
+            match change {
+                Change::Rules(preset) => {
+                    bytes.push(RULES);
+                    write_preset(&mut bytes, preset);
+                }
+                Change::Settings(settings) => {
+                    bytes.push(SETTINGS);
+                    bytes.extend_from_slice(&settings.substeps.to_le_bytes());
+                    bytes.extend_from_slice(&settings.time_scale.to_le_bytes());
+                    bytes.push(settings.fast_forward as u8);
+                    bytes.extend_from_slice(&settings.frame_budget_ms.to_le_bytes());
+                }
+                Change::Lifecycle(lifecycle) => {
+                    bytes.push(LIFECYCLE);
+                    bytes.extend_from_slice(&lifecycle.to_bytes(flavours));
+                }
+                Change::Camera(camera) => {
+                    bytes.push(CAMERA);
+                    for value in [camera.yaw, camera.pitch, camera.distance, camera.fov] {
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                Change::Obstacles(obstacles) => {
+                    bytes.push(OBSTACLES);
+                    write_section(&mut bytes, &obstacles_to_bytes(Some(obstacles)));
+                }
+                Change::Forces(forces) => {
+                    bytes.push(FORCES);
+                    write_section(&mut bytes, &forces_to_bytes(forces));
+                }
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ReplayError> {
+        let mut reader = Reader(bytes);
+
+        if reader.take::<4>()? != *MAGIC {
+            return Err(ReplayError::BadMagic);
+        }
+        let version = u16::from_le_bytes(reader.take()?);
+        if !(1..=REPLAY_VERSION).contains(&version) {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+
+        let initial = reader.preset()?;
+        let world_size = (reader.u32()?, reader.u32()?);
+        let three_d = reader.u32()? != 0;
+        let fps = reader.u32()?;
+        let frames = reader.u32()?;
+        let change_count = reader.u32()?;
+
+        let mut timeline = Vec::new();
+        for _ in 0..change_count {
+            let frame = reader.u32()?;
+            let [tag] = reader.take()?;
+            let change = match tag {
+                RULES if version == 1 => {
+                    let (width, height) = world_size;
+                    let mut rules = reader.preset()?;
+                    let obstacles = rules.obstacles.take();
+                    let forces = std::mem::take(&mut rules.forces);
+                    timeline.push((frame, Change::Rules(Box::new(rules))));
+                    timeline.push((
+                        frame,
+                        Change::Obstacles(obstacles.unwrap_or(ObstacleMap::new(width, height))),
+                    ));
+                    Change::Forces(forces)
+                }
+                RULES => Change::Rules(Box::new(reader.preset()?)),
+                SETTINGS => Change::Settings(SimulationSettings {
+                    substeps: reader.u32()?,
+                    time_scale: reader.f32()?,
+                    fast_forward: reader.take::<1>()? != [0],
+                    frame_budget_ms: reader.f32()?,
+                }),
+                LIFECYCLE => Change::Lifecycle(Box::new(
+                    Lifecycle::read(&mut reader.0, initial.flavour_count)
+                        .ok_or(ReplayError::BadChange(tag))?,
+                )),
+                CAMERA => Change::Camera(OrbitCamera {
+                    yaw: reader.f32()?,
+                    pitch: reader.f32()?,
+                    distance: reader.f32()?,
+                    fov: reader.f32()?,
+                }),
+                OBSTACLES if version >= 2 => {
+                    let (width, height) = world_size;
+                    let obstacles =
+                        obstacles_from_bytes(reader.section()?).map_err(ReplayError::BadPreset)?;
+                    Change::Obstacles(obstacles.unwrap_or(ObstacleMap::new(width, height)))
+                }
+                FORCES if version >= 2 => Change::Forces(
+                    forces_from_bytes(reader.section()?).map_err(ReplayError::BadPreset)?,
+                ),
+                _ => return Err(ReplayError::BadChange(tag)),
+            };
+            timeline.push((frame, change));
+        }
+
+        Ok(Self {
+            initial,
+            world_size,
+            three_d,
+            fps,
+            frames,
+            timeline,
+        })
+    }
+}
+
+fn write_preset(bytes: &mut Vec<u8>, preset: &Preset) {
+    write_section(bytes, &preset.to_bytes());
+}
+
+/// Length prefixed, since the preset format has no end markers
+fn write_section(bytes: &mut Vec<u8>, section: &[u8]) {
+    bytes.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(section);
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ReplayError> {
+        if self.0.len() < N {
+            return Err(ReplayError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, ReplayError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, ReplayError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn section(&mut self) -> Result<&[u8], ReplayError> {
+        let len = self.u32()? as usize;
+        if self.0.len() < len {
+            return Err(ReplayError::Truncated);
+        }
+        let (section, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(section)
+    }
+
+    fn preset(&mut self) -> Result<Preset, ReplayError> {
+        Preset::from_bytes(self.section()?).map_err(ReplayError::BadPreset)
+    }
+}
+
+/// Records with `--record` and plays back with `--replay`
+pub struct Replays;
+impl Plugin for Replays {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, start_recording)
+            .add_systems(PreUpdate, play)
+            .add_systems(PostUpdate, record)
+            .add_systems(Last, save_recording)
+            .add_systems(OnEnter(AppState::Done), finished);
+    }
+}
+
+/// The session so far, written to `path` when the app exits
+#[derive(Resource)]
+struct Recording {
+    replay: Replay,
+    path: PathBuf,
+}
+
+/// The rules as they are now, in the preset format
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn current_rules(
+    config: &Config,
+    weights: &Weights,
+    colours: &ParticleColours,
+    params: &ForceParams,
+    properties: &FlavourProperties,
+    obstacles: &ObstacleMap,
+    forces: &ExternalForces,
+) -> Preset {
+    Preset {
+        seed: config.seed,
+        particle_count: config.particle_count as u32,
+        flavour_count: config.flavour_count as u32,
+        weights: *weights,
+        colours: *colours,
+        params: *params,
+        properties: *properties,
+        obstacles: (!obstacles.is_empty()).then(|| obstacles.clone()),
+        forces: forces.clone(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_recording(
+    mut commands: Commands,
+    config: Res<Config>,
+    weights: Res<Weights>,
+    colours: Res<ParticleColours>,
+    params: Res<ForceParams>,
+    properties: Res<FlavourProperties>,
+    obstacles: Res<ObstacleMap>,
+    forces: Res<ExternalForces>,
+) {
+    let Some(path) = &config.record else {
+        return;
+    };
+    let initial = current_rules(
+        &config,
+        &weights,
+        &colours,
+        &params,
+        &properties,
+        &obstacles,
+        &forces,
+    );
+    commands.insert_resource(Recording {
+        replay: Replay {
+            initial,
+            world_size: config.world_size,
+            three_d: config.three_d,
+            fps: config.fps,
+            frames: 0,
+            timeline: Vec::new(),
+        },
+        path: path.clone(),
+    });
+}
+
+/// Every resource reads as changed on the first frame, so the timeline starts with all of them
+#[allow(clippy::too_many_arguments)]
+fn record(
+    recording: Option<ResMut<Recording>>,
+    config: Res<Config>,
+    frame: Res<FrameCount>,
+    rules: (
+        Res<Weights>,
+        Res<ParticleColours>,
+        Res<ForceParams>,
+        Res<FlavourProperties>,
+    ),
+    obstacles: Res<ObstacleMap>,
+    forces: Res<ExternalForces>,
+    settings: Res<SimulationSettings>,
+    lifecycle: Res<Lifecycle>,
+    camera: Res<OrbitCamera>,
+) {
+    let Some(mut recording) = recording else {
+        return;
+    };
+    let (weights, colours, params, properties) = rules;
+
+    let frame = frame.0;
+    if weights.is_changed()
+        || colours.is_changed()
+        || params.is_changed()
+        || properties.is_changed()
+    {
+        let rules = Preset {
+            obstacles: None,
+            forces: ExternalForces::default(),
+            ..current_rules(
+                &config,
+                &weights,
+                &colours,
+                &params,
+                &properties,
+                &obstacles,
+                &forces,
+            )
+        };
+        recording
+            .replay
+            .timeline
+            .push((frame, Change::Rules(Box::new(rules))));
+    }
+    if obstacles.is_changed() {
+        let change = Change::Obstacles(obstacles.clone());
+        recording.replay.timeline.push((frame, change));
+    }
+    if forces.is_changed() {
+        let change = Change::Forces(forces.clone());
+        recording.replay.timeline.push((frame, change));
+    }
+    if settings.is_changed() {
+        let change = Change::Settings(*settings);
+        recording.replay.timeline.push((frame, change));
+    }
+    if lifecycle.is_changed() {
+        let change = Change::Lifecycle(Box::new(lifecycle.clone()));
+        recording.replay.timeline.push((frame, change));
+    }
+    if camera.is_changed() {
+        recording
+            .replay
+            .timeline
+            .push((frame, Change::Camera(*camera)));
+    }
+    recording.replay.frames = frame + 1;
+}
+
+fn save_recording(exit: EventReader<AppExit>, recording: Option<Res<Recording>>) {
+    if exit.is_empty() {
+        return;
+    }
+    let Some(recording) = recording else {
+        return;
+    };
+    let path = &recording.path;
+    match std::fs::write(path, recording.replay.to_bytes()) {
+        Ok(()) => info!(
+            "recorded {} frames to {}",
+            recording.replay.frames,
+            path.display()
+        ),
+        Err(err) => error!("couldn't write {}: {}", path.display(), err),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn play(
+    config: Res<Config>,
+    frame: Res<FrameCount>,
+    mut next: Local<usize>,
+    mut next_state: ResMut<NextState<AppState>>,
+    mut rules: (
+        ResMut<Weights>,
+        ResMut<ParticleColours>,
+        ResMut<ForceParams>,
+        ResMut<FlavourProperties>,
+    ),
+    mut obstacles: ResMut<ObstacleMap>,
+    mut forces: ResMut<ExternalForces>,
+    mut settings: ResMut<SimulationSettings>,
+    mut lifecycle: ResMut<Lifecycle>,
+    mut camera: ResMut<OrbitCamera>,
+) {
+    let Some(replay) = &config.replay else {
+        return;
+    };
+
+    let frame = frame.0;
+    while let Some((_, change)) = replay.timeline.get(*next).filter(|(at, _)| *at <= frame) {
+        match change {
+            Change::Rules(preset) => {
+                let (weights, colours, params, properties) = &mut rules;
+                weights.set_if_neq(preset.weights);
+                colours.set_if_neq(preset.colours);
+                **params = preset.params;
+                **properties = preset.properties;
+            }
+            Change::Obstacles(replayed) => {
+                let (width, height) = config.world_size;
+                obstacles.set_if_neq(replayed.resized(width, height));
+            }
+            Change::Forces(replayed) => {
+                forces.set_if_neq(replayed.clone());
+            }
+            Change::Settings(replayed) => *settings = *replayed,
+            Change::Lifecycle(replayed) => *lifecycle = (**replayed).clone(),
+            Change::Camera(replayed) => *camera = *replayed,
+        }
+        *next += 1;
+    }
+
+    if frame + 1 == replay.frames {
+        next_state.set(AppState::Done);
+    }
+}
+
+fn finished(config: Res<Config>) {
+    if let Some(replay) = &config.replay {
+        info!("replayed {} frames", replay.frames);
+    }
+}
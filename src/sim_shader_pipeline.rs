@@ -1,253 +1,920 @@
 use bevy::{
     prelude::*,
     render::{
-        render_asset::RenderAssets,
         render_graph::{self},
         render_resource::{
             BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
-            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType,
-            BufferBindingType, BufferSize, CachedComputePipelineId, CachedPipelineState,
-            ComputePassDescriptor, ComputePipelineDescriptor, MapMode, PipelineCache, ShaderStages,
-            StorageTextureAccess, TextureFormat, TextureViewDimension,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+            BufferBindingType, BufferDescriptor, BufferSize, BufferUsages, CachedComputePipelineId,
+            CommandEncoder, CommandEncoderDescriptor, ComputePassDescriptor, ComputePipeline,
+            ComputePipelineDescriptor, Extent3d, ImageCopyTexture, ImageDataLayout, MapMode,
+            Origin3d, PipelineCache, PipelineLayoutDescriptor, RawComputePipelineDescriptor,
+            ShaderModuleDescriptor, ShaderSource, ShaderStages, Texture, TextureAspect,
+            TextureDescriptor, TextureDimension, TextureFormat, TextureSampleType, TextureUsages,
+            TextureView, TextureViewDescriptor, TextureViewDimension,
         },
-        renderer::{RenderContext, RenderDevice},
+        renderer::{RenderContext, RenderDevice, RenderQueue},
     },
 };
-use bytemuck::cast_slice;
-use std::{borrow::Cow, num::NonZeroU32};
-use wgpu::TextureSampleType;
+use bytemuck::{bytes_of, cast_slice, Pod, Zeroable};
+use std::borrow::Cow;
 
 use crate::{
-    objects::{Particle, Particles, WeightsImage},
-    render::{ComputeShaderState, ParticleBuffer},
+    backend::SimulationBackend,
+    config::Config,
+    forces::{ForceField, ForceFieldUniform, FLOW_SIZE},
+    lifecycle::{spawn_count, Lifecycle, LifecycleRule, SpawnBatch, Spawner, MAX_EMITTERS},
+    objects::{
+        FlavourProperties, ForceParams, PackedParticle, Particle, Particles, PhysicalProperties,
+        SimulationParams, SimulationSteps, Weights,
+    },
+    obstacles::ObstacleField,
+    perf,
+    render::particle_workgroups,
 };
 
-#[derive(Resource)]
-pub struct SimulationBindGroup(pub BindGroup);
+/// Compiled straight from the source by `GpuSimulation::new`, so the simulation can run outside
+/// the render graph, e.g. in tests. The app loads the asset through the pipeline cache instead, so
+/// edits to it are hot reloaded.
+const SHADER: &str = include_str!("../assets/shaders/simulation.wgsl");
 
-#[derive(Resource)]
-pub struct SimulationShaderPipeline {
-    texture_bind_group_layout: BindGroupLayout,
-    init_pipeline: CachedComputePipelineId,
-    update_pipeline: CachedComputePipelineId,
+/// The part of Bevy's shader preprocessing these shaders use, `#ifdef`, `#ifndef`, `#else` and
+/// `#endif`, for compiling them outside the pipeline cache. Removed lines are left blank so
+/// naga's errors point at the right line.
+pub fn preprocess(source: &str, shader_defs: &[&str]) -> String {
+    // whether the lines in each enclosing block are kept
+    let mut blocks: Vec<bool> = Vec::new();
+    let mut output = String::with_capacity(source.len());
+
+    for line in source.lines() {
+        let directive = line.trim();
+        if let Some(def) = directive.strip_prefix("#ifdef") {
+            blocks.push(shader_defs.contains(&def.trim()));
+        } else if let Some(def) = directive.strip_prefix("#ifndef") {
+            blocks.push(!shader_defs.contains(&def.trim()));
+        } else if directive.starts_with("#else") {
+            if let Some(keep) = blocks.last_mut() {
+                *keep = !*keep;
+            }
+        } else if directive.starts_with("#endif") {
+            blocks.pop();
+        } else if blocks.iter().all(|&keep| keep) {
+            output.push_str(line);
+        }
+        output.push('\n');
+    }
+
+    output
 }
 
-impl FromWorld for SimulationShaderPipeline {
-    fn from_world(world: &mut World) -> Self {
-        let texture_bind_group_layout =
-            world
-                .resource::<RenderDevice>()
-                .create_bind_group_layout(&BindGroupLayoutDescriptor {
-                    label: Some("sim bind group"),
-                    entries: &[
-                        BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Buffer {
-                                ty: BufferBindingType::Storage { read_only: false },
-                                has_dynamic_offset: false,
-                                min_binding_size: BufferSize::new(
-                                    std::mem::size_of::<Particles>() as u64
-                                ),
-                            },
-                            count: None,
-                        },
-                        // BindGroupLayoutEntry {
-                        //     binding: 1,
-                        //     visibility: ShaderStages::COMPUTE,
-                        //     ty: BindingType::StorageTexture {
-                        //         access: StorageTextureAccess::ReadWrite,
-                        //         format: TextureFormat::Rgba8Unorm,
-                        //         view_dimension: TextureViewDimension::D2,
-                        //     },
-                        //     count: None,
-                        // },
-                        BindGroupLayoutEntry {
-                            binding: 1,
-                            visibility: ShaderStages::COMPUTE,
-                            ty: BindingType::Texture {
-                                sample_type: TextureSampleType::Float { filterable: false },
-                                view_dimension: TextureViewDimension::D2,
-                                multisampled: false,
-                            },
-                            count: NonZeroU32::new(1),
-                        },
-                        // BindGroupLayoutEntry {
-                        //     binding: 2,
-                        //     visibility: ShaderStages::COMPUTE,
-                        //     ty: BindingType::Buffer {
-                        //         ty: BufferBindingType::Uniform,
-                        //         has_dynamic_offset: false,
-                        //         min_binding_size: BufferSize::new(
-                        //             std::mem::size_of::<ParticleColours>() as u64,
-                        //         ),
-                        //     },
-                        //     count: None,
-                        // },
-                    ],
-                });
-        let shader = world
-            .resource::<AssetServer>()
-            .load("shaders/simulation.wgsl");
-        let pipeline_cache = world.resource_mut::<PipelineCache>();
-        let init_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("sim init pipeline")),
-            layout: vec![texture_bind_group_layout.clone()],
-            shader: shader.clone(),
-            shader_defs: vec![],
-            entry_point: Cow::from("init"),
-            push_constant_ranges: vec![],
+/// Bytes of one particle in the particle buffer, which holds `PackedParticle`s in 2D
+pub fn particle_size(three_d: bool) -> usize {
+    if three_d {
+        std::mem::size_of::<Particle>()
+    } else {
+        std::mem::size_of::<PackedParticle>()
+    }
+}
+
+/// `struct FreeList` in simulation.wgsl before its slots
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct FreeListHeader {
+    /// signed since the spawn pass's pops can take it below 0 for a moment
+    count: i32,
+}
+
+const FREE_LIST_HEADER: usize = std::mem::size_of::<FreeListHeader>();
+
+/// simulation.wgsl's entry points, compiled for 3D or not
+#[derive(Clone)]
+pub struct SimulationPipelines {
+    pub init: ComputePipeline,
+    pub update: ComputePipeline,
+    pub integrate: ComputePipeline,
+    pub spawn: ComputePipeline,
+}
+
+impl SimulationPipelines {
+    /// From the source built into the binary, without the pipeline cache
+    pub fn compile(device: &RenderDevice, layout: &BindGroupLayout, three_d: bool) -> Self {
+        let shader_defs: &[&str] = if three_d { &["THREE_D"] } else { &[] };
+        let module = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("simulation shader"),
+            source: ShaderSource::Wgsl(Cow::Owned(preprocess(SHADER, shader_defs))),
         });
-        let update_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("sim update pipeline")),
-            layout: vec![texture_bind_group_layout.clone()],
-            shader,
-            shader_defs: vec![],
-            entry_point: Cow::from("update"),
-            push_constant_ranges: vec![],
+        let layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("sim pipeline layout"),
+            bind_group_layouts: &[layout],
+            push_constant_ranges: &[],
         });
+        let pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&RawComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                module: &module,
+                entry_point,
+            })
+        };
 
-        SimulationShaderPipeline {
-            texture_bind_group_layout,
-            init_pipeline,
-            update_pipeline,
+        Self {
+            init: pipeline("sim init pipeline", "init"),
+            update: pipeline("sim update pipeline", "update"),
+            integrate: pipeline("sim integrate pipeline", "integrate"),
+            spawn: pipeline("sim spawn pipeline", "spawn"),
         }
     }
+
+    fn all(&self) -> [&ComputePipeline; 4] {
+        [&self.init, &self.update, &self.integrate, &self.spawn]
+    }
 }
 
-pub fn queue_bind_group(
-    mut commands: Commands,
-    pipeline: Res<SimulationShaderPipeline>,
-    render_device: Res<RenderDevice>,
-    particles_buffer: Res<ParticleBuffer>,
-    weights_image: Res<WeightsImage>,
-    gpu_images: Res<RenderAssets<Image>>,
-    // particle_colours_buffer: Res<ParticleColourBuffer>,
-) {
-    let weights_view: &bevy::render::texture::GpuImage = &gpu_images[&weights_image.image];
+/// The WGSL compute implementation. In the app it lives in the render world, where
+/// `prepare_simulation` keeps it in sync and `SimulationShaderNode` records the steps.
+///
+/// Particles are stored as `Particle`s in 3D and `PackedParticle`s in 2D, so the shader is
+/// compiled for one or the other. Alongside them is a free list of the dead particles' indices,
+/// which integrate pushes to and the spawn pass pops from, so emitters can reuse the slots
+/// without the buffer ever being compacted. In 2D the walls' `ObstacleField` is bound too, as a
+/// texture. The `ForceField` is only written when it changes, like the walls.
+///
+/// The weights, properties and lifecycle rules only hold the first `flavours` rows of their
+/// `MAX_FLAVOURS`, so their buffers are sized by the flavours in use, and replaced by
+/// `set_flavours` when that changes.
+///
+/// Nothing is dispatched until it has pipelines. An upload before then is initialised once they
+/// arrive.
+#[derive(Resource)]
+pub struct GpuSimulation {
+    three_d: bool,
+    flavours: usize,
+    device: RenderDevice,
+    queue: RenderQueue,
+    bind_group_layout: BindGroupLayout,
+    pipelines: Option<SimulationPipelines>,
+    /// whether the last upload still has to be run through the init pass
+    init_pending: bool,
+    weights_buffer: Buffer,
+    params_buffer: Buffer,
+    properties_buffer: Buffer,
+    rules_buffer: Buffer,
+    spawns_buffer: Buffer,
+    forces_buffer: Buffer,
+    flow_buffer: Buffer,
+    particle_buffer: Option<Buffer>,
+    free_list_buffer: Option<Buffer>,
+    obstacle_texture: Texture,
+    obstacle_view: TextureView,
+    bind_group: Option<BindGroup>,
+    particle_count: usize,
+    world_size: [f32; 3],
+    spawner: Spawner,
+    /// spawns in the last uniforms written
+    spawn_count: u32,
+}
+
+impl GpuSimulation {
+    /// Compiled from the source built into the binary, ready to run
+    pub fn new(device: &RenderDevice, queue: &RenderQueue, three_d: bool, flavours: usize) -> Self {
+        let mut simulation = Self::without_pipelines(device, queue, three_d, flavours);
+        let pipelines =
+            SimulationPipelines::compile(device, &simulation.bind_group_layout, three_d);
+        simulation.set_pipelines(pipelines);
+        simulation
+    }
+
+    /// Waiting for `set_pipelines`, which the app calls once the pipeline cache has them
+    pub fn without_pipelines(
+        device: &RenderDevice,
+        queue: &RenderQueue,
+        three_d: bool,
+        flavours: usize,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("sim bind group"),
+            entries: &Self::bind_group_layout_entries(three_d),
+        });
+
+        let (obstacle_texture, obstacle_view) =
+            Self::create_obstacle_texture(device, &ObstacleField::default());
+        let (weights_buffer, properties_buffer, rules_buffer) =
+            Self::create_flavour_buffers(device, flavours);
+        let mut simulation = Self {
+            weights_buffer,
+            params_buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("simulation params buffer"),
+                size: std::mem::size_of::<SimulationParams>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            properties_buffer,
+            rules_buffer,
+            spawns_buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("spawn batches buffer"),
+                size: std::mem::size_of::<[SpawnBatch; MAX_EMITTERS]>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            forces_buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("force field buffer"),
+                size: std::mem::size_of::<ForceFieldUniform>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            flow_buffer: device.create_buffer(&BufferDescriptor {
+                label: Some("flow field buffer"),
+                size: (FLOW_SIZE * FLOW_SIZE * std::mem::size_of::<[f32; 2]>()) as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            three_d,
+            flavours,
+            device: device.clone(),
+            queue: queue.clone(),
+            bind_group_layout,
+            pipelines: None,
+            init_pending: false,
+            particle_buffer: None,
+            free_list_buffer: None,
+            obstacle_texture,
+            obstacle_view,
+            bind_group: None,
+            particle_count: 0,
+            world_size: [0.; 3],
+            spawner: Spawner::default(),
+            spawn_count: 0,
+        };
+        simulation.set_obstacles(&ObstacleField::default());
+        simulation.set_forces(&ForceField::default());
+        simulation
+    }
+
+    /// The weights, properties and lifecycle rules buffers for `flavours` flavours
+    fn create_flavour_buffers(device: &RenderDevice, flavours: usize) -> (Buffer, Buffer, Buffer) {
+        let buffer = |label, size| {
+            device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: size as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        (
+            buffer(
+                "weights buffer",
+                flavours * flavours * std::mem::size_of::<f32>(),
+            ),
+            buffer(
+                "flavour properties buffer",
+                flavours * std::mem::size_of::<PhysicalProperties>(),
+            ),
+            buffer(
+                "lifecycle rules buffer",
+                flavours * std::mem::size_of::<LifecycleRule>(),
+            ),
+        )
+    }
+
+    /// Resizes the flavour tables for `flavours` flavours. The next `write_uniforms` fills them.
+    pub fn set_flavours(&mut self, flavours: usize) {
+        if flavours == self.flavours {
+            return;
+        }
+        (
+            self.weights_buffer,
+            self.properties_buffer,
+            self.rules_buffer,
+        ) = Self::create_flavour_buffers(&self.device, flavours);
+        self.flavours = flavours;
+        if let (Some(particle_buffer), Some(free_list_buffer)) =
+            (&self.particle_buffer, &self.free_list_buffer)
+        {
+            self.bind_group = Some(self.create_bind_group(particle_buffer, free_list_buffer));
+        }
+    }
+
+    fn create_obstacle_texture(
+        device: &RenderDevice,
+        field: &ObstacleField,
+    ) -> (Texture, TextureView) {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("obstacle texture"),
+            size: Extent3d {
+                width: field.width,
+                height: field.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        (texture, view)
+    }
+
+    /// Bindings of simulation.wgsl, compiled for 3D or not
+    pub fn bind_group_layout_entries(three_d: bool) -> Vec<BindGroupLayoutEntry> {
+        let mut entries = vec![
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(particle_size(three_d) as u64),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(std::mem::size_of::<f32>() as u64),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        std::mem::size_of::<SimulationParams>() as u64
+                    ),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        std::mem::size_of::<PhysicalProperties>() as u64
+                    ),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(std::mem::size_of::<LifecycleRule>() as u64),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        std::mem::size_of::<[SpawnBatch; MAX_EMITTERS]>() as u64,
+                    ),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 6,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        (FREE_LIST_HEADER + std::mem::size_of::<u32>()) as u64,
+                    ),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 8,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(
+                        std::mem::size_of::<ForceFieldUniform>() as u64
+                    ),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 9,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(std::mem::size_of::<[f32; 2]>() as u64),
+                },
+                count: None,
+            },
+        ];
+        if !three_d {
+            entries.push(BindGroupLayoutEntry {
+                binding: 7,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: false },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            });
+        }
+        entries
+    }
+
+    /// What the pipelines are laid out for
+    pub fn bind_group_layout(&self) -> &BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    /// Replaces the pipelines, e.g. with a hot reloaded shader's, and initialises an upload that
+    /// was waiting for them
+    pub fn set_pipelines(&mut self, pipelines: SimulationPipelines) {
+        self.pipelines = Some(pipelines);
+        self.dispatch_init();
+    }
+
+    /// Runs the init pass over the last upload if it hasn't been, and there are pipelines to
+    fn dispatch_init(&mut self) {
+        let (true, Some(pipelines), Some(bind_group)) =
+            (self.init_pending, &self.pipelines, &self.bind_group)
+        else {
+            return;
+        };
 
-    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-        label: Some("sim bind group"),
-        layout: &pipeline.texture_bind_group_layout,
-        entries: &[
+        // the init pass wraps the new positions into the world, so only needs its size
+        let params = SimulationParams::new(0., self.world_size, &ForceParams::default());
+        self.queue
+            .write_buffer(&self.params_buffer, 0, bytes_of(&params));
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("simulation upload"),
+            });
+        {
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("simulation init"),
+            });
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.set_pipeline(&pipelines.init);
+            pass.dispatch_workgroups(particle_workgroups(self.particle_count), 1, 1);
+        }
+        self.queue.submit([encoder.finish()]);
+        self.init_pending = false;
+    }
+
+    /// The walls' signed distance field, which the render pass draws them from in 2D
+    pub fn obstacle_view(&self) -> &TextureView {
+        &self.obstacle_view
+    }
+
+    fn create_bind_group(&self, particle_buffer: &Buffer, free_list_buffer: &Buffer) -> BindGroup {
+        let mut entries = vec![
             BindGroupEntry {
                 binding: 0,
-                resource: particles_buffer
-                    .buffer
-                    .as_ref()
-                    .unwrap()
-                    .as_entire_binding(),
+                resource: particle_buffer.as_entire_binding(),
             },
             BindGroupEntry {
                 binding: 1,
-                resource: BindingResource::TextureView(&weights_view.texture_view),
-            },
-            // BindGroupEntry {
-            //     binding: 2,
-            //     resource: particle_colours_buffer
-            //         .buffer
-            //         .as_ref()
-            //         .unwrap()
-            //         .as_entire_binding(),
-            // },
-        ],
-    });
-    commands.insert_resource(SimulationBindGroup(bind_group));
-}
+                resource: self.weights_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: self.params_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: self.properties_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: self.rules_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: self.spawns_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: free_list_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: self.forces_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 9,
+                resource: self.flow_buffer.as_entire_binding(),
+            },
+        ];
+        if !self.three_d {
+            entries.push(BindGroupEntry {
+                binding: 7,
+                resource: BindingResource::TextureView(&self.obstacle_view),
+            });
+        }
+        self.device.create_bind_group(&BindGroupDescriptor {
+            label: Some("sim bind group"),
+            layout: &self.bind_group_layout,
+            entries: &entries,
+        })
+    }
 
-pub struct SimulationShaderNode {
-    pub state: ComputeShaderState,
-}
+    /// The particles, bound by the render pipeline and copied by readback
+    pub fn particle_buffer(&self) -> Option<&Buffer> {
+        self.particle_buffer.as_ref()
+    }
 
-impl Default for SimulationShaderNode {
-    fn default() -> Self {
-        Self {
-            state: ComputeShaderState::Loading,
+    /// Bytes of one particle in the particle buffer
+    pub fn particle_size(&self) -> usize {
+        particle_size(self.three_d)
+    }
+
+    /// `particles` in the layout of the particle buffer
+    pub fn encode(&self, particles: &[Particle]) -> Vec<u8> {
+        if self.three_d {
+            cast_slice(particles).to_vec()
+        } else {
+            let packed: Vec<_> = particles.iter().map(PackedParticle::from).collect();
+            cast_slice(&packed).to_vec()
+        }
+    }
+
+    /// Particles from a copy of the particle buffer
+    pub fn decode(&self, bytes: &[u8]) -> Vec<Particle> {
+        if self.three_d {
+            cast_slice(bytes).to_vec()
+        } else {
+            cast_slice::<_, PackedParticle>(bytes)
+                .iter()
+                .map(Particle::from)
+                .collect()
+        }
+    }
+
+    /// Queues writes of everything the next `steps` steps of `dt` read besides the particles,
+    /// including the spawns due after them
+    pub fn write_uniforms(
+        &mut self,
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        lifecycle: &Lifecycle,
+        dt: f32,
+        steps: u32,
+    ) {
+        let (batches, seed) = self.spawner.advance(&lifecycle.emitters, dt * steps as f32);
+        self.spawn_count = spawn_count(&batches);
+
+        let flavours = self.flavours;
+        let weights: Vec<f32> = weights.0[..flavours]
+            .iter()
+            .flat_map(|row| &row[..flavours])
+            .copied()
+            .collect();
+        self.queue
+            .write_buffer(&self.weights_buffer, 0, cast_slice(&weights));
+        self.queue.write_buffer(
+            &self.properties_buffer,
+            0,
+            cast_slice(&properties.0[..flavours]),
+        );
+        self.queue.write_buffer(
+            &self.rules_buffer,
+            0,
+            cast_slice(&lifecycle.rules[..flavours]),
+        );
+        self.queue
+            .write_buffer(&self.spawns_buffer, 0, bytes_of(&batches));
+        let params = SimulationParams {
+            seed,
+            spawn_count: self.spawn_count,
+            flavour_count: flavours as u32,
+            ..SimulationParams::new(dt, self.world_size, params)
+        };
+        self.queue
+            .write_buffer(&self.params_buffer, 0, bytes_of(&params));
+    }
+
+    /// Records `steps` steps and then the spawns into `encoder`, using the last uniforms written
+    pub fn encode_steps(&self, encoder: &mut CommandEncoder, steps: u32) {
+        let (Some(bind_group), Some(pipelines)) = (&self.bind_group, &self.pipelines) else {
+            return;
+        };
+        let workgroups = particle_workgroups(self.particle_count);
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("simulation"),
+        });
+        pass.set_bind_group(0, bind_group, &[]);
+
+        // dispatches within a pass are ordered, so each step sees the previous one's writes
+        for _ in 0..steps {
+            pass.set_pipeline(&pipelines.update);
+            pass.dispatch_workgroups(workgroups, 1, 1);
+            pass.set_pipeline(&pipelines.integrate);
+            pass.dispatch_workgroups(workgroups, 1, 1);
+        }
+
+        if self.spawn_count > 0 {
+            pass.set_pipeline(&pipelines.spawn);
+            pass.dispatch_workgroups(particle_workgroups(self.spawn_count as usize), 1, 1);
         }
     }
 }
 
-impl render_graph::Node for SimulationShaderNode {
-    fn update(&mut self, world: &mut World) {
-        let pipeline = world.resource::<SimulationShaderPipeline>();
-        let pipeline_cache = world.resource::<PipelineCache>();
-        // let particle_buffer = world.resource::<ParticleBuffer>();
-
-        // if the corresponding pipeline has loaded, transition to the next stage
-        match self.state {
-            ComputeShaderState::Loading => {
-                if let CachedPipelineState::Ok(_) =
-                    pipeline_cache.get_compute_pipeline_state(pipeline.init_pipeline)
-                {
-                    self.state = ComputeShaderState::Init;
-                }
+impl SimulationBackend for GpuSimulation {
+    fn init(&mut self, particle_count: usize, world_size: [f32; 3]) {
+        self.world_size = world_size;
+        if self.particle_count == particle_count && self.particle_buffer.is_some() {
+            return;
+        }
+
+        let particle_buffer = self.device.create_buffer(&BufferDescriptor {
+            label: Some("particles buffer"),
+            size: (particle_count * self.particle_size()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let free_list_buffer = self.device.create_buffer(&BufferDescriptor {
+            label: Some("free list buffer"),
+            size: (FREE_LIST_HEADER + particle_count * std::mem::size_of::<u32>()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.bind_group = Some(self.create_bind_group(&particle_buffer, &free_list_buffer));
+        self.particle_buffer = Some(particle_buffer);
+        self.free_list_buffer = Some(free_list_buffer);
+        self.particle_count = particle_count;
+    }
+
+    fn upload(&mut self, particles: &[Particle]) {
+        let (Some(buffer), Some(free_list_buffer)) =
+            (&self.particle_buffer, &self.free_list_buffer)
+        else {
+            return;
+        };
+        self.queue.write_buffer(buffer, 0, &self.encode(particles));
+
+        let free_slots: Vec<u32> = (0..particles.len() as u32)
+            .filter(|&i| !particles[i as usize].is_alive())
+            .collect();
+        self.queue.write_buffer(
+            free_list_buffer,
+            0,
+            bytes_of(&FreeListHeader {
+                count: free_slots.len() as i32,
+            }),
+        );
+        if !free_slots.is_empty() {
+            self.queue.write_buffer(
+                free_list_buffer,
+                FREE_LIST_HEADER as u64,
+                cast_slice(&free_slots),
+            );
+        }
+
+        self.init_pending = true;
+        self.dispatch_init();
+    }
+
+    fn step(
+        &mut self,
+        weights: &Weights,
+        params: &ForceParams,
+        properties: &FlavourProperties,
+        lifecycle: &Lifecycle,
+        dt: f32,
+        steps: u32,
+    ) {
+        self.write_uniforms(weights, params, properties, lifecycle, dt, steps);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("simulation step"),
+            });
+        self.encode_steps(&mut encoder, steps);
+        self.queue.submit([encoder.finish()]);
+    }
+
+    fn set_obstacles(&mut self, obstacles: &ObstacleField) {
+        let size = self.obstacle_texture.size();
+        if (size.width, size.height) != (obstacles.width, obstacles.height) {
+            (self.obstacle_texture, self.obstacle_view) =
+                Self::create_obstacle_texture(&self.device, obstacles);
+            if let (Some(particle_buffer), Some(free_list_buffer)) =
+                (&self.particle_buffer, &self.free_list_buffer)
+            {
+                self.bind_group = Some(self.create_bind_group(particle_buffer, free_list_buffer));
             }
-            ComputeShaderState::Init => {
-                if let CachedPipelineState::Ok(_) =
-                    pipeline_cache.get_compute_pipeline_state(pipeline.update_pipeline)
-                {
-                    self.state = ComputeShaderState::Update;
-                }
+        }
+
+        self.queue.write_texture(
+            ImageCopyTexture {
+                texture: &self.obstacle_texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            cast_slice(&obstacles.distance),
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(obstacles.width * 4),
+                rows_per_image: None,
+            },
+            self.obstacle_texture.size(),
+        );
+    }
+
+    fn set_forces(&mut self, forces: &ForceField) {
+        self.queue
+            .write_buffer(&self.forces_buffer, 0, bytes_of(&forces.uniform));
+        self.queue
+            .write_buffer(&self.flow_buffer, 0, cast_slice(&forces.flow));
+    }
+
+    /// Blocks on the GPU, so the app uses the asynchronous `readback` module instead
+    fn readback(&mut self) -> Vec<Particle> {
+        let Some(particle_buffer) = &self.particle_buffer else {
+            return Vec::new();
+        };
+
+        let staging = self.device.create_buffer(&BufferDescriptor {
+            label: Some("particles staging buffer"),
+            size: particle_buffer.size(),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("simulation readback"),
+            });
+        encoder.copy_buffer_to_buffer(particle_buffer, 0, &staging, 0, staging.size());
+        self.queue.submit([encoder.finish()]);
+
+        let slice = staging.slice(..);
+        slice.map_async(MapMode::Read, |result| {
+            if let Err(err) = result {
+                error!("couldn't map particles staging buffer: {}", err);
             }
-            ComputeShaderState::Update => {}
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+
+        let particles = self.decode(&slice.get_mapped_range());
+        staging.unmap();
+        particles
+    }
+}
+
+/// The simulation's pipelines in the render world's pipeline cache, queued from the shader asset
+#[derive(Resource)]
+pub struct SimulationPipelineIds {
+    init: CachedComputePipelineId,
+    update: CachedComputePipelineId,
+    integrate: CachedComputePipelineId,
+    spawn: CachedComputePipelineId,
+}
+
+impl FromWorld for SimulationPipelineIds {
+    fn from_world(world: &mut World) -> Self {
+        let three_d = world.resource::<Config>().three_d;
+        let layout = world
+            .resource::<GpuSimulation>()
+            .bind_group_layout()
+            .clone();
+        let shader = world
+            .resource::<AssetServer>()
+            .load("shaders/simulation.wgsl");
+        let shader_defs = if three_d {
+            vec!["THREE_D".into()]
+        } else {
+            vec![]
+        };
+        let pipeline_cache = world.resource_mut::<PipelineCache>();
+        let pipeline = |label: &'static str, entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(Cow::from(label)),
+                layout: vec![layout.clone()],
+                shader: shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: Cow::from(entry_point),
+                push_constant_ranges: vec![],
+            })
+        };
+
+        Self {
+            init: pipeline("sim init pipeline", "init"),
+            update: pipeline("sim update pipeline", "update"),
+            integrate: pipeline("sim integrate pipeline", "integrate"),
+            spawn: pipeline("sim spawn pipeline", "spawn"),
         }
     }
+}
+
+/// Hands the simulation the cache's pipelines once they're all compiled, and again whenever the
+/// shader is hot reloaded
+pub fn update_simulation_pipelines(
+    ids: Res<SimulationPipelineIds>,
+    pipeline_cache: Res<PipelineCache>,
+    mut simulation: ResMut<GpuSimulation>,
+) {
+    let pipeline = |id| pipeline_cache.get_compute_pipeline(id).cloned();
+    let (Some(init), Some(update), Some(integrate), Some(spawn)) = (
+        pipeline(ids.init),
+        pipeline(ids.update),
+        pipeline(ids.integrate),
+        pipeline(ids.spawn),
+    ) else {
+        return;
+    };
+    let pipelines = SimulationPipelines {
+        init,
+        update,
+        integrate,
+        spawn,
+    };
+
+    let same = |current: &SimulationPipelines| {
+        current
+            .all()
+            .iter()
+            .zip(pipelines.all())
+            .all(|(a, b)| a.id() == b.id())
+    };
+    if !simulation.pipelines.as_ref().is_some_and(same) {
+        simulation.set_pipelines(pipelines);
+    }
+}
+
+/// Keeps the render world's simulation in sync with the extracted resources, which only change
+/// when the main world changes them
+#[allow(clippy::too_many_arguments)]
+pub fn prepare_simulation(
+    mut simulation: ResMut<GpuSimulation>,
+    particles: Res<Particles>,
+    weights: Res<Weights>,
+    params: Res<ForceParams>,
+    properties: Res<FlavourProperties>,
+    lifecycle: Res<Lifecycle>,
+    obstacles: Res<ObstacleField>,
+    forces: Res<ForceField>,
+    steps: Res<SimulationSteps>,
+    config: Res<Config>,
+) {
+    simulation.set_flavours(config.flavour_count);
+    if particles.is_changed() {
+        simulation.init(particles.0.len(), config.volume());
+        simulation.upload(&particles.0);
+    }
+    if obstacles.is_changed() {
+        simulation.set_obstacles(&obstacles);
+    }
+    if forces.is_changed() {
+        simulation.set_forces(&forces);
+    }
+
+    simulation.write_uniforms(
+        &weights,
+        &params,
+        &properties,
+        &lifecycle,
+        steps.dt,
+        steps.count,
+    );
+}
 
+pub struct SimulationShaderNode;
+
+impl render_graph::Node for SimulationShaderNode {
     fn run(
         &self,
         _graph: &mut render_graph::RenderGraphContext,
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
-        let texture_bind_group = &world.resource::<SimulationBindGroup>().0;
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<SimulationShaderPipeline>();
-        // let particle_buffer = world.resource::<ParticleBuffer>();
-
-        let mut pass = render_context
-            .command_encoder()
-            .begin_compute_pass(&ComputePassDescriptor::default());
-
-        pass.set_bind_group(0, texture_bind_group, &[]);
-
-        // select the pipeline based on the current state
-        match self.state {
-            ComputeShaderState::Loading => {}
-            ComputeShaderState::Init => {
-                let init_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.init_pipeline)
-                    .unwrap();
-                pass.set_pipeline(init_pipeline);
-                pass.dispatch_workgroups(1, 1, 1); // TODO: workgroup size
-            }
-            ComputeShaderState::Update => {
-                let update_pipeline = pipeline_cache
-                    .get_compute_pipeline(pipeline.update_pipeline)
-                    .unwrap();
-                pass.set_pipeline(update_pipeline);
-                pass.dispatch_workgroups(1, 1, 1); // TODO: workgroup size
-            }
-        }
+        let simulation = world.resource::<GpuSimulation>();
+        let steps = world.resource::<SimulationSteps>();
 
-        // if let Some(buffer) = &particle_buffer.buffer {
-        //     buffer.slice(..).map_async(MapMode::Read, move |result| {
-        //         let err = result.err();
-        //         if err.is_some() {
-        //             let some_err = err.unwrap();
-        //             panic!("{}", some_err.to_string());
-        //         }
-        //     });
-
-        //     let device = world.resource::<RenderDevice>();
-        //     device.poll(wgpu::MaintainBase::Wait);
-
-        //     let range = buffer.slice(..).get_mapped_range();
-        //     let _vec: Vec<Particle> = cast_slice(&range).to_vec();
-        //     // println!("{:?}", _vec.first()); // uncomment for debugging
-
-        //     drop(range);
-        //     buffer.unmap();
-        // }
+        perf::write_timestamp(world, render_context, perf::SIMULATION_START);
+        simulation.encode_steps(render_context.command_encoder(), steps.count);
+        perf::write_timestamp(world, render_context, perf::SIMULATION_END);
+        // the simulation runs after the render node, so this is the last pass of ours in the frame
+        perf::resolve_timestamps(world, render_context);
 
         Ok(())
     }
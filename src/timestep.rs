@@ -0,0 +1,64 @@
+use bevy::prelude::*;
+
+use crate::{
+    config::Config,
+    objects::{
+        SimulationSettings, SimulationSteps, FIXED_TIMESTEP, MAX_FAST_FORWARD_STEPS,
+        MAX_STEPS_PER_FRAME,
+    },
+};
+
+pub struct Timestep;
+impl Plugin for Timestep {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationSettings>()
+            .init_resource::<SimulationSteps>()
+            .add_systems(Update, update_simulation_steps);
+    }
+}
+
+fn update_simulation_steps(
+    time: Res<Time>,
+    settings: Res<SimulationSettings>,
+    mut steps: ResMut<SimulationSteps>,
+    config: Res<Config>,
+) {
+    let substeps = settings.substeps.max(1);
+    steps.dt = FIXED_TIMESTEP / substeps as f32;
+
+    // recordings step by the frame rate, not by how long rendering took
+    let frame_time = config.frame_time();
+    if settings.fast_forward && frame_time.is_none() {
+        // scale the previous frame's step count by how far we were from the budget,
+        // at most doubling so one fast frame doesn't blow through the next
+        let frame_ms = time.raw_delta_seconds() * 1000.;
+        let count = steps.count.max(substeps) as f32;
+        let target = if frame_ms > 0. {
+            count * (settings.frame_budget_ms / frame_ms)
+        } else {
+            count * 2.
+        };
+
+        steps.count = (target.min(count * 2.) as u32).clamp(substeps, MAX_FAST_FORWARD_STEPS);
+        steps.accumulator = 0.;
+        return;
+    }
+
+    steps.accumulator += frame_time.unwrap_or(time.delta_seconds()) * settings.time_scale;
+
+    let fixed_steps = (steps.accumulator / FIXED_TIMESTEP) as u32;
+    steps.accumulator -= fixed_steps as f32 * FIXED_TIMESTEP;
+
+    // a recording takes as many steps as its frames need, however slowly
+    if frame_time.is_some() {
+        steps.count = fixed_steps * substeps;
+        return;
+    }
+
+    if fixed_steps > MAX_STEPS_PER_FRAME {
+        // drop the backlog rather than trying to catch up
+        steps.accumulator = 0.;
+    }
+
+    steps.count = fixed_steps.min(MAX_STEPS_PER_FRAME) * substeps;
+}
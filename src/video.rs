@@ -0,0 +1,100 @@
+//! Encodes captured frames into a video by piping them to an ffmpeg process as raw RGBA. ffmpeg
+//! picks the container and codec from the output's extension, so `.mp4`, `.webm` and anything
+//! else it knows all work.
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+};
+
+use bevy::prelude::*;
+
+use crate::capture::CapturedFrame;
+
+#[derive(Resource)]
+pub struct VideoEncoder {
+    ffmpeg: Child,
+    /// `None` once the video is finished
+    input: Option<ChildStdin>,
+    width: u32,
+    height: u32,
+}
+
+impl VideoEncoder {
+    /// Starts `program` encoding `width` by `height` frames at `fps` into `path`, replacing it
+    pub fn spawn(
+        program: &Path,
+        path: &Path,
+        (width, height): (u32, u32),
+        fps: u32,
+    ) -> Result<Self, String> {
+        let mut ffmpeg = Command::new(program)
+            .args(ffmpeg_args((width, height), fps))
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("couldn't start {}: {}", program.display(), err))?;
+        let input = ffmpeg.stdin.take();
+        Ok(Self {
+            ffmpeg,
+            input,
+            width,
+            height,
+        })
+    }
+
+    pub fn write_frame(&mut self, frame: &CapturedFrame) -> Result<(), String> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err(format!(
+                "the frame is {}x{}, but the video is {}x{}",
+                frame.width, frame.height, self.width, self.height
+            ));
+        }
+        let input = self.input.as_mut().ok_or("the video is finished")?;
+        // fails if ffmpeg has exited, which it reports on stderr
+        input
+            .write_all(&frame.data)
+            .map_err(|err| format!("couldn't write to ffmpeg: {}", err))
+    }
+
+    /// Closes ffmpeg's input and waits for it to write the end of the file
+    pub fn finish(&mut self) -> Result<(), String> {
+        drop(self.input.take());
+        let status = self
+            .ffmpeg
+            .wait()
+            .map_err(|err| format!("couldn't wait for ffmpeg: {}", err))?;
+        if !status.success() {
+            return Err(format!("ffmpeg failed with {}", status));
+        }
+        Ok(())
+    }
+}
+
+/// Arguments reading raw frames from stdin, before the output path
+fn ffmpeg_args((width, height): (u32, u32), fps: u32) -> Vec<String> {
+    [
+        "-y",
+        "-loglevel",
+        "error",
+        "-f",
+        "rawvideo",
+        "-pix_fmt",
+        "rgba",
+        "-s",
+        &format!("{}x{}", width, height),
+        "-r",
+        &fps.to_string(),
+        "-i",
+        "-",
+        // most players only decode 4:2:0, which needs even sizes
+        "-vf",
+        "pad=ceil(iw/2)*2:ceil(ih/2)*2",
+        "-pix_fmt",
+        "yuv420p",
+    ]
+    .map(String::from)
+    .to_vec()
+}
@@ -0,0 +1,136 @@
+//! The analysis statistics on small arrangements whose values can be worked out by hand
+
+use std::f32::consts::PI;
+
+use rusty_particle_life::{
+    analysis::{count_clusters, kinetic_energy, mean_speed, mixing_entropy, radial_distribution},
+    objects::Particle,
+};
+
+const WORLD_SIZE: (f32, f32) = (100., 100.);
+
+fn at(x: f32, y: f32, flavour: usize) -> Particle {
+    Particle::new([x, y, 0.], [0.; 3], flavour)
+}
+
+fn assert_near(actual: f32, expected: f32) {
+    assert!(
+        (actual - expected).abs() <= 1e-4 * expected.abs().max(1.),
+        "{} instead of {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn energy_and_speed() {
+    let particles = [
+        Particle::new([10., 10., 0.], [3., 4., 0.], 0),
+        Particle::new([20., 20., 0.], [0., 0., -2.], 1),
+    ];
+    assert_near(kinetic_energy(&particles), 0.5 * 25. + 0.5 * 4.);
+    assert_near(mean_speed(&particles), (5. + 2.) / 2.);
+    assert_eq!(kinetic_energy(&[]), 0.);
+    assert_eq!(mean_speed(&[]), 0.);
+}
+
+/// One neighbour in a bin, from one particle, is 1 / (density * the bin's area), where the density
+/// is one particle in the whole world
+#[test]
+fn radial_distribution_of_pairs() {
+    let density = 1. / (WORLD_SIZE.0 * WORLD_SIZE.1);
+    let expected = |inner: f32| 1. / (density * PI * ((inner + 1.).powi(2) - inner * inner));
+
+    let apart = [at(50., 50., 0), at(51.5, 50., 1)];
+    let histogram = radial_distribution(&apart, (0, 1), 4., 4, WORLD_SIZE);
+    assert_near(histogram[1], expected(1.));
+    for bin in [0, 2, 3] {
+        assert_eq!(histogram[bin], 0., "bin {}", bin);
+    }
+    // the other way around too
+    assert_near(
+        radial_distribution(&apart, (1, 0), 4., 4, WORLD_SIZE)[1],
+        expected(1.),
+    );
+
+    // measured across the world's edge
+    let wrapped = [at(0.5, 50., 0), at(98., 50., 1)];
+    let histogram = radial_distribution(&wrapped, (0, 1), 4., 4, WORLD_SIZE);
+    assert_near(histogram[2], expected(2.));
+
+    // both see the other, but each only has one other to see
+    let same = [at(50., 50., 0), at(50., 51.5, 0)];
+    let histogram = radial_distribution(&same, (0, 0), 4., 4, WORLD_SIZE);
+    assert_near(histogram[1], expected(1.));
+
+    // a missing flavour gives nothing rather than dividing by zero
+    assert_eq!(
+        radial_distribution(&apart, (0, 2), 4., 4, WORLD_SIZE),
+        [0.; 4]
+    );
+}
+
+/// Five particles around `centre`, all within 2 of each other
+fn clump(centre: (f32, f32), flavour: usize) -> Vec<Particle> {
+    [(0., 0.), (0.5, 0.), (-0.5, 0.), (0., 0.5), (0., -0.5)]
+        .iter()
+        .map(|(x, y)| at((centre.0 + x).rem_euclid(100.), centre.1 + y, flavour))
+        .collect()
+}
+
+#[test]
+fn clusters() {
+    let mut particles = clump((20., 20.), 0);
+    particles.extend(clump((70., 70.), 1));
+    // noise, too far from anything
+    particles.push(at(45., 45., 0));
+    particles.push(at(45., 80., 1));
+    assert_eq!(count_clusters(&particles, 2., 3, WORLD_SIZE), 2);
+    // nothing has enough neighbours to be a core point
+    assert_eq!(count_clusters(&particles, 2., 6, WORLD_SIZE), 0);
+    // a radius that reaches everything
+    assert_eq!(count_clusters(&particles, 60., 3, WORLD_SIZE), 1);
+
+    // split by the world's edge, but still one cluster
+    particles.extend(clump((0., 50.), 2));
+    assert_eq!(count_clusters(&particles, 2., 3, WORLD_SIZE), 3);
+
+    assert_eq!(count_clusters(&[], 2., 3, WORLD_SIZE), 0);
+}
+
+#[test]
+fn mixing() {
+    // 2x2 cells of 50x50
+    let corners = [(25., 25.), (75., 25.), (25., 75.), (75., 75.)];
+
+    // every cell holds both flavours equally
+    let uniform: Vec<_> = corners
+        .iter()
+        .flat_map(|&(x, y)| [at(x, y, 0), at(x, y, 1)])
+        .collect();
+    assert_near(mixing_entropy(&uniform, 2, WORLD_SIZE), 1.);
+
+    // each flavour on its own side
+    let segregated: Vec<_> = corners
+        .iter()
+        .map(|&(x, y)| at(x, y, (x > 50.) as usize))
+        .collect();
+    assert_eq!(mixing_entropy(&segregated, 2, WORLD_SIZE), 0.);
+
+    // half the particles are in a mixed cell
+    let half = [
+        at(25., 25., 0),
+        at(25., 25., 1),
+        at(75., 25., 0),
+        at(75., 75., 1),
+    ];
+    assert_near(mixing_entropy(&half, 2, WORLD_SIZE), 0.5);
+
+    // three flavours, equally mixed in the only occupied cell
+    let three = [at(10., 10., 0), at(10., 10., 1), at(10., 10., 2)];
+    assert_near(mixing_entropy(&three, 2, WORLD_SIZE), 1.);
+
+    // nothing to mix
+    assert_eq!(mixing_entropy(&[at(10., 10., 0)], 2, WORLD_SIZE), 0.);
+    assert_eq!(mixing_entropy(&[], 2, WORLD_SIZE), 0.);
+}
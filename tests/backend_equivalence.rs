@@ -0,0 +1,220 @@
+//! The compute shader and the CPU implementation should give the same behaviour for the same
+//! preset. Individual trajectories diverge quickly since the system is chaotic, so past the first
+//! step only aggregate statistics are compared. The GPU tests need an adapter, so they're ignored
+//! unless run with `cargo test -- --include-ignored`.
+
+mod common;
+
+use std::sync::Arc;
+
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use common::{assert_close, varied_properties, walls, Feature, CUBE, EMITTED, FLAT};
+use rand::{rngs::StdRng, SeedableRng};
+use rusty_particle_life::{
+    analysis::{mean_speed, mixing_entropy},
+    backend::SimulationBackend,
+    cpu::{CpuSimulation, CpuStepper},
+    lifecycle::Lifecycle,
+    objects::{FlavourProperties, ForceParams, Particle, Particles, Weights, FIXED_TIMESTEP},
+    sim_shader_pipeline::GpuSimulation,
+};
+
+const PARTICLES: usize = 512;
+const FLAVOURS: usize = 6;
+
+fn world_size() -> (f32, f32) {
+    (FLAT[0], FLAT[1])
+}
+
+fn preset(seed: u64, volume: [f32; 3]) -> (Vec<Particle>, Weights, ForceParams, FlavourProperties) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let particles = Particles::new(PARTICLES, FLAVOURS, volume, &mut rng);
+    (
+        particles.0,
+        Weights::random(&mut rng),
+        ForceParams::default(),
+        varied_properties(FLAVOURS),
+    )
+}
+
+fn gpu_simulation(volume: [f32; 3], flavours: usize) -> GpuSimulation {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter =
+        pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))
+            .expect("no GPU adapter");
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: None,
+            features: wgpu::Features::empty(),
+            limits: adapter.limits(),
+        },
+        None,
+    ))
+    .expect("couldn't open the GPU");
+
+    GpuSimulation::new(
+        &RenderDevice::from(device),
+        &RenderQueue(Arc::new(queue)),
+        volume[2] > 0.,
+        flavours,
+    )
+}
+
+fn run(
+    backend: &mut impl SimulationBackend,
+    seed: u64,
+    steps: u32,
+    volume: [f32; 3],
+) -> Vec<Particle> {
+    let (particles, weights, params, properties) = preset(seed, volume);
+    backend.init(particles.len(), volume);
+    backend.upload(&particles);
+    backend.step(
+        &weights,
+        &params,
+        &properties,
+        &Lifecycle::default(),
+        FIXED_TIMESTEP,
+        steps,
+    );
+    backend.readback()
+}
+
+/// Checks the run went down the feature's paths, so matching means something
+fn assert_exercised(feature: Feature, volume: [f32; 3], before: &[Particle], after: &[Particle]) {
+    match feature {
+        Feature::Lifecycle => {
+            let converted =
+                |particles: &[Particle]| particles.iter().filter(|p| p.flavour == 2).count();
+            assert!(after.iter().any(|p| !p.is_alive()), "nothing died");
+            assert!(
+                after.iter().any(|p| p.flavour == EMITTED && p.age < 0.1),
+                "nothing was emitted"
+            );
+            assert!(
+                converted(after) < converted(before),
+                "nothing was converted"
+            );
+        }
+        Feature::Obstacles => {
+            let field = walls(volume).field();
+            let inside = |particles: &[Particle]| {
+                particles
+                    .iter()
+                    .filter(|p| field.at(p.position[0] as i32, p.position[1] as i32) < 0.)
+                    .count()
+            };
+            assert!(inside(before) > 0, "no particles start inside the walls");
+            assert!(inside(after) < inside(before), "nothing was pushed out");
+        }
+        Feature::Interactions | Feature::Forces => {}
+    }
+}
+
+/// Both fill the lowest free slots first, so even the emitted particles match, whether the
+/// backend is called once per step or for several at a time
+fn stepper_matches(feature: Feature, volume: [f32; 3], calls: u32, steps: u32) {
+    let (mut expected, weights, params, properties) = preset(1 + feature as u64, volume);
+    let lifecycle = feature.lifecycle(volume, &mut expected);
+    let before = expected.clone();
+
+    let mut backend = CpuSimulation::default();
+    backend.init(expected.len(), volume);
+    backend.upload(&expected);
+    let mut stepper = CpuStepper::default();
+    feature.apply(volume, &mut stepper, &mut backend);
+
+    for _ in 0..calls {
+        backend.step(
+            &weights,
+            &params,
+            &properties,
+            &lifecycle,
+            FIXED_TIMESTEP,
+            steps,
+        );
+        for _ in 0..steps {
+            stepper.step(
+                &mut expected,
+                &weights,
+                &params,
+                &properties,
+                &lifecycle,
+                volume,
+                FIXED_TIMESTEP,
+            );
+        }
+    }
+
+    assert_exercised(feature, volume, &before, &expected);
+    assert_close(&backend.readback(), &expected);
+}
+
+#[test]
+fn cpu_backend_matches_stepper() {
+    for feature in Feature::ALL {
+        for &volume in feature.volumes() {
+            for (calls, steps) in [(10, 1), (2, 5)] {
+                eprintln!("{:?} in {:?}, {} steps at a time", feature, volume, steps);
+                stepper_matches(feature, volume, calls, steps);
+            }
+        }
+    }
+}
+
+#[test]
+#[ignore = "needs a GPU adapter"]
+fn gpu_first_step_matches_cpu() {
+    for volume in [FLAT, CUBE] {
+        let mut gpu = gpu_simulation(volume, FLAVOURS);
+        let cpu = run(&mut CpuSimulation::default(), 2, 1, volume);
+        let gpu = run(&mut gpu, 2, 1, volume);
+        assert_close(&gpu, &cpu);
+    }
+}
+
+#[test]
+#[ignore = "needs a GPU adapter"]
+fn gpu_resized_flavours_match_cpu() {
+    // as when the menu raises the flavour count, with particles of flavours the tables first lacked
+    let mut gpu = gpu_simulation(FLAT, 2);
+    gpu.set_flavours(FLAVOURS);
+    let cpu = run(&mut CpuSimulation::default(), 3, 1, FLAT);
+    let gpu = run(&mut gpu, 3, 1, FLAT);
+    assert_close(&gpu, &cpu);
+}
+
+#[test]
+#[ignore = "needs a GPU adapter"]
+fn gpu_statistics_match_cpu() {
+    let mut gpu = gpu_simulation(FLAT, FLAVOURS);
+
+    // single runs vary a lot with rounding, even between CPU implementations, so compare means
+    // over several presets
+    let (seeds, steps) = (8, 300);
+    let (mut cpu_speed, mut gpu_speed, mut cpu_mixing, mut gpu_mixing) = (0., 0., 0., 0.);
+    for seed in 0..seeds {
+        let cpu = run(&mut CpuSimulation::default(), seed, steps, FLAT);
+        let gpu = run(&mut gpu, seed, steps, FLAT);
+        cpu_speed += mean_speed(&cpu);
+        gpu_speed += mean_speed(&gpu);
+        cpu_mixing += mixing_entropy(&cpu, 8, world_size());
+        gpu_mixing += mixing_entropy(&gpu, 8, world_size());
+    }
+    let runs = seeds as f32;
+    let (cpu_speed, gpu_speed) = (cpu_speed / runs, gpu_speed / runs);
+    let (cpu_mixing, gpu_mixing) = (cpu_mixing / runs, gpu_mixing / runs);
+
+    assert!(
+        (cpu_speed - gpu_speed).abs() <= 0.25 * cpu_speed.max(gpu_speed),
+        "mean speed {} on the CPU vs {} on the GPU",
+        cpu_speed,
+        gpu_speed
+    );
+    assert!(
+        (cpu_mixing - gpu_mixing).abs() <= 0.1,
+        "mixing entropy {} on the CPU vs {} on the GPU",
+        cpu_mixing,
+        gpu_mixing
+    );
+}
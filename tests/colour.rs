@@ -0,0 +1,175 @@
+//! Conversions between the render image's half floats and the colours shown and saved, and the
+//! palettes particles are coloured from
+
+use bevy::core_pipeline::tonemapping::Tonemapping;
+use rusty_particle_life::{
+    hdr::{encode_pixel, from_f16, to_f16, to_rgba8, to_srgb, TONEMAPPERS},
+    palette::{hsv, parse_gpl, parse_hex, parse_palette},
+};
+
+fn grey(value: f32, tonemapping: Tonemapping) -> [u8; 4] {
+    to_rgba8(&encode_pixel([value, value, value, 1.]), tonemapping)
+        .try_into()
+        .unwrap()
+}
+
+#[test]
+fn f16_round_trips() {
+    // every half that isn't a NaN, including the subnormals and infinities
+    for half in 0..=u16::MAX {
+        let value = from_f16(half);
+        if !value.is_nan() {
+            assert_eq!(to_f16(value), half, "{:#06x} read as {}", half, value);
+        }
+    }
+
+    for value in [0., 1., -2., 0.5, 0.1, 1. / 3., 1000.5, 65504.] {
+        let error = (from_f16(to_f16(value)) - value).abs();
+        assert!(error <= value.abs() / 2048., "{} lost {}", value, error);
+    }
+}
+
+#[test]
+fn f16_subnormals() {
+    let smallest = 2f32.powi(-24);
+    assert_eq!(to_f16(smallest), 0x0001);
+    assert_eq!(to_f16(-smallest), 0x8001);
+    assert_eq!(to_f16(2f32.powi(-14) - smallest), 0x03ff);
+    assert_eq!(from_f16(0x0200), 2f32.powi(-15));
+    // too small for a subnormal
+    assert_eq!(to_f16(2f32.powi(-26)), 0x0000);
+    assert_eq!(to_f16(-2f32.powi(-26)), 0x8000);
+}
+
+#[test]
+fn f16_overflow() {
+    assert_eq!(to_f16(65504.), 0x7bff);
+    // rounds up past the largest half
+    assert_eq!(to_f16(65520.), 0x7c00);
+    assert_eq!(to_f16(1e6), 0x7c00);
+    assert_eq!(to_f16(-1e6), 0xfc00);
+    assert_eq!(to_f16(f32::INFINITY), 0x7c00);
+    assert_eq!(from_f16(0xfc00), f32::NEG_INFINITY);
+}
+
+#[test]
+fn f16_nan() {
+    assert!(from_f16(to_f16(f32::NAN)).is_nan());
+    assert!(from_f16(0x7e00).is_nan());
+    assert!(from_f16(0xfc01).is_nan());
+}
+
+#[test]
+fn no_tonemapping_clips() {
+    let pixel = to_rgba8(&encode_pixel([2., 0.5, -1., 1.]), Tonemapping::None);
+    let half = (to_srgb(0.5) * 255.).round() as u8;
+    assert_eq!(pixel, [255, half, 0, 255]);
+}
+
+#[test]
+fn tonemapping_keeps_overlaps_apart() {
+    for (tonemapping, name) in TONEMAPPERS {
+        assert_eq!(grey(0., tonemapping), [0, 0, 0, 255], "{}", name);
+        if tonemapping == Tonemapping::None {
+            continue;
+        }
+        // one particle, then two and four overlapping
+        let levels = [1., 2., 4.].map(|value| grey(value, tonemapping)[0]);
+        assert!(
+            levels[0] < levels[1] && levels[1] < levels[2] && levels[2] < 255,
+            "{} maps 1, 2 and 4 to {:?}",
+            name,
+            levels
+        );
+    }
+}
+
+#[test]
+fn hex_palettes() {
+    let colours = parse_hex("ff0000\n\n  #00FF80  \n000000\n").unwrap();
+    assert_eq!(
+        colours,
+        [
+            [1., 0., 0., 1.],
+            [0., 1., 128. / 255., 1.],
+            [0., 0., 0., 1.]
+        ]
+    );
+
+    for (text, line) in [
+        ("ff0000\n+ff000", 2),
+        ("-00000", 1),
+        ("ff00zz", 1),
+        ("ff000", 1),
+        ("ff00000", 1),
+        ("ff 000", 1),
+    ] {
+        assert_eq!(
+            parse_hex(text),
+            Err(format!("line {} isn't a hex colour", line)),
+            "{:?}",
+            text
+        );
+    }
+}
+
+#[test]
+fn gpl_palettes() {
+    let text = "GIMP Palette\nName: Test\nColumns: 4\n# a comment\n\n255   0   0\tRed\n  0 128 255 Sky blue\n";
+    assert_eq!(
+        parse_gpl(text).unwrap(),
+        [[1., 0., 0., 1.], [0., 128. / 255., 1., 1.]]
+    );
+
+    assert!(parse_gpl("255 0 0\n").is_err());
+    for (text, line) in [
+        ("GIMP Palette\n255 0\n", 2),
+        ("GIMP Palette\n# comment\n255 0 256\n", 3),
+        ("GIMP Palette\nred green blue\n", 2),
+    ] {
+        assert_eq!(
+            parse_gpl(text),
+            Err(format!("line {} isn't a colour", line)),
+            "{:?}",
+            text
+        );
+    }
+}
+
+#[test]
+fn empty_palettes_are_rejected() {
+    assert!(parse_palette("empty.hex", "\n  \n").is_err());
+    assert!(parse_palette("empty.GPL", "GIMP Palette\n# nothing\n").is_err());
+    assert_eq!(
+        parse_palette("one.gpl", "GIMP Palette\n0 0 0\n")
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn hsv_primaries() {
+    let close = |a: [f32; 4], b: [f32; 4]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5);
+    for (hue, rgb) in [
+        (0., [1., 0., 0., 1.]),
+        (1. / 6., [1., 1., 0., 1.]),
+        (1. / 3., [0., 1., 0., 1.]),
+        (0.5, [0., 1., 1., 1.]),
+        (2. / 3., [0., 0., 1., 1.]),
+        (5. / 6., [1., 0., 1., 1.]),
+        // hues wrap around
+        (1., [1., 0., 0., 1.]),
+        (-1. / 3., [0., 0., 1., 1.]),
+    ] {
+        assert!(
+            close(hsv(hue, 1., 1.), rgb),
+            "hue {} is {:?}",
+            hue,
+            hsv(hue, 1., 1.)
+        );
+    }
+    assert!(close(hsv(0.3, 0., 0.5), [0.5, 0.5, 0.5, 1.]));
+    assert!(close(hsv(0.7, 1., 0.), [0., 0., 0., 1.]));
+    assert!(close(hsv(0., 0.5, 1.), [1., 0.5, 0.5, 1.]));
+}
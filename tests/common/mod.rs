@@ -0,0 +1,200 @@
+//! Helpers shared by the tests that compare implementations of the simulation
+
+use rusty_particle_life::{
+    backend::SimulationBackend,
+    cpu::CpuStepper,
+    forces::{ExternalForces, FieldSource, Flow, ForceField},
+    lifecycle::{Emitter, Lifecycle, LifecycleRule},
+    objects::{FlavourProperties, Particle, PhysicalProperties, FIXED_TIMESTEP},
+    obstacles::ObstacleMap,
+};
+
+pub const FLAT: [f32; 3] = [256., 256., 0.];
+/// Smaller than the flat world so the same particles have a similar number of neighbours
+pub const CUBE: [f32; 3] = [128., 128., 128.];
+
+/// What's simulated on top of the particles' interactions, which the implementations are
+/// compared with one at a time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// just the interactions
+    Interactions,
+    Lifecycle,
+    Obstacles,
+    Forces,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 4] = [
+        Feature::Interactions,
+        Feature::Lifecycle,
+        Feature::Obstacles,
+        Feature::Forces,
+    ];
+
+    /// The worlds it's compared in. Walls only exist in flat ones.
+    pub fn volumes(self) -> &'static [[f32; 3]] {
+        match self {
+            Feature::Obstacles => &[FLAT],
+            _ => &[FLAT, CUBE],
+        }
+    }
+
+    /// The lifecycle it's stepped with, ageing `particles` for `varied_lifecycle`'s
+    pub fn lifecycle(self, volume: [f32; 3], particles: &mut [Particle]) -> Lifecycle {
+        if self != Feature::Lifecycle {
+            return Lifecycle::default();
+        }
+        age(particles);
+        varied_lifecycle(volume)
+    }
+
+    /// Gives both implementations its walls or forces, once the particles are uploaded
+    pub fn apply(
+        self,
+        volume: [f32; 3],
+        stepper: &mut CpuStepper,
+        backend: &mut impl SimulationBackend,
+    ) {
+        match self {
+            Feature::Obstacles => {
+                let field = walls(volume).field();
+                backend.set_obstacles(&field);
+                stepper.obstacles = field;
+            }
+            Feature::Forces => {
+                let forces = varied_forces(volume);
+                backend.set_forces(&forces);
+                stepper.forces = forces;
+            }
+            Feature::Interactions | Feature::Lifecycle => {}
+        }
+    }
+}
+
+/// Different for each of the first `flavours` flavours, so every property's path is exercised.
+/// Odd flavours have speed limits low enough to be reached.
+pub fn varied_properties(flavours: usize) -> FlavourProperties {
+    let mut properties = FlavourProperties::default();
+    for (flavour, properties) in properties.0.iter_mut().enumerate().take(flavours) {
+        let f = flavour as f32;
+        *properties = PhysicalProperties {
+            mass: 0.5 + 0.25 * f,
+            radius: 0.6 + 0.2 * f,
+            friction: 1.5 - 0.15 * f,
+            max_speed: if flavour % 2 == 1 { 30. + 5. * f } else { 0. },
+        };
+    }
+    properties
+}
+
+/// Flavour of `varied_lifecycle`'s emitter, which no rule applies to
+pub const EMITTED: u32 = 5;
+
+/// Rules down every path: flavour 0 respawns and 1 dies after half a second, 2 is converted to 4
+/// by touching 3, and an emitter in the middle of the world refills the slots of the dead
+pub fn varied_lifecycle(world_size: [f32; 3]) -> Lifecycle {
+    let mut lifecycle = Lifecycle::default();
+    lifecycle.rules[0] = LifecycleRule {
+        lifespan: 0.5,
+        respawn: 1,
+        ..Default::default()
+    };
+    lifecycle.rules[1] = LifecycleRule {
+        lifespan: 0.5,
+        ..Default::default()
+    };
+    lifecycle.rules[2] = LifecycleRule {
+        conversion_time: 2. * FIXED_TIMESTEP,
+        convert_by: 3,
+        convert_to: 4,
+        ..Default::default()
+    };
+    lifecycle.emitters.push(Emitter {
+        position: world_size.map(|size| size / 2.),
+        flavour: EMITTED,
+        rate: 4. / FIXED_TIMESTEP,
+        radius: 16.,
+    });
+    lifecycle
+}
+
+/// Ages the particles so a third of those with lifespans reach them on the next step and the rest
+/// over the following ones, and gives half of them a step's contact towards converting
+pub fn age(particles: &mut [Particle]) {
+    for (i, particle) in particles.iter_mut().enumerate() {
+        particle.age = 0.495 - 0.1 * (i % 3) as f32;
+        particle.contact = (i % 2) as f32 * FIXED_TIMESTEP;
+    }
+}
+
+/// A bar across the middle of a flat world and a disc in a corner, big enough that random
+/// particles start inside them
+pub fn walls(world_size: [f32; 3]) -> ObstacleMap {
+    let (width, height) = (world_size[0] as u32, world_size[1] as u32);
+    let mut walls = ObstacleMap::new(width, height);
+    for y in height / 2 - 12..height / 2 + 12 {
+        for x in width / 8..width * 7 / 8 {
+            walls.cells[(y * width + x) as usize] = true;
+        }
+    }
+    walls.paint([width as f32 / 4., height as f32 / 4.], 24., true);
+    walls
+}
+
+/// Gravity, an attractor, a repeller, a vortex that reaches across the world's edges and a
+/// drifting noise flow, taken part way through its drift
+pub fn varied_forces(world_size: [f32; 3]) -> ForceField {
+    let at = |fractions: [f32; 3]| std::array::from_fn(|axis| fractions[axis] * world_size[axis]);
+    let source = |position, kind, strength, radius| FieldSource {
+        position,
+        kind,
+        strength,
+        radius,
+        ..Default::default()
+    };
+    ExternalForces {
+        gravity: [4., 30., -8.],
+        sources: vec![
+            source(at([0.25, 0.25, 0.5]), FieldSource::ATTRACTOR, 800., 96.),
+            source(at([0.75, 0.25, 0.25]), FieldSource::ATTRACTOR, -600., 64.),
+            source(at([0.9, 0.75, 0.75]), FieldSource::VORTEX, 1200., 80.),
+        ],
+        flow: Flow::Noise {
+            cells: 3,
+            speed: 0.5,
+        },
+        flow_strength: 60.,
+    }
+    .field(1.3)
+}
+
+/// Within rounding of the force sums, which are done in different orders
+pub fn assert_close(actual: &[Particle], expected: &[Particle]) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, b) in actual.iter().zip(expected) {
+        assert_eq!(a.flavour(), b.flavour());
+        assert_eq!(a.flags, b.flags);
+        assert!((a.age - b.age).abs() < 1e-5, "age {} vs {}", a.age, b.age);
+        assert!(
+            (a.contact - b.contact).abs() < 1e-5,
+            "contact {} vs {}",
+            a.contact,
+            b.contact
+        );
+        for axis in 0..3 {
+            assert!(
+                (a.position[axis] - b.position[axis]).abs() < 1e-3,
+                "position {:?} vs {:?}",
+                a.position,
+                b.position
+            );
+            assert!(
+                (a.velocity[axis] - b.velocity[axis]).abs() < 1e-2,
+                "velocity {:?} vs {:?}",
+                a.velocity,
+                b.velocity
+            );
+        }
+    }
+}
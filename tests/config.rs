@@ -0,0 +1,178 @@
+//! Validation of the configuration, from the command line and from the files that can replace
+//! its arguments
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use rusty_particle_life::{
+    checkpoint::Checkpoint,
+    config::{parse_size, Args, Config, MAX_PARTICLES, MAX_WORLD_SIZE},
+    forces::ExternalForces,
+    lifecycle::Lifecycle,
+    objects::{FlavourProperties, ForceParams, Particle, ParticleColours, Weights},
+    preset::Preset,
+    replay::Replay,
+};
+
+fn preset(particles: u32, flavours: u32) -> Preset {
+    Preset {
+        seed: 7,
+        particle_count: particles,
+        flavour_count: flavours,
+        weights: Weights::default(),
+        colours: ParticleColours::default(),
+        params: ForceParams::default(),
+        properties: FlavourProperties::default(),
+        obstacles: None,
+        forces: ExternalForces::default(),
+    }
+}
+
+/// Writes `bytes` to a file of its own in the temporary directory
+fn temp_file(name: &str, bytes: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "rusty-particle-life-{}-{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, bytes).unwrap();
+    path
+}
+
+fn from_args(args: &[&str]) -> Result<Config, String> {
+    let args = Args::try_parse_from(["rusty-particle-life"].iter().chain(args))
+        .map_err(|err| err.to_string())?;
+    Config::from_parsed(args)
+}
+
+fn replay(fps: u32, world_size: (u32, u32)) -> Replay {
+    Replay {
+        initial: preset(100, 4),
+        world_size,
+        three_d: false,
+        fps,
+        frames: 10,
+        timeline: Vec::new(),
+    }
+}
+
+#[test]
+fn replay_replaces_the_arguments() {
+    let path = temp_file("replay.rplr", &replay(24, (300, 200)).to_bytes());
+    let config =
+        from_args(&["--replay", path.to_str().unwrap(), "-n", "5", "--fps", "60"]).unwrap();
+    assert_eq!(config.particle_count, 100);
+    assert_eq!(config.flavour_count, 4);
+    assert_eq!(config.world_size, (300, 200));
+    assert_eq!(config.fps, 24);
+}
+
+#[test]
+fn replay_is_validated() {
+    for (name, replay) in [
+        ("no fps", replay(0, (300, 200))),
+        ("no width", replay(24, (0, 200))),
+        ("no height", replay(24, (300, 0))),
+    ] {
+        let path = temp_file(&format!("{}.rplr", name), &replay.to_bytes());
+        assert!(
+            from_args(&["--replay", path.to_str().unwrap()]).is_err(),
+            "{} was accepted",
+            name
+        );
+    }
+}
+
+fn checkpoint(particles: u32, world_size: (u32, u32), three_d: bool) -> Checkpoint {
+    Checkpoint {
+        rules: preset(particles, 2),
+        lifecycle: Lifecycle::default(),
+        world_size,
+        three_d,
+        particles: vec![Particle::default(); particles as usize],
+    }
+}
+
+#[test]
+fn checkpoint_replaces_the_arguments() {
+    let path = temp_file("resume.rplk", &checkpoint(3, (300, 200), false).to_bytes());
+    let config = from_args(&["--checkpoint", path.to_str().unwrap(), "-n", "5", "--3d"]).unwrap();
+    assert_eq!(config.particle_count, 3);
+    assert_eq!(config.world_size, (300, 200));
+    assert!(!config.three_d);
+    assert_eq!(config.checkpoint.unwrap().particles.len(), 3);
+}
+
+#[test]
+fn checkpoint_is_validated() {
+    for (name, checkpoint) in [
+        ("no particles", checkpoint(0, (300, 200), false)),
+        ("no width", checkpoint(3, (0, 200), false)),
+        ("no height", checkpoint(3, (300, 0), false)),
+    ] {
+        let path = temp_file(&format!("{}.rplk", name), &checkpoint.to_bytes());
+        assert!(
+            from_args(&["--checkpoint", path.to_str().unwrap()]).is_err(),
+            "{} was accepted",
+            name
+        );
+    }
+
+    // the 3D view needs a cube
+    let path = temp_file("box.rplk", &checkpoint(3, (300, 200), true).to_bytes());
+    let config = from_args(&["--checkpoint", path.to_str().unwrap()]).unwrap();
+    assert_eq!(config.world_size, (200, 200));
+}
+
+#[test]
+fn particle_count_fits_one_dispatch() {
+    assert_eq!(MAX_PARTICLES, 65535 * 64);
+    let most = MAX_PARTICLES.to_string();
+    assert_eq!(
+        from_args(&["-n", &most]).unwrap().particle_count,
+        MAX_PARTICLES
+    );
+    let too_many = (MAX_PARTICLES + 1).to_string();
+    assert!(from_args(&["-n", &too_many]).is_err());
+    assert!(Config::from_query(&format!("?particles={}", too_many)).is_err());
+}
+
+#[test]
+fn sizes() {
+    assert_eq!(parse_size("300x200"), Ok((300, 200)));
+    assert_eq!(parse_size("1x8192"), Ok((1, MAX_WORLD_SIZE)));
+    for size in [
+        "300", "300x", "x200", "0x200", "300x0", "300x8193", "-3x200", "3.5x2", "300X200",
+    ] {
+        assert!(parse_size(size).is_err(), "{} was accepted", size);
+    }
+}
+
+#[test]
+fn queries() {
+    let config = Config::from_query(
+        "?particles=1000&seed=4&no-vsync&world-size=300%78200&output=my+frames%2f",
+    )
+    .unwrap();
+    assert_eq!(config.particle_count, 1000);
+    assert_eq!(config.seed, 4);
+    assert!(!config.vsync);
+    assert_eq!(config.world_size, (300, 200));
+    assert_eq!(config.output_dir, Some(PathBuf::from("my frames/")));
+
+    // empty pairs are skipped
+    assert_eq!(Config::from_query("&&seed=5&").unwrap().seed, 5);
+
+    for query in [
+        "seed=%zz",
+        "seed=4%",
+        "seed=%4",
+        "seed=%ff",
+        "seed=four",
+        "unknown=1",
+        "world-size=0x200",
+        "particles=0",
+    ] {
+        assert!(Config::from_query(query).is_err(), "{} was accepted", query);
+    }
+}
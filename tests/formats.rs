@@ -0,0 +1,527 @@
+//! The binary formats of presets, replays and checkpoints: that they round trip, and that
+//! corrupt or hostile files are rejected rather than crashing whatever loads them
+
+use rusty_particle_life::{
+    camera::OrbitCamera,
+    checkpoint::{Checkpoint, CheckpointError, CHECKPOINT_VERSION},
+    forces::{ExternalForces, FieldSource, Flow, FLOW_SIZE},
+    lifecycle::{Emitter, Lifecycle},
+    objects::{
+        FlavourProperties, ForceParams, Particle, ParticleColours, PhysicalProperties,
+        SimulationSettings, Weights,
+    },
+    obstacles::ObstacleMap,
+    preset::{forces_to_bytes, obstacles_to_bytes, Preset, PresetError, PRESET_VERSION},
+    replay::{Change, Replay, ReplayError},
+};
+
+fn preset(particles: u32, flavours: u32) -> Preset {
+    Preset {
+        seed: 7,
+        particle_count: particles,
+        flavour_count: flavours,
+        weights: Weights::default(),
+        colours: ParticleColours::default(),
+        params: ForceParams::default(),
+        properties: FlavourProperties::default(),
+        obstacles: None,
+        forces: ExternalForces::default(),
+    }
+}
+
+fn replay_with(timeline: Vec<(u32, Change)>) -> Replay {
+    Replay {
+        initial: preset(100, 3),
+        world_size: (300, 200),
+        three_d: false,
+        fps: 24,
+        frames: 90,
+        timeline,
+    }
+}
+
+/// Walls, sources of both kinds and an imported flow field, so every part of the format is used
+fn varied_forces() -> ExternalForces {
+    ExternalForces {
+        gravity: [0., -9.8, 0.],
+        sources: vec![
+            FieldSource {
+                position: [10., 20., 0.],
+                kind: FieldSource::ATTRACTOR,
+                strength: 50.,
+                radius: 30.,
+                ..Default::default()
+            },
+            FieldSource {
+                position: [200., 100., 0.],
+                kind: FieldSource::VORTEX,
+                strength: -20.,
+                radius: 60.,
+                ..Default::default()
+            },
+        ],
+        flow: Flow::Image(vec![[200, 40]; FLOW_SIZE * FLOW_SIZE]),
+        flow_strength: 3.,
+    }
+}
+
+fn walls() -> ObstacleMap {
+    let mut walls = ObstacleMap::new(300, 200);
+    walls.paint([150., 100.], 20., true);
+    walls
+}
+
+#[test]
+fn replay_round_trips() {
+    let mut lifecycle = Lifecycle::default();
+    lifecycle.rules[1].lifespan = 4.;
+    lifecycle.rules[2].convert_to = 1;
+    lifecycle.emitters.push(Emitter {
+        position: [1., 2., 3.],
+        flavour: 2,
+        rate: 5.,
+        radius: 9.,
+    });
+    let replay = replay_with(vec![
+        (0, Change::Rules(Box::new(preset(100, 3)))),
+        (0, Change::Obstacles(walls())),
+        (0, Change::Forces(varied_forces())),
+        (
+            3,
+            Change::Settings(SimulationSettings {
+                substeps: 3,
+                ..Default::default()
+            }),
+        ),
+        (5, Change::Lifecycle(Box::new(lifecycle))),
+        (
+            8,
+            Change::Camera(OrbitCamera {
+                yaw: 1.5,
+                ..Default::default()
+            }),
+        ),
+        (8, Change::Obstacles(ObstacleMap::new(300, 200))),
+    ]);
+
+    let bytes = replay.to_bytes();
+    let loaded = Replay::from_bytes(&bytes).unwrap();
+    assert_eq!(loaded.world_size, replay.world_size);
+    assert_eq!(loaded.fps, replay.fps);
+    assert_eq!(loaded.frames, replay.frames);
+    assert_eq!(loaded.timeline.len(), replay.timeline.len());
+    for ((loaded_at, loaded), (at, change)) in loaded.timeline.iter().zip(&replay.timeline) {
+        assert_eq!(loaded_at, at);
+        match (loaded, change) {
+            (Change::Obstacles(loaded), Change::Obstacles(walls)) => assert_eq!(loaded, walls),
+            (Change::Forces(loaded), Change::Forces(forces)) => assert_eq!(loaded, forces),
+            (Change::Lifecycle(loaded), Change::Lifecycle(lifecycle)) => {
+                assert_eq!(loaded.rules, lifecycle.rules);
+                assert_eq!(loaded.emitters, lifecycle.emitters);
+            }
+            _ => assert_eq!(
+                std::mem::discriminant(loaded),
+                std::mem::discriminant(change)
+            ),
+        }
+    }
+    assert_eq!(loaded.to_bytes(), bytes);
+
+    for len in [0, 5, bytes.len() / 2, bytes.len() - 1] {
+        assert!(Replay::from_bytes(&bytes[..len]).is_err(), "{} bytes", len);
+    }
+    assert_eq!(
+        Replay::from_bytes(b"nope").unwrap_err(),
+        ReplayError::BadMagic
+    );
+}
+
+/// Writes `section` length prefixed, as replays store presets
+fn section(bytes: &mut Vec<u8>, section: &[u8]) {
+    bytes.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(section);
+}
+
+#[test]
+fn replay_version_1_splits_up_rules() {
+    let rules = Preset {
+        obstacles: Some(walls()),
+        forces: varied_forces(),
+        ..preset(100, 3)
+    };
+    let mut bytes = b"RPLR".to_vec();
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    section(&mut bytes, &preset(100, 3).to_bytes());
+    // world size, 2D, fps, frames, and one change of the rules on frame 5
+    for value in [300u32, 200, 0, 24, 90, 1, 5] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.push(0);
+    section(&mut bytes, &rules.to_bytes());
+
+    let loaded = Replay::from_bytes(&bytes).unwrap();
+    let [(5, Change::Rules(_)), (5, Change::Obstacles(obstacles)), (5, Change::Forces(forces))] =
+        &loaded.timeline[..]
+    else {
+        panic!("{:?}", loaded.timeline);
+    };
+    assert_eq!(obstacles, &walls());
+    assert_eq!(forces, &varied_forces());
+}
+
+#[test]
+fn replay_rejects_unknown_flavours() {
+    let mut converts = Lifecycle::default();
+    converts.rules[0].convert_to = 3;
+    let mut converted_by = Lifecycle::default();
+    converted_by.rules[2].convert_by = 64;
+    let mut emits = Lifecycle::default();
+    emits.emitters.push(Emitter {
+        position: [0.; 3],
+        flavour: 3,
+        rate: 1.,
+        radius: 1.,
+    });
+
+    for lifecycle in [converts, converted_by, emits] {
+        let replay = replay_with(vec![(0, Change::Lifecycle(Box::new(lifecycle)))]);
+        assert!(matches!(
+            Replay::from_bytes(&replay.to_bytes()),
+            Err(ReplayError::BadChange(_))
+        ));
+    }
+}
+
+fn checkpoint() -> Checkpoint {
+    let mut dead = Particle::new([1., 2., 0.], [3., 4., 0.], 1);
+    dead.flags = Particle::DEAD;
+    dead.age = 2.5;
+    let mut converting = Particle::new([5., 6., 0.], [-7., 8., 0.], 2);
+    converting.contact = 0.25;
+    let mut lifecycle = Lifecycle::default();
+    lifecycle.rules[2].conversion_time = 1.;
+    lifecycle.rules[2].convert_to = 1;
+    lifecycle.emitters.push(Emitter {
+        position: [10., 20., 0.],
+        flavour: 1,
+        rate: 2.,
+        radius: 3.,
+    });
+    Checkpoint {
+        rules: preset(3, 3),
+        lifecycle,
+        world_size: (64, 32),
+        three_d: false,
+        particles: vec![dead, converting, Particle::default()],
+    }
+}
+
+/// Offset of the last particle's flavour from the end of a checkpoint's bytes
+const LAST_FLAVOUR: usize = 16;
+
+#[test]
+fn checkpoint_round_trips() {
+    let checkpoint = checkpoint();
+    let bytes = checkpoint.to_bytes();
+    let loaded = Checkpoint::from_bytes(&bytes).unwrap();
+
+    assert_eq!(loaded.world_size, checkpoint.world_size);
+    assert_eq!(loaded.three_d, checkpoint.three_d);
+    assert_eq!(loaded.rules.to_bytes(), checkpoint.rules.to_bytes());
+    assert_eq!(loaded.lifecycle, checkpoint.lifecycle);
+    assert_eq!(loaded.particles.len(), checkpoint.particles.len());
+    for (loaded, saved) in loaded.particles.iter().zip(&checkpoint.particles) {
+        assert_eq!(bytemuck::bytes_of(loaded), bytemuck::bytes_of(saved));
+    }
+    assert_eq!(loaded.to_bytes(), bytes);
+}
+
+#[test]
+fn checkpoint_version_1_has_no_lifecycle() {
+    let checkpoint = checkpoint();
+    let bytes = checkpoint.to_bytes();
+    // the lifecycle follows the header and the rules, each length prefixed
+    let preset_len = u32::from_le_bytes(bytes[22..26].try_into().unwrap()) as usize;
+    let lifecycle_at = 26 + preset_len;
+    let lifecycle_len =
+        u32::from_le_bytes(bytes[lifecycle_at..lifecycle_at + 4].try_into().unwrap()) as usize;
+    let mut v1 = bytes[..lifecycle_at].to_vec();
+    v1.extend_from_slice(&bytes[lifecycle_at + 4 + lifecycle_len..]);
+    v1[4..6].copy_from_slice(&1u16.to_le_bytes());
+
+    let loaded = Checkpoint::from_bytes(&v1).unwrap();
+    assert_eq!(loaded.lifecycle, Lifecycle::default());
+    assert_eq!(loaded.particles.len(), checkpoint.particles.len());
+    assert_eq!(
+        u16::from_le_bytes(loaded.to_bytes()[4..6].try_into().unwrap()),
+        CHECKPOINT_VERSION
+    );
+}
+
+#[test]
+fn checkpoint_rejects_corruption() {
+    let bytes = checkpoint().to_bytes();
+
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    assert_eq!(
+        Checkpoint::from_bytes(&magic).unwrap_err(),
+        CheckpointError::BadMagic
+    );
+
+    let mut version = bytes.clone();
+    version[4] = 9;
+    assert_eq!(
+        Checkpoint::from_bytes(&version).unwrap_err(),
+        CheckpointError::UnsupportedVersion(9)
+    );
+
+    for len in [0, 5, 20, bytes.len() / 2, bytes.len() - 1] {
+        assert_eq!(
+            Checkpoint::from_bytes(&bytes[..len]).unwrap_err(),
+            CheckpointError::Truncated,
+            "{} bytes",
+            len
+        );
+    }
+
+    // the header's count is just after the version
+    let mut count = bytes.clone();
+    count[6] = 200;
+    assert_eq!(
+        Checkpoint::from_bytes(&count).unwrap_err(),
+        CheckpointError::WrongCount(200)
+    );
+
+    let mut flavour = bytes.clone();
+    let at = flavour.len() - LAST_FLAVOUR;
+    flavour[at] = 3;
+    assert_eq!(
+        Checkpoint::from_bytes(&flavour).unwrap_err(),
+        CheckpointError::BadFlavour(3)
+    );
+
+    let mut emitter = checkpoint();
+    emitter.lifecycle.emitters[0].flavour = 3;
+    let mut converts = checkpoint();
+    converts.lifecycle.rules[0].convert_by = 3;
+    for checkpoint in [emitter, converts] {
+        assert_eq!(
+            Checkpoint::from_bytes(&checkpoint.to_bytes()).unwrap_err(),
+            CheckpointError::BadLifecycle
+        );
+    }
+}
+
+#[test]
+fn preset_rejects_walls_larger_than_any_world() {
+    // a version 3 preset of one flavour, claiming a single run of u32::MAX free cells
+    let mut bytes = b"RPLP".to_vec();
+    bytes.extend_from_slice(&3u16.to_le_bytes());
+    bytes.extend_from_slice(&7u64.to_le_bytes());
+    for value in [100u32, 1] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    let params = ForceParams::default();
+    let properties = PhysicalProperties::default();
+    for value in [
+        params.max_distance,
+        params.repulsion_distance,
+        params.force_scale,
+        params.friction_half_life,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // the weight and colour
+    bytes.extend_from_slice(&[0; 4 * (1 + 4)]);
+    for value in [
+        properties.mass,
+        properties.radius,
+        properties.friction,
+        properties.max_speed,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [65535u32, 65537, 1, u32::MAX] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    assert_eq!(
+        Preset::from_bytes(&bytes).unwrap_err(),
+        PresetError::BadObstacles
+    );
+}
+
+/// Something other than the default in every part of a preset
+fn varied_preset() -> Preset {
+    let flavours = 3;
+    let mut preset = preset(250, flavours);
+    for a in 0..flavours as usize {
+        for b in 0..flavours as usize {
+            preset.weights.0[a][b] = (a as f32 - b as f32) / 4.;
+        }
+        preset.colours.0[a] = [a as f32 / 3., 0.5, 1., 1.];
+        preset.properties.0[a] = PhysicalProperties {
+            mass: 1. + a as f32,
+            radius: 0.5,
+            friction: 2.,
+            max_speed: 40. * a as f32,
+        };
+    }
+    preset.params.force_scale = 3.5;
+    preset.obstacles = Some(walls());
+    preset.forces = varied_forces();
+    preset
+}
+
+/// `varied_preset` as each version wrote it, oldest first. Each version appended a section to
+/// the one before.
+fn preset_versions() -> Vec<(u16, Vec<u8>)> {
+    let preset = varied_preset();
+    let v4 = preset.to_bytes();
+    let v3 = v4[..v4.len() - forces_to_bytes(&preset.forces).len()].to_vec();
+    let v2 = v3[..v3.len() - obstacles_to_bytes(preset.obstacles.as_ref()).len()].to_vec();
+    let properties_len = preset.flavour_count as usize * std::mem::size_of::<PhysicalProperties>();
+    let v1 = v2[..v2.len() - properties_len].to_vec();
+
+    [v1, v2, v3, v4]
+        .into_iter()
+        .zip(1u16..)
+        .map(|(mut bytes, version)| {
+            bytes[4..6].copy_from_slice(&version.to_le_bytes());
+            (version, bytes)
+        })
+        .collect()
+}
+
+#[test]
+fn preset_round_trips() {
+    let preset = varied_preset();
+    let bytes = preset.to_bytes();
+    let loaded = Preset::from_bytes(&bytes).unwrap();
+    assert_eq!(loaded.to_bytes(), bytes);
+    assert_eq!(
+        Preset::from_base64(&preset.to_base64()).unwrap().to_bytes(),
+        bytes
+    );
+}
+
+#[test]
+fn preset_loads_every_version() {
+    let preset = varied_preset();
+    let versions = preset_versions();
+    assert_eq!(versions.len(), PRESET_VERSION as usize);
+
+    for (version, bytes) in versions {
+        let loaded =
+            Preset::from_bytes(&bytes).unwrap_or_else(|err| panic!("version {}: {}", version, err));
+
+        // in every version
+        assert_eq!(loaded.seed, preset.seed);
+        assert_eq!(loaded.particle_count, preset.particle_count);
+        assert_eq!(loaded.flavour_count, preset.flavour_count);
+        assert_eq!(loaded.params.force_scale, preset.params.force_scale);
+        assert_eq!(loaded.weights, preset.weights);
+        assert_eq!(loaded.colours, preset.colours);
+
+        // added since, and defaulted before
+        let properties = if version >= 2 {
+            preset.properties
+        } else {
+            FlavourProperties::default()
+        };
+        assert_eq!(loaded.properties.0, properties.0, "version {}", version);
+        let obstacles = if version >= 3 { Some(walls()) } else { None };
+        assert_eq!(loaded.obstacles, obstacles, "version {}", version);
+        let forces = if version >= 4 {
+            varied_forces()
+        } else {
+            ExternalForces::default()
+        };
+        assert_eq!(loaded.forces, forces, "version {}", version);
+    }
+}
+
+#[test]
+fn preset_rejects_corruption() {
+    let bytes = varied_preset().to_bytes();
+
+    let mut magic = bytes.clone();
+    magic[0] = b'X';
+    assert_eq!(
+        Preset::from_bytes(&magic).unwrap_err(),
+        PresetError::BadMagic
+    );
+
+    for version in [0, PRESET_VERSION + 1] {
+        let mut unsupported = bytes.clone();
+        unsupported[4..6].copy_from_slice(&version.to_le_bytes());
+        assert_eq!(
+            Preset::from_bytes(&unsupported).unwrap_err(),
+            PresetError::UnsupportedVersion(version)
+        );
+    }
+
+    // the flavour count follows the magic, version, seed and particle count
+    let mut flavours = bytes.clone();
+    flavours[18..22].copy_from_slice(&65u32.to_le_bytes());
+    assert_eq!(
+        Preset::from_bytes(&flavours).unwrap_err(),
+        PresetError::TooManyFlavours(65)
+    );
+
+    let params: [fn(&mut ForceParams); 6] = [
+        |params| params.max_distance = 0.,
+        |params| params.max_distance = f32::NAN,
+        |params| params.repulsion_distance = -0.1,
+        |params| params.repulsion_distance = 1.,
+        |params| params.force_scale = f32::INFINITY,
+        |params| params.friction_half_life = f32::NEG_INFINITY,
+    ];
+    for (case, corrupt) in params.into_iter().enumerate() {
+        let mut preset = varied_preset();
+        corrupt(&mut preset.params);
+        assert_eq!(
+            Preset::from_bytes(&preset.to_bytes()).unwrap_err(),
+            PresetError::BadParams,
+            "params case {}",
+            case
+        );
+    }
+    let properties: [fn(&mut PhysicalProperties); 6] = [
+        |properties| properties.mass = 0.,
+        |properties| properties.mass = f32::NAN,
+        |properties| properties.radius = -1.,
+        |properties| properties.friction = -1.,
+        |properties| properties.max_speed = -1.,
+        |properties| properties.max_speed = f32::INFINITY,
+    ];
+    for (case, corrupt) in properties.into_iter().enumerate() {
+        let mut preset = varied_preset();
+        // the last flavour, so the earlier ones are read fine first
+        corrupt(&mut preset.properties.0[2]);
+        assert_eq!(
+            Preset::from_bytes(&preset.to_bytes()).unwrap_err(),
+            PresetError::BadParams,
+            "properties case {}",
+            case
+        );
+    }
+
+    // every version, cut short anywhere
+    for (version, bytes) in preset_versions() {
+        for len in 0..bytes.len() {
+            assert!(
+                Preset::from_bytes(&bytes[..len]).is_err(),
+                "version {} cut to {} bytes",
+                version,
+                len
+            );
+        }
+    }
+
+    assert_eq!(
+        Preset::from_base64("not base64!").unwrap_err(),
+        PresetError::BadEncoding
+    );
+}
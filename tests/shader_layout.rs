@@ -0,0 +1,518 @@
+//! The shaders' structs and bindings are written by hand to match the Rust types and the bind
+//! group layouts. These parse the shaders with naga, with and without THREE_D, and compare what
+//! WGSL's layout rules make of them with what the Rust side uploads and binds. No GPU is needed.
+
+use std::mem::{offset_of, size_of};
+
+use bevy::render::render_resource::{
+    BindGroupLayoutEntry, BindingType, BufferBindingType, StorageTextureAccess, TextureFormat,
+    TextureSampleType, TextureViewDimension,
+};
+use naga::{
+    AddressSpace, ArraySize, ConstantInner, ImageClass, ImageDimension, Module, ScalarKind,
+    ScalarValue, StorageAccess, StorageFormat, TypeInner,
+};
+use rusty_particle_life::{
+    camera::CameraUniform,
+    density::DensityUniform,
+    forces::{FieldSource, ForceFieldUniform},
+    lifecycle::{LifecycleRule, SpawnBatch, MAX_EMITTERS},
+    objects::{PackedParticle, Particle, PhysicalProperties, SimulationParams},
+    render_shader_pipeline::RenderShaderPipeline,
+    sim_shader_pipeline::{particle_size, preprocess, GpuSimulation},
+};
+
+const SIMULATION: &str = include_str!("../assets/shaders/simulation.wgsl");
+const RENDER: &str = include_str!("../assets/shaders/render.wgsl");
+
+fn parse(source: &str, three_d: bool) -> Module {
+    let shader_defs: &[&str] = if three_d { &["THREE_D"] } else { &[] };
+    let source = preprocess(source, shader_defs);
+    naga::front::wgsl::parse_str(&source)
+        .unwrap_or_else(|error| panic!("{}", error.emit_to_string(&source)))
+}
+
+/// Both shaders, compiled for 2D and 3D
+fn shaders() -> impl Iterator<Item = (&'static str, bool, Module)> {
+    [("simulation.wgsl", SIMULATION), ("render.wgsl", RENDER)]
+        .into_iter()
+        .flat_map(|(name, source)| {
+            [false, true].map(|three_d| (name, three_d, parse(source, three_d)))
+        })
+}
+
+fn named_type<'a>(module: &'a Module, name: &str) -> &'a TypeInner {
+    module
+        .types
+        .iter()
+        .find(|(_, ty)| ty.name.as_deref() == Some(name))
+        .map(|(_, ty)| &ty.inner)
+        .unwrap_or_else(|| panic!("no struct {}", name))
+}
+
+fn global<'a>(module: &'a Module, name: &str) -> &'a TypeInner {
+    module
+        .global_variables
+        .iter()
+        .find(|(_, global)| global.name.as_deref() == Some(name))
+        .map(|(_, global)| &module.types[global.ty].inner)
+        .unwrap_or_else(|| panic!("no global {}", name))
+}
+
+fn array_length(module: &Module, size: ArraySize) -> Option<u64> {
+    match size {
+        ArraySize::Constant(constant) => match module.constants[constant].inner {
+            ConstantInner::Scalar {
+                value: ScalarValue::Uint(length),
+                ..
+            } => Some(length),
+            ref other => panic!("array length {:?}", other),
+        },
+        ArraySize::Dynamic => None,
+    }
+}
+
+/// How failures name a shader and its defines
+fn label(shader: &str, three_d: bool) -> String {
+    format!("{} in {}", shader, if three_d { "3D" } else { "2D" })
+}
+
+/// `fields` are the names and offsets of the Rust type's fields, in order. `shader` is the
+/// module's `label`.
+fn assert_struct(shader: &str, module: &Module, name: &str, size: usize, fields: &[(&str, usize)]) {
+    let TypeInner::Struct { members, span } = named_type(module, name) else {
+        panic!("{} isn't a struct in {}", name, shader);
+    };
+    let members: Vec<_> = members
+        .iter()
+        .map(|member| (member.name.as_deref().unwrap(), member.offset as usize))
+        .collect();
+    assert_eq!(members, fields, "members of {} in {}", name, shader);
+    assert_eq!(*span as usize, size, "size of {} in {}", name, shader);
+}
+
+#[test]
+fn particle_matches_in_3d() {
+    for (shader, three_d, module) in shaders() {
+        if !three_d {
+            continue;
+        }
+        assert_struct(
+            &label(shader, three_d),
+            &module,
+            "Particle",
+            size_of::<Particle>(),
+            &[
+                ("position", offset_of!(Particle, position)),
+                ("flavour", offset_of!(Particle, flavour)),
+                ("velocity", offset_of!(Particle, velocity)),
+                ("flags", offset_of!(Particle, flags)),
+                ("age", offset_of!(Particle, age)),
+                ("contact", offset_of!(Particle, contact)),
+            ],
+        );
+    }
+}
+
+#[test]
+fn packed_particle_matches_in_2d() {
+    for (shader, three_d, module) in shaders() {
+        if three_d {
+            continue;
+        }
+        assert_struct(
+            &label(shader, three_d),
+            &module,
+            "Particle",
+            size_of::<PackedParticle>(),
+            &[
+                ("position", offset_of!(PackedParticle, position)),
+                ("velocity", offset_of!(PackedParticle, velocity)),
+                ("flavour", offset_of!(PackedParticle, flavour)),
+                ("flags", offset_of!(PackedParticle, flags)),
+                ("age", offset_of!(PackedParticle, age)),
+                ("contact", offset_of!(PackedParticle, contact)),
+            ],
+        );
+    }
+}
+
+/// `Particles` is uploaded packed end to end, so the shaders' arrays mustn't pad between them
+#[test]
+fn particles_are_unpadded() {
+    for (shader, three_d, module) in shaders() {
+        let TypeInner::Array { size, stride, .. } = *global(&module, "particles") else {
+            panic!("particles isn't an array in {}", shader);
+        };
+        assert_eq!(array_length(&module, size), None, "{}", shader);
+        assert_eq!(stride as usize, particle_size(three_d), "{}", shader);
+    }
+}
+
+/// Only the flavours in use are uploaded, packed end to end, so the shaders take as many as
+/// their buffers hold
+#[test]
+fn flavour_tables_are_runtime_sized() {
+    for (shader, _, module) in shaders() {
+        let tables: &[(&str, usize)] = if shader == "render.wgsl" {
+            &[("colours", size_of::<[f32; 4]>())]
+        } else {
+            &[
+                ("weights", size_of::<f32>()),
+                ("properties", size_of::<PhysicalProperties>()),
+                ("rules", size_of::<LifecycleRule>()),
+            ]
+        };
+        for &(name, element_size) in tables {
+            let TypeInner::Array { size, stride, .. } = *global(&module, name) else {
+                panic!("{} isn't an array in {}", name, shader);
+            };
+            assert_eq!(array_length(&module, size), None, "{} in {}", name, shader);
+            assert_eq!(stride as usize, element_size, "{} in {}", name, shader);
+        }
+    }
+}
+
+#[test]
+fn simulation_params_match() {
+    for three_d in [false, true] {
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &parse(SIMULATION, three_d),
+            "SimulationParams",
+            size_of::<SimulationParams>(),
+            &[
+                ("world_size", offset_of!(SimulationParams, world_size)),
+                ("dt", offset_of!(SimulationParams, dt)),
+                ("max_distance", offset_of!(SimulationParams, max_distance)),
+                (
+                    "repulsion_distance",
+                    offset_of!(SimulationParams, repulsion_distance),
+                ),
+                ("force_scale", offset_of!(SimulationParams, force_scale)),
+                (
+                    "friction_half_life",
+                    offset_of!(SimulationParams, friction_half_life),
+                ),
+                ("seed", offset_of!(SimulationParams, seed)),
+                ("spawn_count", offset_of!(SimulationParams, spawn_count)),
+                ("flavour_count", offset_of!(SimulationParams, flavour_count)),
+            ],
+        );
+    }
+}
+
+#[test]
+fn lifecycle_matches() {
+    for three_d in [false, true] {
+        let module = parse(SIMULATION, three_d);
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &module,
+            "LifecycleRule",
+            size_of::<LifecycleRule>(),
+            &[
+                ("lifespan", offset_of!(LifecycleRule, lifespan)),
+                ("respawn", offset_of!(LifecycleRule, respawn)),
+                (
+                    "conversion_time",
+                    offset_of!(LifecycleRule, conversion_time),
+                ),
+                ("convert_by", offset_of!(LifecycleRule, convert_by)),
+                ("convert_to", offset_of!(LifecycleRule, convert_to)),
+            ],
+        );
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &module,
+            "SpawnBatch",
+            size_of::<SpawnBatch>(),
+            &[
+                ("position", offset_of!(SpawnBatch, position)),
+                ("flavour", offset_of!(SpawnBatch, flavour)),
+                ("radius", offset_of!(SpawnBatch, radius)),
+                ("first", offset_of!(SpawnBatch, first)),
+                ("count", offset_of!(SpawnBatch, count)),
+            ],
+        );
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &module,
+            "SpawnBatches",
+            size_of::<[SpawnBatch; MAX_EMITTERS]>(),
+            &[("batches", 0)],
+        );
+    }
+}
+
+#[test]
+fn force_field_matches() {
+    for three_d in [false, true] {
+        let module = parse(SIMULATION, three_d);
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &module,
+            "FieldSource",
+            size_of::<FieldSource>(),
+            &[
+                ("position", offset_of!(FieldSource, position)),
+                ("kind", offset_of!(FieldSource, kind)),
+                ("strength", offset_of!(FieldSource, strength)),
+                ("radius", offset_of!(FieldSource, radius)),
+            ],
+        );
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &module,
+            "ForceField",
+            size_of::<ForceFieldUniform>(),
+            &[
+                ("gravity", offset_of!(ForceFieldUniform, gravity)),
+                ("source_count", offset_of!(ForceFieldUniform, source_count)),
+                (
+                    "flow_strength",
+                    offset_of!(ForceFieldUniform, flow_strength),
+                ),
+                ("sources", offset_of!(ForceFieldUniform, sources)),
+            ],
+        );
+    }
+}
+
+#[test]
+fn flavour_properties_match() {
+    for three_d in [false, true] {
+        let module = parse(SIMULATION, three_d);
+        assert_struct(
+            &label("simulation.wgsl", three_d),
+            &module,
+            "PhysicalProperties",
+            size_of::<PhysicalProperties>(),
+            &[
+                ("mass", offset_of!(PhysicalProperties, mass)),
+                ("radius", offset_of!(PhysicalProperties, radius)),
+                ("friction", offset_of!(PhysicalProperties, friction)),
+                ("max_speed", offset_of!(PhysicalProperties, max_speed)),
+            ],
+        );
+    }
+}
+
+#[test]
+fn camera_matches() {
+    assert_struct(
+        &label("render.wgsl", true),
+        &parse(RENDER, true),
+        "Camera",
+        size_of::<CameraUniform>(),
+        &[
+            (
+                "view_projection",
+                offset_of!(CameraUniform, view_projection),
+            ),
+            ("viewport", offset_of!(CameraUniform, viewport)),
+            ("focal_length", offset_of!(CameraUniform, focal_length)),
+            (
+                "particle_radius",
+                offset_of!(CameraUniform, particle_radius),
+            ),
+        ],
+    );
+}
+
+#[test]
+fn density_matches() {
+    assert_struct(
+        &label("render.wgsl", false),
+        &parse(RENDER, false),
+        "Density",
+        size_of::<DensityUniform>(),
+        &[
+            ("mode", offset_of!(DensityUniform, mode)),
+            ("radius", offset_of!(DensityUniform, radius)),
+            ("threshold", offset_of!(DensityUniform, threshold)),
+            ("_padding", offset_of!(DensityUniform, _padding)),
+        ],
+    );
+}
+
+fn texture_format(format: StorageFormat) -> TextureFormat {
+    match format {
+        StorageFormat::Rgba8Unorm => TextureFormat::Rgba8Unorm,
+        StorageFormat::Rgba16Float => TextureFormat::Rgba16Float,
+        other => panic!("no texture format for {:?}", other),
+    }
+}
+
+/// Every layout entry is declared by the shader as the same kind of binding, with a minimum
+/// size that fits its type, and the shader declares nothing else
+fn assert_bindings_match(shader: &str, module: &Module, entries: &[BindGroupLayoutEntry]) {
+    let declared = module
+        .global_variables
+        .iter()
+        .filter(|(_, global)| global.binding.is_some())
+        .count();
+    assert_eq!(declared, entries.len(), "bindings in {}", shader);
+
+    for entry in entries {
+        let global = module
+            .global_variables
+            .iter()
+            .map(|(_, global)| global)
+            .find(|global| {
+                global
+                    .binding
+                    .as_ref()
+                    .is_some_and(|binding| binding.group == 0 && binding.binding == entry.binding)
+            })
+            .unwrap_or_else(|| panic!("binding {} isn't declared in {}", entry.binding, shader));
+        let ty = &module.types[global.ty].inner;
+        let context = format!("binding {} in {}", entry.binding, shader);
+
+        match (entry.ty, global.space) {
+            (
+                BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    min_binding_size,
+                    ..
+                },
+                AddressSpace::Uniform,
+            ) => {
+                let size = ty.size(&module.constants) as u64;
+                assert_eq!(
+                    min_binding_size.map(|size| size.get()),
+                    Some(size),
+                    "{}",
+                    context
+                );
+            }
+            (
+                BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only },
+                    min_binding_size,
+                    ..
+                },
+                AddressSpace::Storage { access },
+            ) => {
+                assert_eq!(
+                    read_only,
+                    !access.contains(StorageAccess::STORE),
+                    "{}",
+                    context
+                );
+                let min_size = min_binding_size.expect(&context).get();
+                // a runtime-sized array may come after a header
+                let (header, array) = match *ty {
+                    TypeInner::Struct { ref members, .. } => {
+                        let last = members.last().expect(&context);
+                        (last.offset as u64, &module.types[last.ty].inner)
+                    }
+                    ref array => (0, array),
+                };
+                match *array {
+                    TypeInner::Array {
+                        size: ArraySize::Dynamic,
+                        stride,
+                        ..
+                    } => {
+                        // the header, then at least one element, and whole elements
+                        assert!(min_size > header, "{}", context);
+                        assert!((min_size - header) % stride as u64 == 0, "{}", context);
+                    }
+                    _ => assert_eq!(min_size, ty.size(&module.constants) as u64, "{}", context),
+                }
+            }
+            (
+                BindingType::StorageTexture {
+                    access,
+                    format,
+                    view_dimension,
+                },
+                AddressSpace::Handle,
+            ) => {
+                let TypeInner::Image {
+                    dim,
+                    arrayed,
+                    class:
+                        ImageClass::Storage {
+                            format: shader_format,
+                            access: shader_access,
+                        },
+                } = *ty
+                else {
+                    panic!("{} isn't a storage texture", context);
+                };
+                assert_eq!(texture_format(shader_format), format, "{}", context);
+                assert_eq!(
+                    shader_access == StorageAccess::STORE,
+                    access == StorageTextureAccess::WriteOnly,
+                    "{}",
+                    context
+                );
+                assert!(
+                    dim == ImageDimension::D2 && !arrayed,
+                    "{} isn't 2D",
+                    context
+                );
+                assert_eq!(view_dimension, TextureViewDimension::D2, "{}", context);
+            }
+            (
+                BindingType::Texture {
+                    sample_type,
+                    view_dimension,
+                    multisampled,
+                },
+                AddressSpace::Handle,
+            ) => {
+                // only read with textureLoad, so needn't be filterable
+                let TypeInner::Image {
+                    dim,
+                    arrayed,
+                    class:
+                        ImageClass::Sampled {
+                            kind: ScalarKind::Float,
+                            multi,
+                        },
+                } = *ty
+                else {
+                    panic!("{} isn't a sampled float texture", context);
+                };
+                assert!(
+                    matches!(sample_type, TextureSampleType::Float { .. }),
+                    "{}",
+                    context
+                );
+                assert_eq!(multi, multisampled, "{}", context);
+                assert!(
+                    dim == ImageDimension::D2 && !arrayed,
+                    "{} isn't 2D",
+                    context
+                );
+                assert_eq!(view_dimension, TextureViewDimension::D2, "{}", context);
+            }
+            (layout, space) => panic!("{} is {:?} but declared {:?}", context, layout, space),
+        }
+    }
+}
+
+#[test]
+fn simulation_bindings_match_layout() {
+    for three_d in [false, true] {
+        assert_bindings_match(
+            "simulation.wgsl",
+            &parse(SIMULATION, three_d),
+            &GpuSimulation::bind_group_layout_entries(three_d),
+        );
+    }
+}
+
+#[test]
+fn render_bindings_match_layout() {
+    for three_d in [false, true] {
+        assert_bindings_match(
+            "render.wgsl",
+            &parse(RENDER, three_d),
+            &RenderShaderPipeline::bind_group_layout_entries(three_d),
+        );
+    }
+}
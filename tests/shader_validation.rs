@@ -0,0 +1,229 @@
+//! Catches broken shaders in `cargo test`. Both shaders are validated by naga for 2D and 3D,
+//! their entry points are checked against what the pipelines dispatch, and the simulation kernels
+//! are run on a software adapter and compared with the CPU reference stepper. Those need the
+//! adapter, so they're ignored unless run with `cargo test -- --include-ignored`.
+
+mod common;
+
+use std::sync::Arc;
+
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use common::{assert_close, varied_properties, Feature, EMITTED, FLAT};
+use naga::{
+    valid::{Capabilities, ValidationFlags, Validator},
+    Module, ShaderStage,
+};
+use rand::{rngs::StdRng, SeedableRng};
+use rusty_particle_life::{
+    backend::SimulationBackend,
+    cpu::CpuStepper,
+    objects::{ForceParams, Particle, Particles, Weights, FIXED_TIMESTEP, MAX_FLAVOURS},
+    sim_shader_pipeline::{preprocess, GpuSimulation, SimulationPipelines},
+    WORKGROUP_SIZE,
+};
+
+const SIMULATION: &str = include_str!("../assets/shaders/simulation.wgsl");
+const RENDER: &str = include_str!("../assets/shaders/render.wgsl");
+/// Enough for `varied_lifecycle`'s emitter and conversion
+const FLAVOURS: usize = 6;
+
+fn parse_and_validate(name: &str, source: &str, three_d: bool) -> Module {
+    let shader_defs: &[&str] = if three_d { &["THREE_D"] } else { &[] };
+    let source = preprocess(source, shader_defs);
+    let module = naga::front::wgsl::parse_str(&source)
+        .unwrap_or_else(|error| panic!("{}", error.emit_to_string_with_path(&source, name)));
+    Validator::new(ValidationFlags::all(), Capabilities::empty())
+        .validate(&module)
+        .unwrap_or_else(|error| panic!("{} is invalid: {:?}", name, error));
+    module
+}
+
+/// The compute entry points in `module`, which must all use `WORKGROUP_SIZE` since the
+/// dispatches are counted in its workgroups
+fn entry_points(name: &str, module: &Module) -> Vec<String> {
+    let size = [WORKGROUP_SIZE.0, WORKGROUP_SIZE.1, WORKGROUP_SIZE.2];
+    module
+        .entry_points
+        .iter()
+        .map(|entry_point| {
+            assert_eq!(entry_point.stage, ShaderStage::Compute, "{}", name);
+            assert_eq!(
+                entry_point.workgroup_size, size,
+                "workgroup size of {} in {}",
+                entry_point.name, name
+            );
+            entry_point.name.clone()
+        })
+        .collect()
+}
+
+#[test]
+fn simulation_shader_is_valid() {
+    for three_d in [false, true] {
+        let module = parse_and_validate("simulation.wgsl", SIMULATION, three_d);
+        assert_eq!(
+            entry_points("simulation.wgsl", &module),
+            ["init", "update", "integrate", "spawn"]
+        );
+    }
+}
+
+#[test]
+fn render_shader_is_valid() {
+    let module = parse_and_validate("render.wgsl", RENDER, false);
+    assert_eq!(
+        entry_points("render.wgsl", &module),
+        ["init", "update", "splat", "resolve"]
+    );
+
+    let module = parse_and_validate("render.wgsl", RENDER, true);
+    assert_eq!(
+        entry_points("render.wgsl", &module),
+        ["init", "depth", "update"]
+    );
+}
+
+/// A CPU implementation of WebGPU, such as llvmpipe, WARP or SwiftShader, so results don't
+/// depend on the machine's GPU
+fn software_device() -> (RenderDevice, RenderQueue) {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    let adapter = instance
+        .enumerate_adapters(wgpu::Backends::all())
+        .find(|adapter| adapter.get_info().device_type == wgpu::DeviceType::Cpu)
+        .expect("no software adapter");
+    let (device, queue) = pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: None,
+            features: wgpu::Features::empty(),
+            limits: adapter.limits(),
+        },
+        None,
+    ))
+    .expect("couldn't open the software adapter");
+
+    (RenderDevice::from(device), RenderQueue(Arc::new(queue)))
+}
+
+fn software_simulation(three_d: bool, flavours: usize) -> GpuSimulation {
+    let (device, queue) = software_device();
+    GpuSimulation::new(&device, &queue, three_d, flavours)
+}
+
+/// Just emitted, which nothing else is in the first step
+fn is_spawn(particle: &Particle) -> bool {
+    particle.is_alive() && particle.flavour == EMITTED && particle.age == 0.
+}
+
+/// Spawns go into whichever free slots the GPU pops first, so they're compared by position
+fn assert_lifecycle_close(actual: &[Particle], expected: &[Particle]) {
+    let free = |particle: &Particle| !particle.is_alive() || is_spawn(particle);
+    let (mut kept, mut expected_kept) = (Vec::new(), Vec::new());
+    for (a, b) in actual.iter().zip(expected) {
+        assert_eq!(free(a), free(b), "{:?} vs {:?}", a, b);
+        if !free(a) {
+            kept.push(*a);
+            expected_kept.push(*b);
+        }
+    }
+    assert_close(&kept, &expected_kept);
+
+    let spawns = |particles: &[Particle]| {
+        let mut spawns: Vec<_> = particles.iter().copied().filter(is_spawn).collect();
+        spawns.sort_by(|a, b| a.position[0].total_cmp(&b.position[0]));
+        spawns
+    };
+    let expected_spawns = spawns(expected);
+    assert!(!expected_spawns.is_empty(), "nothing was emitted");
+    assert_close(&spawns(actual), &expected_spawns);
+}
+
+/// One step of the kernels against the stepper. Some particles start outside the world, for
+/// upload to wrap, and inside the walls, to be pushed out. With the lifecycle some are already
+/// dead, so upload's free list, integrate's pushes and spawn's pops are all used.
+fn kernels_match_stepper(feature: Feature, volume: [f32; 3], flavours: usize) {
+    let mut gpu = software_simulation(volume[2] > 0., flavours);
+
+    let mut rng = StdRng::seed_from_u64(3 + feature as u64);
+    let mut particles = Particles::new(500, flavours, volume, &mut rng).0;
+    let (weights, params) = (Weights::random(&mut rng), ForceParams::default());
+    let properties = varied_properties(flavours);
+    let lifecycle = feature.lifecycle(volume, &mut particles);
+    if feature == Feature::Lifecycle {
+        for particle in particles.iter_mut().skip(1).step_by(11) {
+            particle.flags |= Particle::DEAD;
+        }
+    }
+    let mut outside = particles.clone();
+    for particle in outside.iter_mut().step_by(7) {
+        particle.position[0] -= volume[0];
+        particle.position[1] += volume[1];
+    }
+
+    gpu.init(particles.len(), volume);
+    gpu.upload(&outside);
+    assert_close(&gpu.readback(), &particles);
+
+    let mut stepper = CpuStepper::default();
+    feature.apply(volume, &mut stepper, &mut gpu);
+    gpu.step(
+        &weights,
+        &params,
+        &properties,
+        &lifecycle,
+        FIXED_TIMESTEP,
+        1,
+    );
+    stepper.step(
+        &mut particles,
+        &weights,
+        &params,
+        &properties,
+        &lifecycle,
+        volume,
+        FIXED_TIMESTEP,
+    );
+
+    if feature == Feature::Lifecycle {
+        assert_lifecycle_close(&gpu.readback(), &particles);
+    } else {
+        assert_close(&gpu.readback(), &particles);
+    }
+}
+
+#[test]
+#[ignore = "needs a software adapter"]
+fn kernels_match_stepper_on_software_adapter() {
+    for feature in Feature::ALL {
+        for &volume in feature.volumes() {
+            eprintln!("{:?} in {:?}", feature, volume);
+            kernels_match_stepper(feature, volume, FLAVOURS);
+        }
+    }
+}
+
+/// Every row of the packed weights and properties is read at the right offset
+#[test]
+#[ignore = "needs a software adapter"]
+fn kernels_match_stepper_with_max_flavours_on_software_adapter() {
+    kernels_match_stepper(Feature::Interactions, FLAT, MAX_FLAVOURS);
+}
+
+/// The app uploads the particles before the pipeline cache has compiled the shader
+#[test]
+#[ignore = "needs a software adapter"]
+fn upload_waits_for_pipelines_on_software_adapter() {
+    let (device, queue) = software_device();
+    let mut gpu = GpuSimulation::without_pipelines(&device, &queue, false, FLAVOURS);
+
+    let mut rng = StdRng::seed_from_u64(9);
+    let particles = Particles::new(500, FLAVOURS, FLAT, &mut rng).0;
+    let mut outside = particles.clone();
+    for particle in &mut outside {
+        particle.position[0] += FLAT[0];
+    }
+    gpu.init(particles.len(), FLAT);
+    gpu.upload(&outside);
+    let pipelines = SimulationPipelines::compile(&device, gpu.bind_group_layout(), false);
+    gpu.set_pipelines(pipelines);
+    assert_close(&gpu.readback(), &particles);
+}